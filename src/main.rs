@@ -3,8 +3,19 @@ mod ray_tracer;
 pub use crate::ray_tracer::rt_prelude::*;
 pub use crate::ray_tracer::matrices::*;
 pub use crate::ray_tracer::rays::*;
-
-use std::io::Write;
+pub use crate::ray_tracer::materials::*;
+pub use crate::ray_tracer::world::*;
+pub use crate::ray_tracer::camera::*;
+pub use crate::ray_tracer::shapes::*;
+pub use crate::ray_tracer::obj_parser::*;
+pub use crate::ray_tracer::bounds::*;
+pub use crate::ray_tracer::patterns::*;
+pub use crate::ray_tracer::noise::*;
+pub use crate::ray_tracer::ppm::*;
+pub use crate::ray_tracer::png::*;
+pub use crate::ray_tracer::hdr::*;
+pub use crate::ray_tracer::tone_mapping::*;
+pub use crate::ray_tracer::quantization::*;
 
 fn projectile_arc() {
 	let start = Vec4::new_point(0.0, 1.0, 0.0);
@@ -27,17 +38,14 @@ fn projectile_arc() {
 		tick(&env, &mut proj)
 	}
 
-	let ppm = c.to_ppm();
-
-	let mut file = std::fs::File::create("projectile_arc.ppm").expect("create failed");
-	file.write_all(ppm.as_bytes()).expect("write failed");
+	c.write_ppm_file("projectile_arc.ppm", PpmFormat::P6, BitDepth::Eight).expect("write failed");
 }
 
 fn circle_outline() {
 	use std::f64::consts::PI;
 
 	let mut c = Canvas::new(400, 400, Color::new(0.0, 0.0, 0.0));
-	let white = Color::new(255.0, 255.0, 255.0);
+	let white = Color::new(1.0, 1.0, 1.0);
 
 	let origin = Vec4::new_point(0.0, 0.0, 0.0);
 	let rotation = Mat4::new_rotation_z(PI / 6.0);
@@ -50,15 +58,13 @@ fn circle_outline() {
 		c.write_pixel((p.x + 200.0) as usize, (p.y + 200.0) as usize, &white);
 	}
 
-	let ppm = c.to_ppm();
-
-	let mut file = std::fs::File::create("circle.ppm").expect("create failed");
-	file.write_all(ppm.as_bytes()).expect("write failed");
+	c.write_ppm_file("circle.ppm", PpmFormat::P6, BitDepth::Eight).expect("write failed");
 }
 
+#[allow(clippy::len_zero)]
 fn draw_sphere_isometric() {
 	let mut c = Canvas::new(200, 200, Color::new(0.0, 0.0, 0.0));
-	let red = Color::new(255.0, 0.0, 0.0);
+	let red = Color::new(1.0, 0.0, 0.0);
 
 	let mut s = Sphere::new_sphere(0);
 
@@ -76,17 +82,15 @@ fn draw_sphere_isometric() {
 		}
 	}
 
-	let ppm = c.to_ppm();
-
-	let mut file = std::fs::File::create("sphere_iso.ppm").expect("create failed");
-	file.write_all(ppm.as_bytes()).expect("write failed");
+	c.write_ppm_file("sphere_iso.ppm", PpmFormat::P6, BitDepth::Eight).expect("write failed");
 }
 
+#[allow(clippy::len_zero)]
 fn draw_sphere_perspective() {
 	let canvas_size = 100;
 	let mut c = Canvas::new(canvas_size, canvas_size,
 							Color::new(0.0, 0.0, 0.0));
-	let red = Color::new(255.0, 0.0, 0.0);
+	let red = Color::new(1.0, 0.0, 0.0);
 
 	let s = Sphere::new_sphere(0);
 
@@ -121,16 +125,94 @@ fn draw_sphere_perspective() {
 		}
 	}
 
-	let ppm = c.to_ppm();
-
-	let mut file = std::fs::File::create("sphere_perspective.ppm").expect("create failed");
-	file.write_all(ppm.as_bytes()).expect("write failed");
+	c.write_ppm_file("sphere_perspective.ppm", PpmFormat::P6, BitDepth::Eight).expect("write failed");
 }
 
+fn draw_scene() {
+	use std::f64::consts::PI;
+
+	let mut world = World::new_world();
+
+	let mut floor = Plane::new_plane();
+	floor.material_mut().color = Color::new(1.0, 0.9, 0.9);
+	floor.material_mut().specular = 0.0;
+
+	let mut left_wall = Plane::new_plane();
+	left_wall.set_transform(Mat4::new_translation(0.0, 0.0, 5.0)
+		.rotate_y(-PI / 4.0)
+		.rotate_x(PI / 2.0));
+	left_wall.set_material(floor.material().clone());
+
+	let mut right_wall = Plane::new_plane();
+	right_wall.set_transform(Mat4::new_translation(0.0, 0.0, 5.0)
+		.rotate_y(PI / 4.0)
+		.rotate_x(PI / 2.0));
+	right_wall.set_material(floor.material().clone());
+
+	// Only the floor gets checkered
+	let checkers = CheckersPattern::new_checkers_pattern(Color::new(1.0, 0.9, 0.9), Color::new(0.6, 0.5, 0.5));
+	floor.material_mut().set_pattern(checkers);
+
+	let mut middle = Sphere::new_sphere(3);
+	middle.set_transform(Mat4::new_translation(-0.5, 1.0, 0.5));
+	middle.material_mut().color = Color::new(0.1, 1.0, 0.5);
+	middle.material_mut().diffuse = 0.7;
+	middle.material_mut().specular = 0.3;
+
+	let mut right = Sphere::new_sphere(4);
+	right.set_transform(Mat4::new_translation(1.5, 0.5, -0.5).scale(0.5, 0.5, 0.5));
+	right.material_mut().color = Color::new(0.5, 1.0, 0.1);
+	right.material_mut().diffuse = 0.7;
+	right.material_mut().specular = 0.3;
+
+	let mut left = Sphere::new_sphere(5);
+	left.set_transform(Mat4::new_translation(-1.5, 0.33, -0.75).scale(0.33, 0.33, 0.33));
+	left.material_mut().color = Color::new(1.0, 0.8, 0.1);
+	left.material_mut().diffuse = 0.7;
+	left.material_mut().specular = 0.3;
+
+	world.add_object(floor);
+	world.add_object(left_wall);
+	world.add_object(right_wall);
+	world.add_object(middle);
+	world.add_object(right);
+	world.add_object(left);
+
+	world.add_light(PointLight::new_point_light(Vec4::new_point(-10.0, 10.0, -10.0),
+												Color::new(1.0, 1.0, 1.0)));
+
+	let mut camera = Camera::new_camera(200, 100, PI / 3.0);
+	camera.set_transform(view_transform(Vec4::new_point(0.0, 1.5, -5.0),
+										Vec4::new_point(0.0, 1.0, 0.0),
+										Vec4::new_vec(0.0, 1.0, 0.0)))
+		.expect("camera transform is not invertible");
+
+	let canvas = camera.render(&world);
+
+	if cfg!(debug_assertions) {
+		if let Err(invalid) = canvas.validate() {
+			eprintln!("{} invalid pixels", invalid.len());
+
+			for pixel in invalid.iter().take(5) {
+				eprintln!("{}", pixel);
+			}
+		}
+	}
+
+	canvas.write_hdr_file("scene.hdr").expect("write failed");
+
+	let display = canvas.tone_mapped(&ToneMapper::new_tone_mapper(ToneMapOperator::AcesFilmic));
+	let mut quantizer = Quantizer::new_quantizer(Rounding::Nearest);
+	quantizer.dither = Dither::Ordered;
+
+	display.write_ppm_file_with("scene.ppm", PpmFormat::P6, BitDepth::Eight, &quantizer).expect("write failed");
+	display.write_png_file_with("scene.png", PngColorType::Rgb, BitDepth::Eight, &quantizer).expect("write failed");
+}
 
 fn main() {
 	projectile_arc();
 	circle_outline();
 	draw_sphere_isometric();
 	draw_sphere_perspective();
+	draw_scene();
 }
\ No newline at end of file
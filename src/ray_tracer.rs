@@ -63,6 +63,7 @@ pub mod rt_prelude {
         }
     }
 
+    #[allow(clippy::neg_multiply)]
     impl Neg for Vec4 {
         type Output = Vec4;
 
@@ -157,6 +158,10 @@ pub mod rt_prelude {
                           self.z * other.x - self.x * other.z,
                           self.x * other.y - self.y * other.x)
         }
+
+        pub fn reflect(&self, normal: &Vec4) -> Vec4 {
+            self - normal * 2.0 * self.dot(normal)
+        }
     }
 
     #[derive(Copy, Clone, Debug)]
@@ -211,15 +216,41 @@ pub mod rt_prelude {
             }
         }
 
+        // Truncates after clamping each channel to 0..1. Use a Quantizer to round or dither instead.
         pub fn as_u8_tup(&self) -> (u8, u8, u8) {
-            (
-                (self.r * 255.0) as u8,
-                (self.g * 255.0) as u8,
-                (self.b * 255.0) as u8,
-            )
+            let channel = |c: f64| quantize_channel(c, 255, Rounding::Truncate, 0.0) as u8;
+
+            (channel(self.r), channel(self.g), channel(self.b))
+        }
+
+        pub fn as_u16_tup(&self) -> (u16, u16, u16) {
+            let channel = |c: f64| quantize_channel(c, 65535, Rounding::Truncate, 0.0);
+
+            (channel(self.r), channel(self.g), channel(self.b))
         }
     }
 
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Rounding {
+        Nearest,
+        // Always rounds down, which is what a plain cast does
+        Truncate,
+    }
+
+    // Clamps the value to 0..1, scales it to 0..max_value and rounds it. offset is added after
+    // scaling, in steps of the output. NaN becomes 0.
+    pub fn quantize_channel(value: f64, max_value: u16, rounding: Rounding, offset: f64) -> u16 {
+        let value = if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) };
+        let scaled = value * max_value as f64 + offset;
+
+        let rounded = match rounding {
+            Rounding::Nearest => scaled.round(),
+            Rounding::Truncate => scaled.floor(),
+        };
+
+        rounded.clamp(0.0, max_value as f64) as u16
+    }
+
     pub struct Canvas {
         pub width: usize,
         pub height: usize,
@@ -235,6 +266,7 @@ pub mod rt_prelude {
             }
         }
 
+        #[allow(clippy::clone_on_copy)]
         pub fn write_pixel(&mut self, x: usize, y: usize, color: &Color) {
             if x > self.width - 1 || y > self.height - 1 {
                 return;
@@ -247,65 +279,57 @@ pub mod rt_prelude {
             self.pixels[y][x]
         }
 
-        pub fn to_ppm(&self) -> String {
-            let mut ppm_str: String = String::new();
-
-            // PPM Header
-            ppm_str.push_str(&format!("P3\n{w} {h}\n255\n", w = self.width, h = self.height));
-
-            let mut chars_in_current_line = 0;
-
-            for row in self.pixels.iter() {
-                for pixel in row.iter() {
-                    let (r, g, b) = pixel.as_u8_tup();
-
-                    let line = format!("{r} {g} {b} ", r = r, g = g, b = b);
-
-                    // Lines in a PPM file shouldn't be longer than 70 characters
-                    if chars_in_current_line + line.len() > 70 {
-                        let chars_remaining = 70 - chars_in_current_line;
-                        let r_str = r.to_string();
-                        let b_str = b.to_string();
-                        let rg_str = format!("{r} {g}\n", r = r, g = g);
+        // Reports every pixel with a NaN, infinite or negative channel, row by row. Quantizing clamps
+        // these to black or white, so they are easy to miss in the output image.
+        pub fn validate(&self) -> Result<(), Vec<InvalidPixel>> {
+            let mut invalid: Vec<InvalidPixel> = Vec::new();
+
+            for (y, row) in self.pixels.iter().enumerate() {
+                for (x, color) in row.iter().enumerate() {
+                    let channels = [color.r, color.g, color.b];
+
+                    let problem = if channels.iter().any(|c| c.is_nan()) {
+                        PixelProblem::NotANumber
+                    } else if channels.iter().any(|c| c.is_infinite()) {
+                        PixelProblem::Infinite
+                    } else if channels.iter().any(|c| *c < 0.0) {
+                        PixelProblem::Negative
+                    } else {
+                        continue;
+                    };
 
-                        if rg_str.len() <= chars_remaining {
-                            // R and G go on the current line
-                            ppm_str.push_str(&rg_str);
+                    invalid.push(InvalidPixel { x, y, color: *color, problem });
+                }
+            }
 
-                            // B goes on the next line
-                            ppm_str.push_str(&b_str);
-                            ppm_str.push_str(" ");
+            if invalid.is_empty() { Ok(()) } else { Err(invalid) }
+        }
+    }
 
-                            chars_in_current_line = b_str.len() + 1;
-                        } else if r_str.len() + 1 <= chars_remaining {
-                            // R goes on the current line
-                            ppm_str.push_str(&r_str);
-                            ppm_str.push_str("\n");
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum PixelProblem {
+        NotANumber,
+        Infinite,
+        Negative,
+    }
 
-                            // G and B go on the next line
-                            let gb_str = format!("{g} {b} ", g = g, b = b);
-                            ppm_str.push_str(&gb_str);
+    #[derive(Copy, Clone, Debug)]
+    pub struct InvalidPixel {
+        pub x: usize,
+        pub y: usize,
+        pub color: Color,
+        pub problem: PixelProblem,
+    }
 
-                            chars_in_current_line = gb_str.len();
-                        } else {
-                            // Replace space at the end of the line with a newline
-                            ppm_str.pop();
-                            ppm_str.push_str("\n");
-                            ppm_str.push_str(&line);
+    impl fmt::Display for InvalidPixel {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let problem = match self.problem {
+                PixelProblem::NotANumber => "NaN",
+                PixelProblem::Infinite => "infinite",
+                PixelProblem::Negative => "negative",
+            };
 
-                            // R G and B go on the next line
-                            chars_in_current_line = line.len();
-                        }
-                    } else {
-                        chars_in_current_line += line.len();
-                        ppm_str.push_str(&line);
-                    }
-                }
-                ppm_str.pop();    // Remove the space at the end of the line
-                ppm_str.push_str("\n");
-                chars_in_current_line = 0;
-            }
-            ppm_str
+            write!(f, "pixel ({}, {}) is {}: {:?}", self.x, self.y, problem, self.color)
         }
     }
 
@@ -342,11 +366,15 @@ pub mod rt_prelude {
         proj.vel = proj.vel + env.gravity + env.wind;
     }
 
+    // Tolerance used when comparing floats and when nudging points off of surfaces
+    pub const EPSILON: f64 = 0.00001;
+
     pub fn equal_approx(a: f64, b: f64) -> bool {
-        (a - b).abs() < 0.00001
+        (a - b).abs() < EPSILON
     }
 
     #[cfg(test)]
+    #[allow(clippy::op_ref, clippy::bool_assert_comparison)]
     mod vector_tests {
         use super::*;
 
@@ -507,9 +535,25 @@ pub mod rt_prelude {
             assert_eq!(a.cross(&b), Vec4::new_vec(-1.0, 2.0, -1.0));
             assert_eq!(b.cross(&a), Vec4::new_vec(1.0, -2.0, 1.0));
         }
+
+        #[test]
+        fn reflect_vec4() {
+            let v: Vec4 = Vec4::new_vec(1.0, -1.0, 0.0);
+            let n: Vec4 = Vec4::new_vec(0.0, 1.0, 0.0);
+
+            assert_eq!(v.reflect(&n), Vec4::new_vec(1.0, 1.0, 0.0));
+
+            // Reflecting off of a slanted surface
+            let v2: Vec4 = Vec4::new_vec(0.0, -1.0, 0.0);
+            let a = 2.0_f64.sqrt() / 2.0;
+            let n2: Vec4 = Vec4::new_vec(a, a, 0.0);
+
+            assert_eq!(v2.reflect(&n2), Vec4::new_vec(1.0, 0.0, 0.0));
+        }
     }
 
     #[cfg(test)]
+    #[allow(clippy::op_ref)]
     mod color_tests {
         use super::*;
 
@@ -577,10 +621,15 @@ pub mod rt_prelude {
             assert_eq!(r2, 0);
             assert_eq!(g2, 127);
             assert_eq!(b2, 127);
+
+            // Out of range channels are clamped rather than left to the cast
+            assert_eq!(Color::new(1.5, f64::NAN, f64::INFINITY).as_u8_tup(), (255, 0, 255));
+            assert_eq!(Color::new(1.5, -0.5, 0.5).as_u16_tup(), (65535, 0, 32767));
         }
     }
 
     #[cfg(test)]
+    #[allow(clippy::single_char_add_str)]
     mod canvas_tests {
         use super::*;
 
@@ -684,6 +733,25 @@ pub mod rt_prelude {
 									255 204 153 255 204 153 255 204 153 255 204 153 255 204 153 255 204\n\
 									153 255 204 153 255 204 153 255 204 153 255 204 153\n");
         }
+
+        #[test]
+        fn validate_canvas() {
+            let mut c: Canvas = Canvas::new(3, 2, Color::new(0.5, 0.5, 0.5));
+
+            assert!(c.validate().is_ok());
+
+            c.write_pixel(2, 0, &Color::new(0.0, -0.1, 1.0));
+            c.write_pixel(0, 1, &Color::new(f64::NAN, 0.0, -1.0));
+            c.write_pixel(1, 1, &Color::new(f64::INFINITY, 2.0, 0.0));
+
+            let invalid = c.validate().unwrap_err();
+            let found: Vec<(usize, usize, PixelProblem)> = invalid.iter().map(|p| (p.x, p.y, p.problem)).collect();
+
+            assert_eq!(found, vec![(2, 0, PixelProblem::Negative),
+                                   (0, 1, PixelProblem::NotANumber),
+                                   (1, 1, PixelProblem::Infinite)]);
+            assert_eq!(invalid[0].to_string(), "pixel (2, 0) is negative: Color { r: 0.0, g: -0.1, b: 1.0 }");
+        }
     }
 }
 
@@ -770,6 +838,7 @@ pub mod matrices {
     impl_op_ex!(* |a: &Mat4, b: &Vec4| -> Vec4 {
         let mut vec4_values: [f64; 4] = [0.0; 4];
 
+        #[allow(clippy::needless_range_loop)]
         for row in 0..4 {
             vec4_values[row] = a.data[row][0] * b.x +
                                a.data[row][1] * b.y +
@@ -880,6 +949,7 @@ pub mod matrices {
             self.submatrix(row, col).determinant()
         }
 
+        #[allow(clippy::manual_is_multiple_of)]
         pub fn cofactor(&self, row: usize, col: usize) -> f64 {
             if (row + col) % 2 == 0 {
                 self.minor(row, col)
@@ -939,6 +1009,7 @@ pub mod matrices {
             self.submatrix(row, col).determinant()
         }
 
+        #[allow(clippy::manual_is_multiple_of)]
         pub fn cofactor(&self, row: usize, col: usize) -> f64 {
             if (row + col) % 2 == 0 {
                 self.minor(row, col)
@@ -961,6 +1032,7 @@ pub mod matrices {
     }
 
     #[cfg(test)]
+    #[allow(clippy::bool_assert_comparison)]
     mod matrix_tests {
         use super::*;
 
@@ -1358,7 +1430,10 @@ pub mod matrices {
 pub mod rays {
     use super::rt_prelude::Vec4;
     use super::matrices::Mat4;
+    use super::materials::Material;
+    use super::bounds::Aabb;
 
+    #[derive(Copy, Clone, Debug)]
     pub struct Ray {
         pub origin: Vec4,
         pub direction: Vec4,
@@ -1378,76 +1453,261 @@ pub mod rays {
         }
     }
 
-    pub struct Sphere {
-        pub transform: Mat4,
-        pub id: i32,
+    // Transform and material handling shared by every shape.
+    // The inverted transforms are cached since they are needed for every intersection.
+    #[derive(Clone, Debug)]
+    pub struct ShapeData {
+        transform: Mat4,
+        transform_inverted: Option<Mat4>,
+        // Combined transform of every group the shape is nested in. The identity if it is not in a group.
+        parent_transform: Mat4,
+        world_transform_inverted: Option<Mat4>,
+        pub material: Material,
     }
 
-    impl Sphere {
-        pub fn new_sphere(id: i32) -> Sphere {
-            Sphere { id, transform: Mat4::id()}
+    impl ShapeData {
+        pub fn new_shape_data() -> ShapeData {
+            ShapeData {
+                transform: Mat4::id(),
+                transform_inverted: Some(Mat4::id()),
+                parent_transform: Mat4::id(),
+                world_transform_inverted: Some(Mat4::id()),
+                material: Material::new_material(),
+            }
         }
 
-        pub fn set_transform(&mut self, mat: Mat4) {
+        // Transforms are only set through the Shape trait, so shapes that contain others always get to update them
+        fn set_transform(&mut self, mat: Mat4) {
             self.transform = mat;
+            self.transform_inverted = mat.inverted();
+            self.world_transform_inverted = self.world_transform().inverted();
+        }
+
+        fn set_parent_transform(&mut self, mat: Mat4) {
+            self.parent_transform = mat;
+            self.world_transform_inverted = self.world_transform().inverted();
+        }
+
+        // Transforms from object space straight to world space, through every parent group
+        pub fn world_transform(&self) -> Mat4 {
+            self.parent_transform * self.transform
+        }
+    }
+
+    pub trait Shape {
+        fn data(&self) -> &ShapeData;
+        fn data_mut(&mut self) -> &mut ShapeData;
+
+        // Intersects a ray that has already been transformed into object space
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>>;
+
+        // Normal at a point that has already been transformed into object space
+        fn local_normal_at(&self, point: Vec4) -> Vec4;
+
+        // Box around the shape in object space
+        fn bounds(&self) -> Aabb;
+
+        // Box around the shape in its parent's space, i.e. after its own transform is applied
+        fn parent_space_bounds(&self) -> Aabb {
+            self.bounds().transform(self.transform())
+        }
+
+        // Splits any groups in this shape into a bounding volume hierarchy. Primitives have nothing to split.
+        fn build_bvh(&mut self) {}
+
+        // Same as local_normal_at, but for a specific hit. Shapes that interpolate their normals
+        // across the surface (like smooth triangles) use the hit's u and v.
+        fn local_normal_at_hit(&self, point: Vec4, _hit: &Intersection) -> Vec4 {
+            self.local_normal_at(point)
+        }
+
+        fn transform(&self) -> &Mat4 {
+            &self.data().transform
+        }
+
+        // None if the transform cannot be inverted
+        fn transform_inverted(&self) -> Option<&Mat4> {
+            self.data().transform_inverted.as_ref()
+        }
+
+        fn set_transform(&mut self, mat: Mat4) {
+            self.data_mut().set_transform(mat);
+            self.update_children();
+        }
+
+        fn parent_transform(&self) -> &Mat4 {
+            &self.data().parent_transform
+        }
+
+        // Called by groups when the shape is added to them or when their own transform changes
+        fn set_parent_transform(&mut self, mat: Mat4) {
+            self.data_mut().set_parent_transform(mat);
+            self.update_children();
+        }
+
+        // Called whenever the shape's world transform changes. Shapes that contain others pass it on to them.
+        fn update_children(&mut self) {}
+
+        fn world_transform_inverted(&self) -> Option<&Mat4> {
+            self.data().world_transform_inverted.as_ref()
+        }
+
+        // True if other is this shape or, for shapes that contain others, one of its descendants.
+        // Every shape owns its own ShapeData, so comparing their addresses compares the shapes.
+        fn includes(&self, other: &dyn Shape) -> bool {
+            std::ptr::eq(self.data(), other.data())
+        }
+
+        fn material(&self) -> &Material {
+            &self.data().material
+        }
+
+        fn material_mut(&mut self) -> &mut Material {
+            &mut self.data_mut().material
+        }
+
+        fn set_material(&mut self, material: Material) {
+            self.data_mut().material = material;
+        }
+
+        // Moves a world space point into object space, undoing the transforms of every parent group
+        fn world_to_object(&self, world_point: Vec4) -> Vec4 {
+            // A shape that cannot be inverted can never be hit, so there is no normal to find
+            let world_transform_inverted = self.world_transform_inverted()
+                .expect("shape transform is not invertible");
+
+            world_transform_inverted * world_point
+        }
+
+        // Moves an object space normal into world space, applying the transforms of every parent group
+        fn normal_to_world(&self, object_normal: Vec4) -> Vec4 {
+            let world_transform_inverted = self.world_transform_inverted()
+                .expect("shape transform is not invertible");
+
+            // Normals must be transformed by the inverse transpose to stay perpendicular to the surface
+            // when the shape has been scaled or sheared non-uniformly
+            let mut world_normal = world_transform_inverted.transposed() * object_normal;
+
+            // The transpose can pull translation into w, but normals are always vectors
+            world_normal.w = 0.0;
+
+            // Shapes without a surface of their own, like degenerate triangles, have a zero normal that cannot be normalized
+            if world_normal.magnitude() == 0.0 {
+                return world_normal;
+            }
+
+            world_normal.normalized()
+        }
+
+        fn normal_at(&self, world_point: Vec4) -> Vec4 {
+            let object_point = self.world_to_object(world_point);
+            self.normal_to_world(self.local_normal_at(object_point))
+        }
+
+        fn normal_at_hit(&self, world_point: Vec4, hit: &Intersection) -> Vec4 {
+            let object_point = self.world_to_object(world_point);
+            self.normal_to_world(self.local_normal_at_hit(object_point, hit))
         }
     }
 
-    pub enum Object<'a> {
-        Sphere(&'a Sphere),
-        SomeOtherObject,
+    // True if both references point to the same shape
+    pub fn same_shape(a: &dyn Shape, b: &dyn Shape) -> bool {
+        std::ptr::eq(a as *const dyn Shape as *const u8, b as *const dyn Shape as *const u8)
     }
 
-    pub struct Intersection<'a> {
-        pub t: f64,
-        pub object: Object<'a>,
+    pub struct Sphere {
+        data: ShapeData,
+        pub id: i32,
     }
 
-    impl Intersection<'_> {
-        pub fn new_intersection(t: f64, object: Object) -> Intersection {
-            Intersection { t, object }
+    impl Sphere {
+        pub fn new_sphere(id: i32) -> Sphere {
+            Sphere { id, data: ShapeData::new_shape_data() }
+        }
+
+        // A fully transparent sphere with the refractive index of glass
+        pub fn new_glass_sphere(id: i32) -> Sphere {
+            let mut sphere = Sphere::new_sphere(id);
+            sphere.data.material.transparency = 1.0;
+            sphere.data.material.refractive_index = 1.5;
+
+            sphere
         }
     }
 
-    // TODO: quietly fails and returns an empty Vec if object's matrix cannot be inverted
-    // TODO: maybe make a special "Transform" struct for functions like this. Traits would help here or Transform.as_mat4
-    pub fn get_intersection<'a>(object: &'a Sphere, ray: &Ray) -> Vec<Intersection<'a>> {
-        let mut intersections: Vec<Intersection> = Vec::new();
+    impl Shape for Sphere {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
 
-        let object_transform_inverted = object.transform.inverted();
-        if object_transform_inverted == None {
-            return intersections;
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
         }
-        let object_transform_inverted = object_transform_inverted.unwrap();
 
-        // Apply the sphere's transform to the ray
-        let ray = ray.transform(object_transform_inverted);
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            let mut intersections: Vec<Intersection> = Vec::new();
 
-        // The vector from the sphere's center to the ray's origin
-        let sphere_to_ray = ray.origin - Vec4::new_point(0.0, 0.0, 0.0);
+            // The vector from the sphere's center to the ray's origin
+            let sphere_to_ray = ray.origin - Vec4::new_point(0.0, 0.0, 0.0);
 
-        let a = ray.direction.dot(&ray.direction);
-        let b = 2.0 * ray.direction.dot(&sphere_to_ray);
-        let c = sphere_to_ray.dot(&sphere_to_ray) - 1.0;
+            let a = ray.direction.dot(&ray.direction);
+            let b = 2.0 * ray.direction.dot(&sphere_to_ray);
+            let c = sphere_to_ray.dot(&sphere_to_ray) - 1.0;
 
-        let discriminant = b.powi(2) - (4.0 * a) * c;
+            let discriminant = b.powi(2) - (4.0 * a) * c;
 
-        // If the discriminant is less than 0 then the ray does not intersect the sphere
-        if discriminant < 0.0 {
-            intersections
-        } else {
-            intersections.push(Intersection::new_intersection(
-                (-b - discriminant.sqrt()) / (2.0 * a),
-                Object::Sphere(object)));
-            intersections.push(Intersection::new_intersection(
-                (-b + discriminant.sqrt()) / (2.0 * a),
-                Object::Sphere(object)));
+            // If the discriminant is less than 0 then the ray does not intersect the sphere
+            if discriminant < 0.0 {
+                intersections
+            } else {
+                intersections.push(Intersection::new_intersection(
+                    (-b - discriminant.sqrt()) / (2.0 * a), self));
+                intersections.push(Intersection::new_intersection(
+                    (-b + discriminant.sqrt()) / (2.0 * a), self));
 
-            intersections
+                intersections
+            }
+        }
+
+        fn local_normal_at(&self, point: Vec4) -> Vec4 {
+            // In object space the sphere is a unit sphere at the origin
+            point - Vec4::new_point(0.0, 0.0, 0.0)
+        }
+
+        fn bounds(&self) -> Aabb {
+            Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0))
+        }
+    }
+
+    #[derive(Copy, Clone)]
+    pub struct Intersection<'a> {
+        pub t: f64,
+        pub object: &'a dyn Shape,
+        // Barycentric coordinates of the hit. Only meaningful for triangles.
+        pub u: f64,
+        pub v: f64,
+    }
+
+    impl Intersection<'_> {
+        pub fn new_intersection(t: f64, object: &dyn Shape) -> Intersection<'_> {
+            Intersection { t, object, u: 0.0, v: 0.0 }
+        }
+
+        pub fn new_intersection_with_uv(t: f64, object: &dyn Shape, u: f64, v: f64) -> Intersection<'_> {
+            Intersection { t, object, u, v }
         }
     }
 
-    fn get_hit<'a>(intersections: &'a Vec<Intersection>) -> Option<&'a Intersection<'a>> {
+    // TODO: quietly fails and returns an empty Vec if object's matrix cannot be inverted
+    pub fn get_intersection<'a>(object: &'a dyn Shape, ray: &Ray) -> Vec<Intersection<'a>> {
+        match object.transform_inverted() {
+            // Apply the object's transform to the ray
+            Some(object_transform_inverted) => object.local_intersect(&ray.transform(*object_transform_inverted)),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_hit<'a>(intersections: &'a [Intersection]) -> Option<&'a Intersection<'a>> {
         let mut smallest_positive_t = f64::MAX;
         let mut hit_tmp: Option<&Intersection> = None;
 
@@ -1481,14 +1741,10 @@ pub mod rays {
         fn create_intersection() {
             let s = Sphere::new_sphere(0);
 
-            let i = Intersection { t: 3.5, object: Object::Sphere(&s) };
+            let i = Intersection { t: 3.5, object: &s, u: 0.0, v: 0.0 };
 
             assert_eq!(i.t, 3.5);
-
-            match i.object {
-                Object::Sphere(s) => assert_eq!(s.id, 0),
-                _ => panic!("Object should have been a sphere"),
-            }
+            assert!(same_shape(i.object, &s));
         }
 
         #[test]
@@ -1576,10 +1832,7 @@ pub mod rays {
             assert_eq!(xs.len(), 2);
 
             for i in xs {
-                match i.object {
-                    Object::Sphere(s) => assert_eq!(s.id, 0),
-                    _ => panic!("Object should have been a sphere"),
-                }
+                assert!(same_shape(i.object, &s));
             }
         }
 
@@ -1587,8 +1840,8 @@ pub mod rays {
         fn hit_test_positive_t() {
             let s = Sphere::new_sphere(0);
 
-            let i1 = Intersection::new_intersection(1.0, Object::Sphere(&s));
-            let i2 = Intersection::new_intersection(2.0, Object::Sphere(&s));
+            let i1 = Intersection::new_intersection(1.0, &s);
+            let i2 = Intersection::new_intersection(2.0, &s);
             let xs = vec![i1, i2];
 
             let i = get_hit(&xs).unwrap();
@@ -1601,8 +1854,8 @@ pub mod rays {
         fn hit_test_some_negative_t() {
             let s = Sphere::new_sphere(0);
 
-            let i1 = Intersection::new_intersection(-1.0, Object::Sphere(&s));
-            let i2 = Intersection::new_intersection(1.0, Object::Sphere(&s));
+            let i1 = Intersection::new_intersection(-1.0, &s);
+            let i2 = Intersection::new_intersection(1.0, &s);
             let xs = vec![i1, i2];
 
             let i = get_hit(&xs).unwrap();
@@ -1615,8 +1868,8 @@ pub mod rays {
         fn hit_test_negative_t() {
             let s = Sphere::new_sphere(0);
 
-            let i1 = Intersection::new_intersection(-2.0, Object::Sphere(&s));
-            let i2 = Intersection::new_intersection(-1.0, Object::Sphere(&s));
+            let i1 = Intersection::new_intersection(-2.0, &s);
+            let i2 = Intersection::new_intersection(-1.0, &s);
             let xs = vec![i1, i2];
 
             let i = get_hit(&xs);
@@ -1629,10 +1882,10 @@ pub mod rays {
         fn hit_unsorted_intersections() {
             let s = Sphere::new_sphere(0);
 
-            let i1 = Intersection::new_intersection(5.0, Object::Sphere(&s));
-            let i2 = Intersection::new_intersection(7.0, Object::Sphere(&s));
-            let i3 = Intersection::new_intersection(-3.0, Object::Sphere(&s));
-            let i4 = Intersection::new_intersection(2.0, Object::Sphere(&s));
+            let i1 = Intersection::new_intersection(5.0, &s);
+            let i2 = Intersection::new_intersection(7.0, &s);
+            let i3 = Intersection::new_intersection(-3.0, &s);
+            let i4 = Intersection::new_intersection(2.0, &s);
             let xs = vec![i1, i2, i3, i4];
 
             let i = get_hit(&xs).unwrap();
@@ -1668,7 +1921,7 @@ pub mod rays {
         #[test]
         fn default_sphere() {
             let s = Sphere::new_sphere(0);
-            assert_eq!(s.transform, Mat4::id());
+            assert_eq!(*s.transform(), Mat4::id());
         }
 
         #[test]
@@ -1678,7 +1931,25 @@ pub mod rays {
 
             s.set_transform(t);
 
-            assert_eq!(s.transform, Mat4::new_translation(2.0, 3.0, 4.0));
+            assert_eq!(*s.transform(), Mat4::new_translation(2.0, 3.0, 4.0));
+        }
+
+        #[test]
+        fn default_sphere_material() {
+            let s = Sphere::new_sphere(0);
+            assert_eq!(*s.material(), Material::new_material());
+        }
+
+        #[test]
+        fn assign_sphere_material() {
+            let mut s = Sphere::new_sphere(0);
+            let mut m = Material::new_material();
+            m.ambient = 1.0;
+
+            s.set_material(m.clone());
+
+            assert_eq!(*s.material(), m);
+            assert_eq!(s.material().ambient, 1.0);
         }
 
         #[test]
@@ -1709,4 +1980,7880 @@ pub mod rays {
             assert_eq!(xs.len(), 0);
         }
     }
-}
\ No newline at end of file
+
+    #[cfg(test)]
+    mod normal_tests {
+        use super::*;
+        use std::f64::consts::PI;
+
+        #[test]
+        fn sphere_normal_on_axes() {
+            let s = Sphere::new_sphere(0);
+
+            assert_eq!(s.normal_at(Vec4::new_point(1.0, 0.0, 0.0)), Vec4::new_vec(1.0, 0.0, 0.0));
+            assert_eq!(s.normal_at(Vec4::new_point(0.0, 1.0, 0.0)), Vec4::new_vec(0.0, 1.0, 0.0));
+            assert_eq!(s.normal_at(Vec4::new_point(0.0, 0.0, 1.0)), Vec4::new_vec(0.0, 0.0, 1.0));
+        }
+
+        #[test]
+        fn sphere_normal_nonaxial() {
+            let s = Sphere::new_sphere(0);
+            let a = 3.0_f64.sqrt() / 3.0;
+
+            let n = s.normal_at(Vec4::new_point(a, a, a));
+
+            assert_eq!(n, Vec4::new_vec(a, a, a));
+            // Normals should always be normalized
+            assert_eq!(n, n.normalized());
+        }
+
+        #[test]
+        fn translated_sphere_normal() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(0.0, 1.0, 0.0));
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let n = s.normal_at(Vec4::new_point(0.0, 1.0 + a, -a));
+
+            assert_eq!(n, Vec4::new_vec(0.0, a, -a));
+        }
+
+        #[test]
+        fn transformed_sphere_normal() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::id().scale(1.0, 0.5, 1.0).rotate_z(PI / 5.0));
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let n = s.normal_at(Vec4::new_point(0.0, a, -a));
+
+            assert_eq!(n, Vec4::new_vec(0.0, 0.97014, -0.24254));
+        }
+
+        #[test]
+        fn sheared_sphere_normal_is_perpendicular() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::id().shear(1.0, 0.0, 0.0, 0.0, 0.0, 0.0).scale(2.0, 1.0, 0.5));
+
+            // Two points on the unit sphere that are very close to each other. Once transformed,
+            // the chord between them lies (almost) in the tangent plane at those points.
+            let theta: f64 = 0.7;
+            let d = 0.0001;
+            let p1 = s.transform() * Vec4::new_point(theta.cos(), theta.sin(), 0.0);
+            let p2 = s.transform() * Vec4::new_point((theta + d).cos(), (theta + d).sin(), 0.0);
+
+            let n = s.normal_at(p1);
+            let tangent = (p2 - p1).normalized();
+
+            assert!(n.dot(&tangent).abs() < 0.001);
+            assert_eq!(n.w, 0.0);
+            assert_eq!(n, n.normalized());
+        }
+    }
+
+    #[cfg(test)]
+    mod shape_tests {
+        use super::*;
+        use std::cell::Cell;
+        use std::f64::consts::PI;
+
+        // A shape that records the object space ray it was intersected with
+        struct TestShape {
+            data: ShapeData,
+            saved_ray: Cell<Option<Ray>>,
+        }
+
+        impl TestShape {
+            fn new_test_shape() -> TestShape {
+                TestShape { data: ShapeData::new_shape_data(), saved_ray: Cell::new(None) }
+            }
+        }
+
+        impl Shape for TestShape {
+            fn data(&self) -> &ShapeData {
+                &self.data
+            }
+
+            fn data_mut(&mut self) -> &mut ShapeData {
+                &mut self.data
+            }
+
+            fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+                self.saved_ray.set(Some(*ray));
+                Vec::new()
+            }
+
+            fn local_normal_at(&self, point: Vec4) -> Vec4 {
+                Vec4::new_vec(point.x, point.y, point.z)
+            }
+
+            fn bounds(&self) -> Aabb {
+                Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0))
+            }
+        }
+
+        #[test]
+        fn default_shape_data() {
+            let s = TestShape::new_test_shape();
+
+            assert_eq!(*s.transform(), Mat4::id());
+            assert_eq!(*s.material(), Material::new_material());
+        }
+
+        #[test]
+        fn assign_shape_material() {
+            let mut s = TestShape::new_test_shape();
+
+            s.material_mut().ambient = 1.0;
+
+            assert_eq!(s.material().ambient, 1.0);
+        }
+
+        #[test]
+        fn intersect_scaled_shape() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let mut s = TestShape::new_test_shape();
+            s.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+
+            get_intersection(&s, &r);
+
+            let saved_ray = s.saved_ray.get().unwrap();
+            assert_eq!(saved_ray.origin, Vec4::new_point(0.0, 0.0, -2.5));
+            assert_eq!(saved_ray.direction, Vec4::new_vec(0.0, 0.0, 0.5));
+        }
+
+        #[test]
+        fn intersect_translated_shape() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let mut s = TestShape::new_test_shape();
+            s.set_transform(Mat4::new_translation(5.0, 0.0, 0.0));
+
+            get_intersection(&s, &r);
+
+            let saved_ray = s.saved_ray.get().unwrap();
+            assert_eq!(saved_ray.origin, Vec4::new_point(-5.0, 0.0, -5.0));
+            assert_eq!(saved_ray.direction, Vec4::new_vec(0.0, 0.0, 1.0));
+        }
+
+        #[test]
+        fn intersect_uninvertible_shape() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let mut s = TestShape::new_test_shape();
+            s.set_transform(Mat4::new_scaling(0.0, 1.0, 1.0));
+
+            assert!(get_intersection(&s, &r).is_empty());
+            // The shape should never have been asked to intersect the ray
+            assert!(s.saved_ray.get().is_none());
+        }
+
+        #[test]
+        fn normal_on_translated_shape() {
+            let mut s = TestShape::new_test_shape();
+            s.set_transform(Mat4::new_translation(0.0, 1.0, 0.0));
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let n = s.normal_at(Vec4::new_point(0.0, 1.0 + a, -a));
+
+            assert_eq!(n, Vec4::new_vec(0.0, a, -a));
+        }
+
+        #[test]
+        fn normal_on_transformed_shape() {
+            let mut s = TestShape::new_test_shape();
+            s.set_transform(Mat4::id().scale(1.0, 0.5, 1.0).rotate_z(PI / 5.0));
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let n = s.normal_at(Vec4::new_point(0.0, a, -a));
+
+            assert_eq!(n, Vec4::new_vec(0.0, 0.97014, -0.24254));
+        }
+
+        #[test]
+        fn intersections_from_different_shapes() {
+            let s1 = Sphere::new_sphere(0);
+            let s2 = TestShape::new_test_shape();
+
+            // Intersections can hold any kind of shape
+            let xs = [Intersection::new_intersection(1.0, &s1),
+                      Intersection::new_intersection(2.0, &s2)];
+
+            assert!(same_shape(xs[0].object, &s1));
+            assert!(same_shape(xs[1].object, &s2));
+            assert!(!same_shape(xs[0].object, xs[1].object));
+        }
+    }
+}
+
+pub mod materials {
+    use std::rc::Rc;
+    use super::rt_prelude::{Vec4, Color};
+    use super::rays::Shape;
+    use super::patterns::Pattern;
+
+    #[derive(Clone, Debug)]
+    pub struct Material {
+        pub color: Color,
+        // Replaces color when set. Patterns are shared, so cloning a material does not copy its pattern.
+        pub pattern: Option<Rc<dyn Pattern>>,
+        pub ambient: f64,
+        pub diffuse: f64,
+        pub specular: f64,
+        pub shininess: f64,
+        // How much of the surrounding scene the surface reflects. 0 is matte and 1 is a perfect mirror.
+        pub reflective: f64,
+        // How much light passes through the surface. 0 is opaque.
+        pub transparency: f64,
+        // How much light bends when entering the material. 1 is a vacuum, 1.5 is glass.
+        pub refractive_index: f64,
+    }
+
+    impl Material {
+        pub fn new_material() -> Material {
+            Material {
+                color: Color::new(1.0, 1.0, 1.0),
+                pattern: None,
+                ambient: 0.1,
+                diffuse: 0.9,
+                specular: 0.9,
+                shininess: 200.0,
+                reflective: 0.0,
+                transparency: 0.0,
+                refractive_index: 1.0,
+            }
+        }
+
+        pub fn set_pattern<P: Pattern + 'static>(&mut self, pattern: P) {
+            self.pattern = Some(Rc::new(pattern));
+        }
+    }
+
+    impl PartialEq for Material {
+        fn eq(&self, other: &Material) -> bool {
+            // Patterns are only equal if they are the same pattern
+            let same_pattern = match (&self.pattern, &other.pattern) {
+                (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+                (None, None) => true,
+                _ => false,
+            };
+
+            same_pattern &&
+            self.color == other.color &&
+            self.ambient == other.ambient &&
+            self.diffuse == other.diffuse &&
+            self.specular == other.specular &&
+            self.shininess == other.shininess &&
+            self.reflective == other.reflective &&
+            self.transparency == other.transparency &&
+            self.refractive_index == other.refractive_index
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct PointLight {
+        pub position: Vec4,
+        pub intensity: Color,
+    }
+
+    impl PointLight {
+        pub fn new_point_light(position: Vec4, intensity: Color) -> PointLight {
+            PointLight { position, intensity }
+        }
+    }
+
+    // Shades a point on a surface using the Phong reflection model.
+    // The object is needed to find where the point is on the material's pattern.
+    pub fn lighting(material: &Material, object: &dyn Shape, light: &PointLight, point: Vec4, eye: Vec4,
+                    normal: Vec4, in_shadow: bool) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+
+        let color = match &material.pattern {
+            Some(pattern) => pattern.pattern_at_shape(object, point),
+            None => material.color,
+        };
+
+        // Combine the surface color with the light's color
+        let effective_color = color * light.intensity;
+
+        // Direction from the point to the light source
+        let light_vec = (light.position - point).normalized();
+
+        let ambient = effective_color * material.ambient;
+
+        // Points in shadow only receive ambient light
+        if in_shadow {
+            return ambient;
+        }
+
+        // Cosine of the angle between the light vector and the normal.
+        // If it is negative then the light is on the other side of the surface.
+        let light_dot_normal = light_vec.dot(&normal);
+
+        let (diffuse, specular) = if light_dot_normal < 0.0 {
+            (black, black)
+        } else {
+            let diffuse = effective_color * material.diffuse * light_dot_normal;
+
+            // Cosine of the angle between the reflection vector and the eye vector.
+            // If it is negative then the light reflects away from the eye.
+            let reflect_vec = (-light_vec).reflect(&normal);
+            let reflect_dot_eye = reflect_vec.dot(&eye);
+
+            let specular = if reflect_dot_eye <= 0.0 {
+                black
+            } else {
+                let factor = reflect_dot_eye.powf(material.shininess);
+                light.intensity * material.specular * factor
+            };
+
+            (diffuse, specular)
+        };
+
+        ambient + diffuse + specular
+    }
+
+    #[cfg(test)]
+    mod lighting_tests {
+        use super::*;
+        use super::super::rays::Sphere;
+        use super::super::patterns::StripePattern;
+
+        fn setup() -> (Material, Sphere, Vec4) {
+            (Material::new_material(), Sphere::new_sphere(0), Vec4::new_point(0.0, 0.0, 0.0))
+        }
+
+        #[test]
+        fn default_material() {
+            let m = Material::new_material();
+
+            assert_eq!(m.color, Color::new(1.0, 1.0, 1.0));
+            assert_eq!(m.ambient, 0.1);
+            assert_eq!(m.diffuse, 0.9);
+            assert_eq!(m.specular, 0.9);
+            assert_eq!(m.shininess, 200.0);
+            assert_eq!(m.reflective, 0.0);
+            assert_eq!(m.transparency, 0.0);
+            assert_eq!(m.refractive_index, 1.0);
+        }
+
+        #[test]
+        fn create_point_light() {
+            let intensity = Color::new(1.0, 1.0, 1.0);
+            let position = Vec4::new_point(0.0, 0.0, 0.0);
+
+            let light = PointLight::new_point_light(position, intensity);
+
+            assert_eq!(light.position, position);
+            assert_eq!(light.intensity, intensity);
+        }
+
+        #[test]
+        fn eye_between_light_and_surface() {
+            let (m, s, position) = setup();
+
+            let eye = Vec4::new_vec(0.0, 0.0, -1.0);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 0.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, false), Color::new(1.9, 1.9, 1.9));
+        }
+
+        #[test]
+        fn eye_offset_45_degrees() {
+            let (m, s, position) = setup();
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let eye = Vec4::new_vec(0.0, a, -a);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 0.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, false), Color::new(1.0, 1.0, 1.0));
+        }
+
+        #[test]
+        fn light_offset_45_degrees() {
+            let (m, s, position) = setup();
+
+            let eye = Vec4::new_vec(0.0, 0.0, -1.0);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 10.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, false), Color::new(0.7364, 0.7364, 0.7364));
+        }
+
+        #[test]
+        fn eye_in_path_of_reflection() {
+            let (m, s, position) = setup();
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let eye = Vec4::new_vec(0.0, -a, -a);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 10.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, false), Color::new(1.6364, 1.6364, 1.6364));
+        }
+
+        #[test]
+        fn light_behind_surface() {
+            let (m, s, position) = setup();
+
+            let eye = Vec4::new_vec(0.0, 0.0, -1.0);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 0.0, 10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            // Only the ambient component should remain
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, false), Color::new(0.1, 0.1, 0.1));
+        }
+
+        #[test]
+        fn surface_in_shadow() {
+            let (m, s, position) = setup();
+
+            let eye = Vec4::new_vec(0.0, 0.0, -1.0);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 0.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            assert_eq!(lighting(&m, &s, &light, position, eye, normal, true), Color::new(0.1, 0.1, 0.1));
+        }
+
+        #[test]
+        fn lighting_with_pattern() {
+            let (mut m, s, _) = setup();
+            m.set_pattern(StripePattern::new_stripe_pattern(Color::new(1.0, 1.0, 1.0), Color::new(0.0, 0.0, 0.0)));
+            m.ambient = 1.0;
+            m.diffuse = 0.0;
+            m.specular = 0.0;
+
+            let eye = Vec4::new_vec(0.0, 0.0, -1.0);
+            let normal = Vec4::new_vec(0.0, 0.0, -1.0);
+            let light = PointLight::new_point_light(Vec4::new_point(0.0, 0.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            // The pattern replaces the material's color
+            assert_eq!(lighting(&m, &s, &light, Vec4::new_point(0.9, 0.0, 0.0), eye, normal, false),
+                       Color::new(1.0, 1.0, 1.0));
+            assert_eq!(lighting(&m, &s, &light, Vec4::new_point(1.1, 0.0, 0.0), eye, normal, false),
+                       Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn cloned_materials_share_patterns() {
+            let mut m = Material::new_material();
+            m.set_pattern(StripePattern::new_stripe_pattern(Color::new(1.0, 1.0, 1.0), Color::new(0.0, 0.0, 0.0)));
+
+            assert_eq!(m.clone(), m);
+
+            let mut other = Material::new_material();
+            other.set_pattern(StripePattern::new_stripe_pattern(Color::new(1.0, 1.0, 1.0), Color::new(0.0, 0.0, 0.0)));
+            assert_ne!(other, m);
+        }
+    }
+}
+
+pub mod world {
+    use super::rt_prelude::{Vec4, Color, EPSILON};
+    use super::matrices::Mat4;
+    use super::rays::{Ray, Shape, Sphere, Intersection, get_intersection, get_hit, same_shape};
+    use super::materials::{PointLight, lighting};
+
+    // Default number of times a ray may bounce between reflective surfaces
+    pub const DEFAULT_MAX_DEPTH: usize = 5;
+
+    pub struct World {
+        pub objects: Vec<Box<dyn Shape>>,
+        pub lights: Vec<PointLight>,
+        // Limits recursion between reflective surfaces, which would otherwise never end between parallel mirrors
+        pub max_depth: usize,
+    }
+
+    impl World {
+        pub fn new_world() -> World {
+            World { objects: Vec::new(), lights: Vec::new(), max_depth: DEFAULT_MAX_DEPTH }
+        }
+
+        // Two concentric spheres lit by a single white light. Handy for testing.
+        pub fn default_world() -> World {
+            let mut s1 = Sphere::new_sphere(0);
+            s1.material_mut().color = Color::new(0.8, 1.0, 0.6);
+            s1.material_mut().diffuse = 0.7;
+            s1.material_mut().specular = 0.2;
+
+            let mut s2 = Sphere::new_sphere(1);
+            s2.set_transform(Mat4::new_scaling(0.5, 0.5, 0.5));
+
+            let light = PointLight::new_point_light(Vec4::new_point(-10.0, 10.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0));
+
+            World { objects: vec![Box::new(s1), Box::new(s2)], lights: vec![light], max_depth: DEFAULT_MAX_DEPTH }
+        }
+
+        pub fn add_object<S: Shape + 'static>(&mut self, object: S) {
+            self.objects.push(Box::new(object));
+        }
+
+        pub fn add_light(&mut self, light: PointLight) {
+            self.lights.push(light);
+        }
+
+        // Intersects the ray with every object in the world. Intersections are sorted by t.
+        pub fn intersect_world(&self, ray: &Ray) -> Vec<Intersection<'_>> {
+            let mut intersections: Vec<Intersection> = Vec::new();
+
+            for object in self.objects.iter() {
+                intersections.append(&mut get_intersection(object.as_ref(), ray));
+            }
+
+            intersections.sort_by(|a, b| a.t.total_cmp(&b.t));
+
+            intersections
+        }
+
+        // Shades a hit. remaining is the number of further reflections that may be followed.
+        pub fn shade_hit(&self, comps: &Computations, remaining: usize) -> Color {
+            let mut color = Color::new(0.0, 0.0, 0.0);
+
+            // Each light contributes to the final color
+            for light in self.lights.iter() {
+                let in_shadow = self.is_shadowed(comps.over_point, light);
+
+                color = color + lighting(comps.object.material(), comps.object, light,
+                                         comps.over_point, comps.eyev, comps.normalv, in_shadow);
+            }
+
+            let reflected = self.reflected_color(comps, remaining);
+            let refracted = self.refracted_color(comps, remaining);
+
+            let material = comps.object.material();
+
+            // Surfaces that both reflect and refract split the light between the two, like glass does
+            if material.reflective > 0.0 && material.transparency > 0.0 {
+                let reflectance = schlick(comps);
+
+                color + reflected * reflectance + refracted * (1.0 - reflectance)
+            } else {
+                color + reflected + refracted
+            }
+        }
+
+        // Color seen in the reflection at a hit. Black for surfaces that are not reflective.
+        pub fn reflected_color(&self, comps: &Computations, remaining: usize) -> Color {
+            let reflective = comps.object.material().reflective;
+
+            if remaining == 0 || reflective == 0.0 {
+                return Color::new(0.0, 0.0, 0.0);
+            }
+
+            let reflect_ray = Ray::new_ray(comps.over_point, comps.reflectv);
+
+            self.color_at_depth(&reflect_ray, remaining - 1) * reflective
+        }
+
+        // Color seen through a hit. Black for opaque surfaces and under total internal reflection.
+        pub fn refracted_color(&self, comps: &Computations, remaining: usize) -> Color {
+            let transparency = comps.object.material().transparency;
+
+            if remaining == 0 || transparency == 0.0 {
+                return Color::new(0.0, 0.0, 0.0);
+            }
+
+            // Snell's law, sin(theta_t) / sin(theta_i) = n1 / n2
+            let n_ratio = comps.n1 / comps.n2;
+            let cos_i = comps.eyev.dot(&comps.normalv);
+            let sin2_t = n_ratio.powi(2) * (1.0 - cos_i.powi(2));
+
+            if sin2_t > 1.0 {
+                return Color::new(0.0, 0.0, 0.0);
+            }
+
+            let cos_t = (1.0 - sin2_t).sqrt();
+            let direction = comps.normalv * (n_ratio * cos_i - cos_t) - comps.eyev * n_ratio;
+            let refract_ray = Ray::new_ray(comps.under_point, direction);
+
+            self.color_at_depth(&refract_ray, remaining - 1) * transparency
+        }
+
+        // Casts a ray from the point toward the light. Anything hit before reaching the light casts a shadow.
+        pub fn is_shadowed(&self, point: Vec4, light: &PointLight) -> bool {
+            let point_to_light = light.position - point;
+            let distance = point_to_light.magnitude();
+
+            let shadow_ray = Ray::new_ray(point, point_to_light.normalized());
+            let intersections = self.intersect_world(&shadow_ray);
+
+            match get_hit(&intersections) {
+                Some(hit) => hit.t < distance,
+                None => false,
+            }
+        }
+
+        pub fn color_at(&self, ray: &Ray) -> Color {
+            self.color_at_depth(ray, self.max_depth)
+        }
+
+        // Same as color_at, but only follows remaining more reflections
+        pub fn color_at_depth(&self, ray: &Ray, remaining: usize) -> Color {
+            let intersections = self.intersect_world(ray);
+
+            match get_hit(&intersections) {
+                Some(hit) => self.shade_hit(&prepare_computations(hit, ray, &intersections), remaining),
+                None => Color::new(0.0, 0.0, 0.0),
+            }
+        }
+    }
+
+    // Precomputed information about an intersection that is needed for shading
+    pub struct Computations<'a> {
+        pub t: f64,
+        pub object: &'a dyn Shape,
+        pub point: Vec4,
+        // The hit point nudged slightly above the surface so that it does not shadow itself
+        pub over_point: Vec4,
+        // The hit point nudged slightly below the surface, where refracted rays start
+        pub under_point: Vec4,
+        pub eyev: Vec4,
+        pub normalv: Vec4,
+        // The ray's direction reflected around the normal
+        pub reflectv: Vec4,
+        pub inside: bool,
+        // Refractive indices of the materials the ray is leaving and entering
+        pub n1: f64,
+        pub n2: f64,
+    }
+
+    // Finds the refractive indices on either side of the hit by walking the intersections up to it.
+    // Every intersection enters or leaves an object, so the objects the ray is inside are tracked.
+    fn refractive_indices(hit: &Intersection, intersections: &[Intersection]) -> (f64, f64) {
+        let mut containers: Vec<&dyn Shape> = Vec::new();
+        let mut n1 = 1.0;
+
+        for intersection in intersections.iter() {
+            let is_hit = intersection.t == hit.t && same_shape(intersection.object, hit.object);
+
+            if is_hit {
+                n1 = containers.last().map_or(1.0, |object| object.material().refractive_index);
+            }
+
+            match containers.iter().position(|object| same_shape(*object, intersection.object)) {
+                Some(index) => {
+                    containers.remove(index);
+                }
+                None => containers.push(intersection.object),
+            }
+
+            if is_hit {
+                let n2 = containers.last().map_or(1.0, |object| object.material().refractive_index);
+                return (n1, n2);
+            }
+        }
+
+        // The hit is not in the list, so assume it is surrounded by a vacuum
+        (1.0, 1.0)
+    }
+
+    // intersections is every intersection along the ray, which is needed to know what the hit is inside of
+    pub fn prepare_computations<'a>(intersection: &Intersection<'a>, ray: &Ray,
+                                    intersections: &[Intersection]) -> Computations<'a> {
+        let object = intersection.object;
+
+        let point = ray.position(intersection.t);
+        let eyev = -ray.direction;
+        let mut normalv = object.normal_at_hit(point, intersection);
+
+        // If the normal points away from the eye then the hit occurred inside the object
+        let inside = normalv.dot(&eyev) < 0.0;
+        if inside {
+            normalv = -normalv;
+        }
+
+        let over_point = point + normalv * EPSILON;
+        let under_point = point - normalv * EPSILON;
+        let reflectv = ray.direction.reflect(&normalv);
+        let (n1, n2) = refractive_indices(intersection, intersections);
+
+        Computations {
+            t: intersection.t,
+            object,
+            point,
+            over_point,
+            under_point,
+            eyev,
+            normalv,
+            reflectv,
+            inside,
+            n1,
+            n2,
+        }
+    }
+
+    // Schlick's approximation of the Fresnel effect. Returns the fraction of light that is reflected.
+    pub fn schlick(comps: &Computations) -> f64 {
+        let mut cos = comps.eyev.dot(&comps.normalv);
+
+        // Total internal reflection can only happen when going into a less dense material
+        if comps.n1 > comps.n2 {
+            let n_ratio = comps.n1 / comps.n2;
+            let sin2_t = n_ratio.powi(2) * (1.0 - cos.powi(2));
+
+            if sin2_t > 1.0 {
+                return 1.0;
+            }
+
+            cos = (1.0 - sin2_t).sqrt();
+        }
+
+        let r0 = ((comps.n1 - comps.n2) / (comps.n1 + comps.n2)).powi(2);
+
+        r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+    }
+
+    #[cfg(test)]
+    mod world_tests {
+        use super::*;
+        use super::super::materials::Material;
+        use super::super::shapes::Plane;
+        use super::super::rt_prelude::equal_approx;
+
+        #[test]
+        fn create_world() {
+            let w = World::new_world();
+
+            assert!(w.objects.is_empty());
+            assert!(w.lights.is_empty());
+        }
+
+        #[test]
+        fn create_default_world() {
+            let w = World::default_world();
+
+            assert_eq!(w.objects.len(), 2);
+            assert_eq!(w.lights, vec![PointLight::new_point_light(Vec4::new_point(-10.0, 10.0, -10.0),
+                                                                  Color::new(1.0, 1.0, 1.0))]);
+
+            let mut m = Material::new_material();
+            m.color = Color::new(0.8, 1.0, 0.6);
+            m.diffuse = 0.7;
+            m.specular = 0.2;
+            assert_eq!(*w.objects[0].material(), m);
+            assert_eq!(*w.objects[1].transform(), Mat4::new_scaling(0.5, 0.5, 0.5));
+        }
+
+        #[test]
+        fn add_to_world() {
+            let mut w = World::new_world();
+
+            w.add_object(Sphere::new_sphere(0));
+            w.add_light(PointLight::new_point_light(Vec4::new_point(0.0, 0.0, 0.0),
+                                                    Color::new(1.0, 1.0, 1.0)));
+
+            assert_eq!(w.objects.len(), 1);
+            assert_eq!(w.lights.len(), 1);
+        }
+
+        #[test]
+        fn intersect_world_sorted() {
+            let w = World::default_world();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            let xs = w.intersect_world(&r);
+
+            assert_eq!(xs.len(), 4);
+            assert_eq!(xs[0].t, 4.0);
+            assert_eq!(xs[1].t, 4.5);
+            assert_eq!(xs[2].t, 5.5);
+            assert_eq!(xs[3].t, 6.0);
+        }
+
+        #[test]
+        fn precompute_intersection_state() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let s = Sphere::new_sphere(0);
+            let i = Intersection::new_intersection(4.0, &s);
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(comps.t, i.t);
+            assert!(same_shape(comps.object, &s));
+            assert_eq!(comps.point, Vec4::new_point(0.0, 0.0, -1.0));
+            assert_eq!(comps.eyev, Vec4::new_vec(0.0, 0.0, -1.0));
+            assert_eq!(comps.normalv, Vec4::new_vec(0.0, 0.0, -1.0));
+            assert!(!comps.inside);
+        }
+
+        #[test]
+        fn precompute_hit_inside() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let s = Sphere::new_sphere(0);
+            let i = Intersection::new_intersection(1.0, &s);
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(comps.point, Vec4::new_point(0.0, 0.0, 1.0));
+            assert_eq!(comps.eyev, Vec4::new_vec(0.0, 0.0, -1.0));
+            assert!(comps.inside);
+            // The normal is inverted so that it points towards the eye
+            assert_eq!(comps.normalv, Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn shade_intersection() {
+            let w = World::default_world();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(4.0, w.objects[0].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.38066, 0.47583, 0.2855));
+        }
+
+        #[test]
+        fn shade_intersection_inside() {
+            let mut w = World::default_world();
+            w.lights = vec![PointLight::new_point_light(Vec4::new_point(0.0, 0.25, 0.0),
+                                                        Color::new(1.0, 1.0, 1.0))];
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(0.5, w.objects[1].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.90498, 0.90498, 0.90498));
+        }
+
+        #[test]
+        fn shade_with_multiple_lights() {
+            let mut w = World::default_world();
+            let light = w.lights[0];
+            w.add_light(light);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            // Two identical lights should contribute twice the color of one
+            assert_eq!(w.color_at(&r), Color::new(0.38066, 0.47583, 0.2855) * 2.0);
+        }
+
+        #[test]
+        fn color_ray_misses() {
+            let w = World::default_world();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+
+            assert_eq!(w.color_at(&r), Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn color_ray_hits() {
+            let w = World::default_world();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert_eq!(w.color_at(&r), Color::new(0.38066, 0.47583, 0.2855));
+        }
+
+        #[test]
+        fn color_intersection_behind_ray() {
+            let mut w = World::default_world();
+            w.objects[0].material_mut().ambient = 1.0;
+            w.objects[1].material_mut().ambient = 1.0;
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.75),
+                                 Vec4::new_vec(0.0, 0.0, -1.0));
+
+            // The ray starts between the spheres, so it should only see the inner sphere
+            assert_eq!(w.color_at(&r), w.objects[1].material().color);
+        }
+
+        #[test]
+        fn no_shadow_when_nothing_collinear() {
+            let w = World::default_world();
+            let p = Vec4::new_point(0.0, 10.0, 0.0);
+
+            assert!(!w.is_shadowed(p, &w.lights[0]));
+        }
+
+        #[test]
+        fn shadow_when_object_between_point_and_light() {
+            let w = World::default_world();
+            let p = Vec4::new_point(10.0, -10.0, 10.0);
+
+            assert!(w.is_shadowed(p, &w.lights[0]));
+        }
+
+        #[test]
+        fn no_shadow_when_object_behind_light() {
+            let w = World::default_world();
+            let p = Vec4::new_point(-20.0, 20.0, -20.0);
+
+            assert!(!w.is_shadowed(p, &w.lights[0]));
+        }
+
+        #[test]
+        fn no_shadow_when_object_behind_point() {
+            let w = World::default_world();
+            let p = Vec4::new_point(-2.0, 2.0, -2.0);
+
+            assert!(!w.is_shadowed(p, &w.lights[0]));
+        }
+
+        #[test]
+        fn shade_intersection_in_shadow() {
+            let mut w = World::new_world();
+            w.add_light(PointLight::new_point_light(Vec4::new_point(0.0, 0.0, -10.0),
+                                                    Color::new(1.0, 1.0, 1.0)));
+
+            let mut s2 = Sphere::new_sphere(1);
+            s2.set_transform(Mat4::new_translation(0.0, 0.0, 10.0));
+
+            w.add_object(Sphere::new_sphere(0));
+            w.add_object(s2);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(4.0, w.objects[1].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.1, 0.1, 0.1));
+        }
+
+        #[test]
+        fn hit_offsets_point() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(5.0, &s);
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert!(comps.over_point.z < -EPSILON / 2.0);
+            assert!(comps.point.z > comps.over_point.z);
+        }
+
+        // The default world with a reflective plane below the spheres
+        fn world_with_mirror_floor(reflective: f64) -> World {
+            let mut w = World::default_world();
+
+            let mut floor = Plane::new_plane();
+            floor.material_mut().reflective = reflective;
+            floor.set_transform(Mat4::new_translation(0.0, -1.0, 0.0));
+            w.add_object(floor);
+
+            w
+        }
+
+        fn ray_towards_floor() -> Ray {
+            let a = 2.0_f64.sqrt() / 2.0;
+
+            Ray::new_ray(Vec4::new_point(0.0, 0.0, -3.0), Vec4::new_vec(0.0, -a, a))
+        }
+
+        #[test]
+        fn precompute_reflection_vector() {
+            let a = 2.0_f64.sqrt() / 2.0;
+            let p = Plane::new_plane();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 1.0, -1.0),
+                                 Vec4::new_vec(0.0, -a, a));
+            let i = Intersection::new_intersection(2.0_f64.sqrt(), &p);
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(comps.reflectv, Vec4::new_vec(0.0, a, a));
+        }
+
+        #[test]
+        fn reflected_color_for_nonreflective_material() {
+            let mut w = World::default_world();
+            w.objects[1].material_mut().ambient = 1.0;
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(1.0, w.objects[1].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.reflected_color(&comps, w.max_depth), Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn reflected_color_for_reflective_material() {
+            let w = world_with_mirror_floor(0.5);
+            let r = ray_towards_floor();
+            let i = Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.reflected_color(&comps, w.max_depth), Color::new(0.19033, 0.23791, 0.14274));
+        }
+
+        #[test]
+        fn shade_hit_with_reflective_material() {
+            let w = world_with_mirror_floor(0.5);
+            let r = ray_towards_floor();
+            let i = Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.87676, 0.92435, 0.82917));
+        }
+
+        #[test]
+        fn mutually_reflective_surfaces_terminate() {
+            let mut w = World::new_world();
+            w.add_light(PointLight::new_point_light(Vec4::new_point(0.0, 0.0, 0.0),
+                                                    Color::new(1.0, 1.0, 1.0)));
+
+            let mut lower = Plane::new_plane();
+            lower.material_mut().reflective = 1.0;
+            lower.set_transform(Mat4::new_translation(0.0, -1.0, 0.0));
+
+            let mut upper = Plane::new_plane();
+            upper.material_mut().reflective = 1.0;
+            upper.set_transform(Mat4::new_translation(0.0, 1.0, 0.0));
+
+            w.add_object(lower);
+            w.add_object(upper);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+
+            // Each bounce adds the ambient and diffuse light of the next plane
+            let color = w.color_at(&r);
+            assert!(color.r > 0.0 && color.r.is_finite());
+        }
+
+        #[test]
+        fn reflected_color_at_maximum_depth() {
+            let w = world_with_mirror_floor(0.5);
+            let r = ray_towards_floor();
+            let i = Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref());
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(w.reflected_color(&comps, 0), Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn max_depth_limits_bounces() {
+            let mut w = world_with_mirror_floor(1.0);
+            let r = ray_towards_floor();
+
+            // With no bounces left the floor only shows its own shading
+            w.max_depth = 0;
+            let without_reflection = w.color_at(&r);
+            w.max_depth = 1;
+            let with_reflection = w.color_at(&r);
+
+            let i = Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref());
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert_eq!(with_reflection, without_reflection + w.reflected_color(&comps, 1));
+            assert_eq!(w.color_at_depth(&r, 0), without_reflection);
+        }
+
+        #[test]
+        fn glass_sphere() {
+            let s = Sphere::new_glass_sphere(0);
+
+            assert_eq!(*s.transform(), Mat4::id());
+            assert_eq!(s.material().transparency, 1.0);
+            assert_eq!(s.material().refractive_index, 1.5);
+        }
+
+        #[test]
+        fn find_n1_and_n2() {
+            let mut a = Sphere::new_glass_sphere(0);
+            a.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            a.material_mut().refractive_index = 1.5;
+
+            let mut b = Sphere::new_glass_sphere(1);
+            b.set_transform(Mat4::new_translation(0.0, 0.0, -0.25));
+            b.material_mut().refractive_index = 2.0;
+
+            let mut c = Sphere::new_glass_sphere(2);
+            c.set_transform(Mat4::new_translation(0.0, 0.0, 0.25));
+            c.material_mut().refractive_index = 2.5;
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -4.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = [Intersection::new_intersection(2.0, &a),
+                      Intersection::new_intersection(2.75, &b),
+                      Intersection::new_intersection(3.25, &c),
+                      Intersection::new_intersection(4.75, &b),
+                      Intersection::new_intersection(5.25, &c),
+                      Intersection::new_intersection(6.0, &a)];
+
+            let expected = [(1.0, 1.5), (1.5, 2.0), (2.0, 2.5), (2.5, 2.5), (2.5, 1.5), (1.5, 1.0)];
+
+            for (i, (n1, n2)) in xs.iter().zip(expected.iter()) {
+                let comps = prepare_computations(i, &r, &xs);
+
+                assert_eq!(comps.n1, *n1);
+                assert_eq!(comps.n2, *n2);
+            }
+        }
+
+        #[test]
+        fn under_point_below_surface() {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let mut s = Sphere::new_glass_sphere(0);
+            s.set_transform(Mat4::new_translation(0.0, 0.0, 1.0));
+            let i = Intersection::new_intersection(5.0, &s);
+
+            let comps = prepare_computations(&i, &r, &[i]);
+
+            assert!(comps.under_point.z > EPSILON / 2.0);
+            assert!(comps.point.z < comps.under_point.z);
+        }
+
+        #[test]
+        fn refracted_color_of_opaque_surface() {
+            let w = World::default_world();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = [Intersection::new_intersection(4.0, w.objects[0].as_ref()),
+                      Intersection::new_intersection(6.0, w.objects[0].as_ref())];
+
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert_eq!(w.refracted_color(&comps, w.max_depth), Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn refracted_color_at_maximum_depth() {
+            let mut w = World::default_world();
+            w.objects[0].material_mut().transparency = 1.0;
+            w.objects[0].material_mut().refractive_index = 1.5;
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = [Intersection::new_intersection(4.0, w.objects[0].as_ref()),
+                      Intersection::new_intersection(6.0, w.objects[0].as_ref())];
+
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert_eq!(w.refracted_color(&comps, 0), Color::new(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn refracted_color_under_total_internal_reflection() {
+            let mut w = World::default_world();
+            w.objects[0].material_mut().transparency = 1.0;
+            w.objects[0].material_mut().refractive_index = 1.5;
+
+            let a = 2.0_f64.sqrt() / 2.0;
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, a),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+            let xs = [Intersection::new_intersection(-a, w.objects[0].as_ref()),
+                      Intersection::new_intersection(a, w.objects[0].as_ref())];
+
+            // The ray starts inside the sphere, so the hit is the second intersection
+            let comps = prepare_computations(&xs[1], &r, &xs);
+
+            assert_eq!(w.refracted_color(&comps, w.max_depth), Color::new(0.0, 0.0, 0.0));
+        }
+
+        // The default world with a transparent floor and a red ball below it
+        fn world_with_glass_floor(reflective: f64) -> World {
+            let mut w = World::default_world();
+
+            let mut floor = Plane::new_plane();
+            floor.set_transform(Mat4::new_translation(0.0, -1.0, 0.0));
+            floor.material_mut().reflective = reflective;
+            floor.material_mut().transparency = 0.5;
+            floor.material_mut().refractive_index = 1.5;
+            w.add_object(floor);
+
+            let mut ball = Sphere::new_sphere(2);
+            ball.material_mut().color = Color::new(1.0, 0.0, 0.0);
+            ball.material_mut().ambient = 0.5;
+            ball.set_transform(Mat4::new_translation(0.0, -3.5, -0.5));
+            w.add_object(ball);
+
+            w
+        }
+
+        #[test]
+        fn shade_hit_with_transparent_material() {
+            let w = world_with_glass_floor(0.0);
+            let r = ray_towards_floor();
+            let xs = [Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref())];
+
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.93642, 0.68642, 0.68642));
+        }
+
+        #[test]
+        fn shade_hit_with_reflective_transparent_material() {
+            let w = world_with_glass_floor(0.5);
+            let r = ray_towards_floor();
+            let xs = [Intersection::new_intersection(2.0_f64.sqrt(), w.objects[2].as_ref())];
+
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert_eq!(w.shade_hit(&comps, w.max_depth), Color::new(0.93391, 0.69643, 0.69243));
+        }
+
+        #[test]
+        fn schlick_under_total_internal_reflection() {
+            let s = Sphere::new_glass_sphere(0);
+            let a = 2.0_f64.sqrt() / 2.0;
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, a),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+            let xs = [Intersection::new_intersection(-a, &s),
+                      Intersection::new_intersection(a, &s)];
+
+            let comps = prepare_computations(&xs[1], &r, &xs);
+
+            assert_eq!(schlick(&comps), 1.0);
+        }
+
+        #[test]
+        fn schlick_with_perpendicular_ray() {
+            let s = Sphere::new_glass_sphere(0);
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+            let xs = [Intersection::new_intersection(-1.0, &s),
+                      Intersection::new_intersection(1.0, &s)];
+
+            let comps = prepare_computations(&xs[1], &r, &xs);
+
+            assert!(equal_approx(schlick(&comps), 0.04));
+        }
+
+        #[test]
+        fn schlick_with_small_angle() {
+            let s = Sphere::new_glass_sphere(0);
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.99, -2.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = [Intersection::new_intersection(1.8589, &s)];
+
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert!(equal_approx(schlick(&comps), 0.48873));
+        }
+    }
+}
+
+pub mod camera {
+    use super::rt_prelude::{Vec4, Color, Canvas};
+    use super::matrices::Mat4;
+    use super::rays::Ray;
+    use super::world::World;
+    use std::error::Error;
+    use std::fmt;
+
+    // Orients the world relative to an eye at `from` looking towards `to`
+    pub fn view_transform(from: Vec4, to: Vec4, up: Vec4) -> Mat4 {
+        let forward = (to - from).normalized();
+        let left = forward.cross(&up.normalized());
+        // Recompute up so that it is exactly perpendicular to forward and left
+        let true_up = left.cross(&forward);
+
+        let orientation = Mat4::new([
+            [left.x, left.y, left.z, 0.0],
+            [true_up.x, true_up.y, true_up.z, 0.0],
+            [-forward.x, -forward.y, -forward.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0]]);
+
+        orientation.translate(-from.x, -from.y, -from.z)
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum CameraError {
+        // The transform cannot be inverted, e.g. a view_transform whose up vector points along the view
+        // direction, or whose from and to are the same point
+        NonInvertibleTransform,
+    }
+
+    impl fmt::Display for CameraError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                CameraError::NonInvertibleTransform => write!(f, "camera transform is not invertible"),
+            }
+        }
+    }
+
+    impl Error for CameraError {}
+
+    pub struct Camera {
+        pub hsize: usize,
+        pub vsize: usize,
+        pub field_of_view: f64,
+        transform: Mat4,
+        transform_inverted: Mat4,
+    }
+
+    impl Camera {
+        pub fn new_camera(hsize: usize, vsize: usize, field_of_view: f64) -> Camera {
+            Camera { hsize, vsize, field_of_view, transform: Mat4::id(), transform_inverted: Mat4::id() }
+        }
+
+        pub fn transform(&self) -> &Mat4 {
+            &self.transform
+        }
+
+        // Leaves the camera unchanged if the transform cannot be inverted, since no rays could be cast from it
+        pub fn set_transform(&mut self, mat: Mat4) -> Result<(), CameraError> {
+            // A view_transform from a point to itself is full of NaNs, which still "invert" to more NaNs
+            let transform_inverted = mat.inverted()
+                .filter(|inverted| inverted.data.iter().flatten().all(|value| value.is_finite()))
+                .ok_or(CameraError::NonInvertibleTransform)?;
+
+            self.transform = mat;
+            self.transform_inverted = transform_inverted;
+
+            Ok(())
+        }
+
+        // Half the width and height of the canvas one unit in front of the camera, in world units
+        fn half_extents(&self) -> (f64, f64) {
+            let half_view = (self.field_of_view / 2.0).tan();
+            let aspect = self.hsize as f64 / self.vsize as f64;
+
+            if aspect >= 1.0 {
+                (half_view, half_view / aspect)
+            } else {
+                (half_view * aspect, half_view)
+            }
+        }
+
+        // Size of a single pixel in world units, one unit in front of the camera
+        pub fn pixel_size(&self) -> f64 {
+            let (half_width, _) = self.half_extents();
+            (half_width * 2.0) / self.hsize as f64
+        }
+
+        pub fn ray_for_pixel(&self, px: usize, py: usize) -> Ray {
+            let (half_width, half_height) = self.half_extents();
+            let pixel_size = (half_width * 2.0) / self.hsize as f64;
+
+            // Offset from the edge of the canvas to the pixel's center
+            let x_offset = (px as f64 + 0.5) * pixel_size;
+            let y_offset = (py as f64 + 0.5) * pixel_size;
+
+            // The camera looks toward -z, so +x is to the left
+            let world_x = half_width - x_offset;
+            let world_y = half_height - y_offset;
+
+            let pixel = self.transform_inverted * Vec4::new_point(world_x, world_y, -1.0);
+            let origin = self.transform_inverted * Vec4::new_point(0.0, 0.0, 0.0);
+            let direction = (pixel - origin).normalized();
+
+            Ray::new_ray(origin, direction)
+        }
+
+        pub fn render(&self, world: &World) -> Canvas {
+            let mut image = Canvas::new(self.hsize, self.vsize, Color::new(0.0, 0.0, 0.0));
+
+            for y in 0..self.vsize {
+                for x in 0..self.hsize {
+                    let ray = self.ray_for_pixel(x, y);
+                    let color = world.color_at(&ray);
+
+                    image.write_pixel(x, y, &color);
+                }
+            }
+
+            image
+        }
+    }
+
+    #[cfg(test)]
+    mod camera_tests {
+        use super::*;
+        use super::super::rt_prelude::equal_approx;
+        use std::f64::consts::PI;
+
+        #[test]
+        fn default_view_transform() {
+            let from = Vec4::new_point(0.0, 0.0, 0.0);
+            let to = Vec4::new_point(0.0, 0.0, -1.0);
+            let up = Vec4::new_vec(0.0, 1.0, 0.0);
+
+            assert_eq!(view_transform(from, to, up), Mat4::id());
+        }
+
+        #[test]
+        fn view_transform_positive_z() {
+            let from = Vec4::new_point(0.0, 0.0, 0.0);
+            let to = Vec4::new_point(0.0, 0.0, 1.0);
+            let up = Vec4::new_vec(0.0, 1.0, 0.0);
+
+            // Looking backwards mirrors the world front to back and left to right
+            assert_eq!(view_transform(from, to, up), Mat4::new_scaling(-1.0, 1.0, -1.0));
+        }
+
+        #[test]
+        fn view_transform_moves_world() {
+            let from = Vec4::new_point(0.0, 0.0, 8.0);
+            let to = Vec4::new_point(0.0, 0.0, 0.0);
+            let up = Vec4::new_vec(0.0, 1.0, 0.0);
+
+            assert_eq!(view_transform(from, to, up), Mat4::new_translation(0.0, 0.0, -8.0));
+        }
+
+        #[test]
+        fn arbitrary_view_transform() {
+            let from = Vec4::new_point(1.0, 3.0, 2.0);
+            let to = Vec4::new_point(4.0, -2.0, 8.0);
+            let up = Vec4::new_vec(1.0, 1.0, 0.0);
+
+            let t = view_transform(from, to, up);
+
+            assert!(t.equal_approx(&Mat4::new([
+                [-0.50709, 0.50709, 0.67612, -2.36643],
+                [0.76772, 0.60609, 0.12122, -2.82843],
+                [-0.35857, 0.59761, -0.71714, 0.0],
+                [0.0, 0.0, 0.0, 1.0]])));
+        }
+
+        #[test]
+        fn create_camera() {
+            let c = Camera::new_camera(160, 120, PI / 2.0);
+
+            assert_eq!(c.hsize, 160);
+            assert_eq!(c.vsize, 120);
+            assert_eq!(c.field_of_view, PI / 2.0);
+            assert_eq!(*c.transform(), Mat4::id());
+        }
+
+        #[test]
+        fn pixel_size_horizontal_canvas() {
+            let c = Camera::new_camera(200, 125, PI / 2.0);
+            assert!(equal_approx(c.pixel_size(), 0.01));
+        }
+
+        #[test]
+        fn pixel_size_vertical_canvas() {
+            let c = Camera::new_camera(125, 200, PI / 2.0);
+            assert!(equal_approx(c.pixel_size(), 0.01));
+        }
+
+        #[test]
+        fn ray_through_center_of_canvas() {
+            let c = Camera::new_camera(201, 101, PI / 2.0);
+            let r = c.ray_for_pixel(100, 50);
+
+            assert_eq!(r.origin, Vec4::new_point(0.0, 0.0, 0.0));
+            assert_eq!(r.direction, Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn ray_through_corner_of_canvas() {
+            let c = Camera::new_camera(201, 101, PI / 2.0);
+            let r = c.ray_for_pixel(0, 0);
+
+            assert_eq!(r.origin, Vec4::new_point(0.0, 0.0, 0.0));
+            assert_eq!(r.direction, Vec4::new_vec(0.66519, 0.33259, -0.66851));
+        }
+
+        #[test]
+        fn ray_with_transformed_camera() {
+            let mut c = Camera::new_camera(201, 101, PI / 2.0);
+            c.set_transform(Mat4::new_rotation_y(PI / 4.0) * Mat4::new_translation(0.0, -2.0, 5.0)).unwrap();
+
+            let r = c.ray_for_pixel(100, 50);
+            let a = 2.0_f64.sqrt() / 2.0;
+
+            assert_eq!(r.origin, Vec4::new_point(0.0, 2.0, -5.0));
+            assert_eq!(r.direction, Vec4::new_vec(a, 0.0, -a));
+        }
+
+        #[test]
+        fn render_world() {
+            let w = World::default_world();
+            let mut c = Camera::new_camera(11, 11, PI / 2.0);
+
+            let from = Vec4::new_point(0.0, 0.0, -5.0);
+            let to = Vec4::new_point(0.0, 0.0, 0.0);
+            let up = Vec4::new_vec(0.0, 1.0, 0.0);
+            c.set_transform(view_transform(from, to, up)).unwrap();
+
+            let image = c.render(&w);
+
+            assert_eq!(image.width, 11);
+            assert_eq!(image.height, 11);
+            assert_eq!(image.read_pixel(5, 5), Color::new(0.38066, 0.47583, 0.2855));
+        }
+
+        #[test]
+        fn reject_degenerate_view_transform() {
+            let mut c = Camera::new_camera(11, 11, PI / 2.0);
+            let from = Vec4::new_point(0.0, 0.0, -5.0);
+            let to = Vec4::new_point(0.0, 0.0, 0.0);
+
+            // (from, to, up)
+            let cases = [
+                (from, from, Vec4::new_vec(0.0, 1.0, 0.0)),
+                (from, to, Vec4::new_vec(0.0, 0.0, 1.0)),
+                (from, to, Vec4::new_vec(0.0, 0.0, 0.0)),
+            ];
+
+            for (from, to, up) in cases.iter() {
+                assert_eq!(c.set_transform(view_transform(*from, *to, *up)), Err(CameraError::NonInvertibleTransform));
+            }
+
+            // The camera keeps its last good transform
+            assert_eq!(*c.transform(), Mat4::id());
+            assert_eq!(c.ray_for_pixel(5, 5).direction, Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+    }
+}
+
+pub mod shapes {
+    use super::rt_prelude::{Vec4, EPSILON};
+    use super::rays::{Ray, Shape, ShapeData, Intersection, get_intersection};
+    use super::bounds::Aabb;
+
+    // An infinite plane. In object space it is the xz plane.
+    pub struct Plane {
+        data: ShapeData,
+    }
+
+    impl Plane {
+        pub fn new_plane() -> Plane {
+            Plane { data: ShapeData::new_shape_data() }
+        }
+    }
+
+    impl Shape for Plane {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            // A ray parallel to the plane never hits it. A coplanar ray would hit it infinitely
+            // many times, but the plane is infinitely thin so it is treated as a miss too.
+            if ray.direction.y.abs() < EPSILON {
+                return Vec::new();
+            }
+
+            let t = -ray.origin.y / ray.direction.y;
+
+            vec![Intersection::new_intersection(t, self)]
+        }
+
+        fn local_normal_at(&self, _point: Vec4) -> Vec4 {
+            // The normal is the same everywhere on the plane
+            Vec4::new_vec(0.0, 1.0, 0.0)
+        }
+
+        fn bounds(&self) -> Aabb {
+            Aabb::new_aabb(Vec4::new_point(f64::NEG_INFINITY, 0.0, f64::NEG_INFINITY),
+                           Vec4::new_point(f64::INFINITY, 0.0, f64::INFINITY))
+        }
+    }
+
+    // An axis-aligned cube spanning -1 to 1 on each axis in object space
+    pub struct Cube {
+        data: ShapeData,
+    }
+
+    impl Cube {
+        pub fn new_cube() -> Cube {
+            Cube { data: ShapeData::new_shape_data() }
+        }
+    }
+
+    impl Shape for Cube {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            // The cube is exactly its own bounding box
+            match self.bounds().intersection_range(ray) {
+                Some((tmin, tmax)) => vec![Intersection::new_intersection(tmin, self),
+                                           Intersection::new_intersection(tmax, self)],
+                None => Vec::new(),
+            }
+        }
+
+        fn local_normal_at(&self, point: Vec4) -> Vec4 {
+            // The face that was hit is the one along the axis with the largest component
+            let abs_x = point.x.abs();
+            let abs_y = point.y.abs();
+            let abs_z = point.z.abs();
+            let max_c = abs_x.max(abs_y).max(abs_z);
+
+            if max_c == abs_x {
+                Vec4::new_vec(point.x, 0.0, 0.0)
+            } else if max_c == abs_y {
+                Vec4::new_vec(0.0, point.y, 0.0)
+            } else {
+                Vec4::new_vec(0.0, 0.0, point.z)
+            }
+        }
+
+        fn bounds(&self) -> Aabb {
+            Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0))
+        }
+    }
+
+    // A cylinder of radius 1 around the y axis, truncated at minimum and maximum (exclusive)
+    pub struct Cylinder {
+        data: ShapeData,
+        pub minimum: f64,
+        pub maximum: f64,
+        pub closed: bool,
+    }
+
+    impl Cylinder {
+        // Creates an infinitely long, open cylinder
+        pub fn new_cylinder() -> Cylinder {
+            Cylinder {
+                data: ShapeData::new_shape_data(),
+                minimum: f64::NEG_INFINITY,
+                maximum: f64::INFINITY,
+                closed: false,
+            }
+        }
+    }
+
+    // True if the ray at t is within radius of the y axis, i.e. within a cap
+    fn check_cap(ray: &Ray, t: f64, radius: f64) -> bool {
+        let x = ray.origin.x + t * ray.direction.x;
+        let z = ray.origin.z + t * ray.direction.z;
+
+        (x.powi(2) + z.powi(2)) <= radius.powi(2)
+    }
+
+    // Intersects the end caps at minimum and maximum, where the cap radius is given for each y
+    fn intersect_caps<'a>(object: &'a dyn Shape, ray: &Ray, minimum: f64, maximum: f64,
+                          radius_at: fn(f64) -> f64, intersections: &mut Vec<Intersection<'a>>) {
+        // Caps can only be hit if the ray is not parallel to them
+        if ray.direction.y.abs() < EPSILON {
+            return;
+        }
+
+        for cap_y in [minimum, maximum].iter() {
+            let t = (cap_y - ray.origin.y) / ray.direction.y;
+
+            if check_cap(ray, t, radius_at(*cap_y)) {
+                intersections.push(Intersection::new_intersection(t, object));
+            }
+        }
+    }
+
+    // Only keeps the body intersections that lie between minimum and maximum
+    fn push_truncated<'a>(object: &'a dyn Shape, ray: &Ray, ts: &[f64], minimum: f64, maximum: f64,
+                          intersections: &mut Vec<Intersection<'a>>) {
+        for t in ts.iter() {
+            let y = ray.origin.y + t * ray.direction.y;
+
+            if minimum < y && y < maximum {
+                intersections.push(Intersection::new_intersection(*t, object));
+            }
+        }
+    }
+
+    impl Shape for Cylinder {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            let mut intersections: Vec<Intersection> = Vec::new();
+
+            let a = ray.direction.x.powi(2) + ray.direction.z.powi(2);
+
+            // If a is 0 then the ray is parallel to the y axis and can only hit the caps
+            if a.abs() >= EPSILON {
+                let b = 2.0 * ray.origin.x * ray.direction.x +
+                        2.0 * ray.origin.z * ray.direction.z;
+                let c = ray.origin.x.powi(2) + ray.origin.z.powi(2) - 1.0;
+
+                let discriminant = b.powi(2) - 4.0 * a * c;
+
+                // The ray does not pass within the cylinder's radius, so it cannot hit the caps either
+                if discriminant < 0.0 {
+                    return intersections;
+                }
+
+                let t0 = (-b - discriminant.sqrt()) / (2.0 * a);
+                let t1 = (-b + discriminant.sqrt()) / (2.0 * a);
+
+                push_truncated(self, ray, &[t0.min(t1), t0.max(t1)], self.minimum, self.maximum,
+                               &mut intersections);
+            }
+
+            if self.closed {
+                intersect_caps(self, ray, self.minimum, self.maximum, |_| 1.0, &mut intersections);
+            }
+
+            intersections
+        }
+
+        fn local_normal_at(&self, point: Vec4) -> Vec4 {
+            let dist = point.x.powi(2) + point.z.powi(2);
+
+            // Open tubes have no caps, so points near their ends are still on the wall
+            if self.closed && dist < 1.0 && point.y >= self.maximum - EPSILON {
+                Vec4::new_vec(0.0, 1.0, 0.0)
+            } else if self.closed && dist < 1.0 && point.y <= self.minimum + EPSILON {
+                Vec4::new_vec(0.0, -1.0, 0.0)
+            } else {
+                Vec4::new_vec(point.x, 0.0, point.z)
+            }
+        }
+
+        fn bounds(&self) -> Aabb {
+            Aabb::new_aabb(Vec4::new_point(-1.0, self.minimum, -1.0), Vec4::new_point(1.0, self.maximum, 1.0))
+        }
+    }
+
+    // A double-napped cone around the y axis with its tip at the origin. The radius at any y is |y|.
+    pub struct Cone {
+        data: ShapeData,
+        pub minimum: f64,
+        pub maximum: f64,
+        pub closed: bool,
+    }
+
+    impl Cone {
+        // Creates an infinitely long, open cone
+        pub fn new_cone() -> Cone {
+            Cone {
+                data: ShapeData::new_shape_data(),
+                minimum: f64::NEG_INFINITY,
+                maximum: f64::INFINITY,
+                closed: false,
+            }
+        }
+    }
+
+    impl Shape for Cone {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            let mut intersections: Vec<Intersection> = Vec::new();
+
+            let a = ray.direction.x.powi(2) - ray.direction.y.powi(2) + ray.direction.z.powi(2);
+            let b = 2.0 * ray.origin.x * ray.direction.x -
+                    2.0 * ray.origin.y * ray.direction.y +
+                    2.0 * ray.origin.z * ray.direction.z;
+            let c = ray.origin.x.powi(2) - ray.origin.y.powi(2) + ray.origin.z.powi(2);
+
+            if a.abs() < EPSILON {
+                // The ray is parallel to one of the cone's halves, so it hits the other half at most once
+                if b.abs() >= EPSILON {
+                    let t = -c / (2.0 * b);
+                    push_truncated(self, ray, &[t], self.minimum, self.maximum, &mut intersections);
+                }
+            } else {
+                let discriminant = b.powi(2) - 4.0 * a * c;
+
+                // Rounding errors can push a ray grazing the tip slightly below 0
+                if discriminant >= -EPSILON {
+                    let discriminant = discriminant.max(0.0);
+
+                    let t0 = (-b - discriminant.sqrt()) / (2.0 * a);
+                    let t1 = (-b + discriminant.sqrt()) / (2.0 * a);
+
+                    push_truncated(self, ray, &[t0.min(t1), t0.max(t1)], self.minimum, self.maximum,
+                                   &mut intersections);
+                }
+            }
+
+            if self.closed {
+                intersect_caps(self, ray, self.minimum, self.maximum, f64::abs, &mut intersections);
+            }
+
+            intersections
+        }
+
+        fn local_normal_at(&self, point: Vec4) -> Vec4 {
+            let dist = point.x.powi(2) + point.z.powi(2);
+
+            if self.closed && dist < self.maximum.powi(2) && point.y >= self.maximum - EPSILON {
+                Vec4::new_vec(0.0, 1.0, 0.0)
+            } else if self.closed && dist < self.minimum.powi(2) && point.y <= self.minimum + EPSILON {
+                Vec4::new_vec(0.0, -1.0, 0.0)
+            } else {
+                let y = if point.y > 0.0 { -dist.sqrt() } else { dist.sqrt() };
+                Vec4::new_vec(point.x, y, point.z)
+            }
+        }
+
+        fn bounds(&self) -> Aabb {
+            // The widest part of the cone is at whichever end is furthest from the tip
+            let radius = self.minimum.abs().max(self.maximum.abs());
+
+            Aabb::new_aabb(Vec4::new_point(-radius, self.minimum, -radius),
+                           Vec4::new_point(radius, self.maximum, radius))
+        }
+    }
+
+    // Moller-Trumbore intersection. Returns t along with the barycentric u and v of the hit.
+    fn is_degenerate(e1: Vec4, e2: Vec4) -> bool {
+        e2.cross(&e1).magnitude() < EPSILON
+    }
+
+    fn intersect_triangle(p1: Vec4, e1: Vec4, e2: Vec4, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let dir_cross_e2 = ray.direction.cross(&e2);
+        let det = e1.dot(&dir_cross_e2);
+
+        // The ray is parallel to the triangle
+        if det.abs() < EPSILON {
+            return None;
+        }
+
+        let f = 1.0 / det;
+
+        let p1_to_origin = ray.origin - p1;
+        let u = f * p1_to_origin.dot(&dir_cross_e2);
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let origin_cross_e1 = p1_to_origin.cross(&e1);
+        let v = f * ray.direction.dot(&origin_cross_e1);
+        if v < 0.0 || (u + v) > 1.0 {
+            return None;
+        }
+
+        Some((f * e2.dot(&origin_cross_e1), u, v))
+    }
+
+    fn triangle_bounds(p1: Vec4, p2: Vec4, p3: Vec4) -> Aabb {
+        let mut bounds = Aabb::new_empty_aabb();
+        bounds.add_point(p1);
+        bounds.add_point(p2);
+        bounds.add_point(p3);
+
+        bounds
+    }
+
+    pub struct Triangle {
+        data: ShapeData,
+        p1: Vec4,
+        p2: Vec4,
+        p3: Vec4,
+        e1: Vec4,
+        e2: Vec4,
+        normal: Vec4,
+    }
+
+    impl Triangle {
+        pub fn new_triangle(p1: Vec4, p2: Vec4, p3: Vec4) -> Triangle {
+            let e1 = p2 - p1;
+            let e2 = p3 - p1;
+
+            // Degenerate triangles have no normal to normalize, so use the zero vector rather than NaNs
+            let normal = if is_degenerate(e1, e2) {
+                Vec4::new_vec(0.0, 0.0, 0.0)
+            } else {
+                e2.cross(&e1).normalized()
+            };
+
+            Triangle {
+                data: ShapeData::new_shape_data(),
+                p1,
+                p2,
+                p3,
+                e1,
+                e2,
+                normal,
+            }
+        }
+
+        // True if the points are collinear or repeated. Such a triangle has no area and can never be hit.
+        pub fn is_degenerate(&self) -> bool {
+            is_degenerate(self.e1, self.e2)
+        }
+
+        pub fn points(&self) -> (Vec4, Vec4, Vec4) {
+            (self.p1, self.p2, self.p3)
+        }
+
+        pub fn edges(&self) -> (Vec4, Vec4) {
+            (self.e1, self.e2)
+        }
+
+        pub fn normal(&self) -> Vec4 {
+            self.normal
+        }
+    }
+
+    impl Shape for Triangle {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            if self.is_degenerate() {
+                return Vec::new();
+            }
+
+            match intersect_triangle(self.p1, self.e1, self.e2, ray) {
+                Some((t, u, v)) => vec![Intersection::new_intersection_with_uv(t, self, u, v)],
+                None => Vec::new(),
+            }
+        }
+
+        fn local_normal_at(&self, _point: Vec4) -> Vec4 {
+            // A flat triangle has the same normal everywhere
+            self.normal
+        }
+
+        fn bounds(&self) -> Aabb {
+            triangle_bounds(self.p1, self.p2, self.p3)
+        }
+    }
+
+    // A triangle that interpolates the normals at its vertices to look smoothly curved
+    pub struct SmoothTriangle {
+        data: ShapeData,
+        p1: Vec4,
+        p2: Vec4,
+        p3: Vec4,
+        n1: Vec4,
+        n2: Vec4,
+        n3: Vec4,
+        e1: Vec4,
+        e2: Vec4,
+    }
+
+    impl SmoothTriangle {
+        pub fn new_smooth_triangle(p1: Vec4, p2: Vec4, p3: Vec4, n1: Vec4, n2: Vec4, n3: Vec4) -> SmoothTriangle {
+            SmoothTriangle {
+                data: ShapeData::new_shape_data(),
+                p1,
+                p2,
+                p3,
+                n1,
+                n2,
+                n3,
+                e1: p2 - p1,
+                e2: p3 - p1,
+            }
+        }
+
+        pub fn points(&self) -> (Vec4, Vec4, Vec4) {
+            (self.p1, self.p2, self.p3)
+        }
+
+        pub fn normals(&self) -> (Vec4, Vec4, Vec4) {
+            (self.n1, self.n2, self.n3)
+        }
+
+        pub fn is_degenerate(&self) -> bool {
+            is_degenerate(self.e1, self.e2)
+        }
+
+        fn interpolate_normal(&self, u: f64, v: f64) -> Vec4 {
+            self.n2 * u + self.n3 * v + self.n1 * (1.0 - u - v)
+        }
+    }
+
+    impl Shape for SmoothTriangle {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            if self.is_degenerate() {
+                return Vec::new();
+            }
+
+            match intersect_triangle(self.p1, self.e1, self.e2, ray) {
+                Some((t, u, v)) => vec![Intersection::new_intersection_with_uv(t, self, u, v)],
+                None => Vec::new(),
+            }
+        }
+
+        fn local_normal_at(&self, point: Vec4) -> Vec4 {
+            // There are no u and v to solve for on a triangle without area
+            if self.is_degenerate() {
+                return self.n1;
+            }
+
+            // Without a hit to take u and v from, work them out from the point itself
+            let p1_to_point = point - self.p1;
+
+            let d00 = self.e1.dot(&self.e1);
+            let d01 = self.e1.dot(&self.e2);
+            let d11 = self.e2.dot(&self.e2);
+            let d20 = p1_to_point.dot(&self.e1);
+            let d21 = p1_to_point.dot(&self.e2);
+            let denom = d00 * d11 - d01 * d01;
+
+            let u = (d11 * d20 - d01 * d21) / denom;
+            let v = (d00 * d21 - d01 * d20) / denom;
+
+            self.interpolate_normal(u, v)
+        }
+
+        fn local_normal_at_hit(&self, _point: Vec4, hit: &Intersection) -> Vec4 {
+            self.interpolate_normal(hit.u, hit.v)
+        }
+
+        fn bounds(&self) -> Aabb {
+            triangle_bounds(self.p1, self.p2, self.p3)
+        }
+    }
+
+    // A collection of shapes that are transformed together
+    pub struct Group {
+        data: ShapeData,
+        children: Vec<Box<dyn Shape>>,
+        // Box around every child in the group's object space, so rays that miss it can skip the children
+        bounds: Aabb,
+    }
+
+    // Cost of testing a ray against a nested group, relative to intersecting one of its children
+    const BVH_TRAVERSAL_COST: f64 = 1.0;
+    // Number of buckets the children are sorted into along each axis when looking for a split
+    const BVH_BINS: usize = 16;
+
+    // Chooses where to split a list of boxes using the surface area heuristic. The boxes are binned
+    // by their centroids along each axis and the split between bins with the lowest cost is kept.
+    // Returns which boxes go on the left side, or None if keeping them together is cheaper.
+    fn sah_split(boxes: &[Aabb]) -> Option<Vec<bool>> {
+        if boxes.len() < 2 {
+            return None;
+        }
+
+        let mut bounds = Aabb::new_empty_aabb();
+        let mut centroid_bounds = Aabb::new_empty_aabb();
+
+        for b in boxes.iter() {
+            bounds.add_box(b);
+            centroid_bounds.add_point(b.centroid());
+        }
+
+        let centroids: Vec<[f64; 3]> = boxes.iter()
+            .map(|b| {
+                let c = b.centroid();
+                [c.x, c.y, c.z]
+            })
+            .collect();
+        let cmin = [centroid_bounds.min.x, centroid_bounds.min.y, centroid_bounds.min.z];
+        let cmax = [centroid_bounds.max.x, centroid_bounds.max.y, centroid_bounds.max.z];
+
+        let bin_of = |axis: usize, centroid: &[f64; 3]| -> usize {
+            let offset = (centroid[axis] - cmin[axis]) / (cmax[axis] - cmin[axis]);
+            ((offset * BVH_BINS as f64) as usize).min(BVH_BINS - 1)
+        };
+
+        // Not splitting means intersecting every box
+        let mut best_cost = boxes.len() as f64;
+        let mut best_split: Option<(usize, usize)> = None;
+
+        for axis in 0..3 {
+            // Every centroid is in the same place along this axis, so it cannot separate them
+            if cmax[axis] - cmin[axis] <= 0.0 {
+                continue;
+            }
+
+            let mut bin_bounds = [Aabb::new_empty_aabb(); BVH_BINS];
+            let mut bin_counts = [0usize; BVH_BINS];
+
+            for (b, centroid) in boxes.iter().zip(centroids.iter()) {
+                let bin = bin_of(axis, centroid);
+                bin_bounds[bin].add_box(b);
+                bin_counts[bin] += 1;
+            }
+
+            // Sweep from the right first to know the area and count on the right of each split
+            let mut right_areas = [0.0; BVH_BINS];
+            let mut right_counts = [0usize; BVH_BINS];
+            let mut right_bounds = Aabb::new_empty_aabb();
+            let mut right_count = 0;
+
+            for split in (1..BVH_BINS).rev() {
+                right_bounds.add_box(&bin_bounds[split]);
+                right_count += bin_counts[split];
+                right_areas[split] = right_bounds.surface_area();
+                right_counts[split] = right_count;
+            }
+
+            let mut left_bounds = Aabb::new_empty_aabb();
+            let mut left_count = 0;
+
+            for split in 1..BVH_BINS {
+                left_bounds.add_box(&bin_bounds[split - 1]);
+                left_count += bin_counts[split - 1];
+
+                if left_count == 0 || right_counts[split] == 0 {
+                    continue;
+                }
+
+                // Each side is hit with a probability proportional to its surface area
+                let cost = BVH_TRAVERSAL_COST +
+                           (left_bounds.surface_area() * left_count as f64 +
+                            right_areas[split] * right_counts[split] as f64) / bounds.surface_area();
+
+                if cost < best_cost {
+                    best_cost = cost;
+                    best_split = Some((axis, split));
+                }
+            }
+        }
+
+        best_split.map(|(axis, split)| {
+            centroids.iter().map(|centroid| bin_of(axis, centroid) < split).collect()
+        })
+    }
+
+    impl Group {
+        pub fn new_group() -> Group {
+            Group { data: ShapeData::new_shape_data(), children: Vec::new(), bounds: Aabb::new_empty_aabb() }
+        }
+
+        pub fn children(&self) -> &[Box<dyn Shape>] {
+            &self.children
+        }
+
+        pub fn add_child<S: Shape + 'static>(&mut self, child: S) {
+            self.add_boxed_child(Box::new(child));
+        }
+
+        pub fn add_boxed_child(&mut self, mut child: Box<dyn Shape>) {
+            child.set_parent_transform(self.data.world_transform());
+            self.bounds.add_box(&child.parent_space_bounds());
+            self.children.push(child);
+        }
+
+        // Moves the children into two nested groups if that makes them cheaper to intersect, and
+        // repeats for each nested group. Children with infinite bounds always stay at the top.
+        fn split_children(&mut self) {
+            let mut bounded: Vec<Box<dyn Shape>> = Vec::new();
+
+            for child in std::mem::take(&mut self.children) {
+                if child.parent_space_bounds().is_finite() {
+                    bounded.push(child);
+                } else {
+                    self.children.push(child);
+                }
+            }
+
+            let boxes: Vec<Aabb> = bounded.iter().map(|child| child.parent_space_bounds()).collect();
+
+            let goes_left = match sah_split(&boxes) {
+                Some(goes_left) => goes_left,
+                None => {
+                    self.children.extend(bounded);
+                    return;
+                }
+            };
+
+            let mut left: Vec<Box<dyn Shape>> = Vec::new();
+            let mut right: Vec<Box<dyn Shape>> = Vec::new();
+
+            for (child, is_left) in bounded.into_iter().zip(goes_left) {
+                if is_left {
+                    left.push(child);
+                } else {
+                    right.push(child);
+                }
+            }
+
+            for side in [left, right] {
+                if side.len() == 1 {
+                    self.children.extend(side);
+                    continue;
+                }
+
+                // The nested group has no transform of its own, so the children keep their world
+                // transforms and do not need their cached inverses updated
+                let mut subgroup = Group::new_group();
+                subgroup.set_parent_transform(self.data.world_transform());
+
+                for child in side.iter() {
+                    subgroup.bounds.add_box(&child.parent_space_bounds());
+                }
+
+                subgroup.children = side;
+                subgroup.split_children();
+
+                self.children.push(Box::new(subgroup));
+            }
+        }
+
+    }
+
+    impl Shape for Group {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        // Children need to know about every transform above them to find their normals
+        fn update_children(&mut self) {
+            let world_transform = self.data.world_transform();
+
+            for child in self.children.iter_mut() {
+                child.set_parent_transform(world_transform);
+            }
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            let mut intersections: Vec<Intersection> = Vec::new();
+
+            if !self.bounds.intersects(ray) {
+                return intersections;
+            }
+
+            // The ray is in the group's space, which is each child's parent space
+            for child in self.children.iter() {
+                intersections.append(&mut get_intersection(child.as_ref(), ray));
+            }
+
+            intersections.sort_by(|a, b| a.t.total_cmp(&b.t));
+
+            intersections
+        }
+
+        fn local_normal_at(&self, _point: Vec4) -> Vec4 {
+            // Intersections always refer to the child that was hit, never the group itself.
+            // The group has no surface of its own, so it has no normal either.
+            Vec4::new_vec(0.0, 0.0, 0.0)
+        }
+
+        fn bounds(&self) -> Aabb {
+            self.bounds
+        }
+
+        fn build_bvh(&mut self) {
+            for child in self.children.iter_mut() {
+                child.build_bvh();
+            }
+
+            self.split_children();
+        }
+
+        fn includes(&self, other: &dyn Shape) -> bool {
+            std::ptr::eq(&self.data, other.data()) || self.children.iter().any(|child| child.includes(other))
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum CsgOperation {
+        Union,
+        Intersection,
+        Difference,
+    }
+
+    // Decides whether a hit on one side of a CSG shape is part of the combined surface.
+    // lhit is true if the left shape was hit, inl and inr are true if the hit is inside the left or right shape.
+    pub fn intersection_allowed(operation: CsgOperation, lhit: bool, inl: bool, inr: bool) -> bool {
+        match operation {
+            // Keep hits that are not inside the other shape
+            CsgOperation::Union => (lhit && !inr) || (!lhit && !inl),
+            // Keep hits that are inside the other shape
+            CsgOperation::Intersection => (lhit && inr) || (!lhit && inl),
+            // Keep left hits outside the right shape, and right hits inside the left shape
+            CsgOperation::Difference => (lhit && !inr) || (!lhit && inl),
+        }
+    }
+
+    // Constructive solid geometry. Combines two shapes with a set operation.
+    pub struct Csg {
+        data: ShapeData,
+        pub operation: CsgOperation,
+        left: Box<dyn Shape>,
+        right: Box<dyn Shape>,
+        bounds: Aabb,
+    }
+
+    impl Csg {
+        pub fn new_csg<L: Shape + 'static, R: Shape + 'static>(operation: CsgOperation, left: L, right: R) -> Csg {
+            Csg::new_boxed_csg(operation, Box::new(left), Box::new(right))
+        }
+
+        pub fn new_boxed_csg(operation: CsgOperation, left: Box<dyn Shape>, right: Box<dyn Shape>) -> Csg {
+            let mut bounds = left.parent_space_bounds();
+            bounds.add_box(&right.parent_space_bounds());
+
+            let mut csg = Csg { data: ShapeData::new_shape_data(), operation, left, right, bounds };
+            csg.update_children();
+
+            csg
+        }
+
+        pub fn left(&self) -> &dyn Shape {
+            self.left.as_ref()
+        }
+
+        pub fn right(&self) -> &dyn Shape {
+            self.right.as_ref()
+        }
+
+        // Keeps the intersections that lie on the combined surface. The intersections must be sorted by t.
+        pub fn filter_intersections<'a>(&self, intersections: &[Intersection<'a>]) -> Vec<Intersection<'a>> {
+            let mut result: Vec<Intersection> = Vec::new();
+
+            // Start outside of both shapes
+            let mut inl = false;
+            let mut inr = false;
+
+            for intersection in intersections.iter() {
+                let lhit = self.left.includes(intersection.object);
+
+                if intersection_allowed(self.operation, lhit, inl, inr) {
+                    result.push(*intersection);
+                }
+
+                // Every hit enters or leaves the shape that was hit
+                if lhit {
+                    inl = !inl;
+                } else {
+                    inr = !inr;
+                }
+            }
+
+            result
+        }
+    }
+
+    impl Shape for Csg {
+        fn data(&self) -> &ShapeData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut ShapeData {
+            &mut self.data
+        }
+
+        fn update_children(&mut self) {
+            let world_transform = self.data.world_transform();
+
+            self.left.set_parent_transform(world_transform);
+            self.right.set_parent_transform(world_transform);
+        }
+
+        fn local_intersect<'a>(&'a self, ray: &Ray) -> Vec<Intersection<'a>> {
+            if !self.bounds.intersects(ray) {
+                return Vec::new();
+            }
+
+            let mut intersections = get_intersection(self.left.as_ref(), ray);
+            intersections.append(&mut get_intersection(self.right.as_ref(), ray));
+
+            intersections.sort_by(|a, b| a.t.total_cmp(&b.t));
+
+            self.filter_intersections(&intersections)
+        }
+
+        fn local_normal_at(&self, _point: Vec4) -> Vec4 {
+            // Intersections always refer to the child that was hit, never the CSG shape itself.
+            // Like a group, it has no surface or normal of its own.
+            Vec4::new_vec(0.0, 0.0, 0.0)
+        }
+
+        fn bounds(&self) -> Aabb {
+            self.bounds
+        }
+
+        fn build_bvh(&mut self) {
+            self.left.build_bvh();
+            self.right.build_bvh();
+        }
+
+        fn includes(&self, other: &dyn Shape) -> bool {
+            std::ptr::eq(&self.data, other.data()) || self.left.includes(other) || self.right.includes(other)
+        }
+    }
+
+    #[cfg(test)]
+    mod plane_tests {
+        use super::*;
+        use super::super::matrices::Mat4;
+        use super::super::rays::{get_intersection, same_shape};
+        use std::f64::consts::PI;
+
+        #[test]
+        fn plane_normal_is_constant() {
+            let p = Plane::new_plane();
+
+            assert_eq!(p.local_normal_at(Vec4::new_point(0.0, 0.0, 0.0)), Vec4::new_vec(0.0, 1.0, 0.0));
+            assert_eq!(p.local_normal_at(Vec4::new_point(10.0, 0.0, -10.0)), Vec4::new_vec(0.0, 1.0, 0.0));
+            assert_eq!(p.local_normal_at(Vec4::new_point(-5.0, 0.0, 150.0)), Vec4::new_vec(0.0, 1.0, 0.0));
+        }
+
+        #[test]
+        fn intersect_parallel_ray() {
+            let p = Plane::new_plane();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 10.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert!(p.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn intersect_coplanar_ray() {
+            let p = Plane::new_plane();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert!(p.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn intersect_from_above() {
+            let p = Plane::new_plane();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 1.0, 0.0),
+                                 Vec4::new_vec(0.0, -1.0, 0.0));
+
+            let xs = p.local_intersect(&r);
+
+            assert_eq!(xs.len(), 1);
+            assert_eq!(xs[0].t, 1.0);
+            assert!(same_shape(xs[0].object, &p));
+        }
+
+        #[test]
+        fn intersect_from_below() {
+            let p = Plane::new_plane();
+            let r = Ray::new_ray(Vec4::new_point(0.0, -1.0, 0.0),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+
+            let xs = p.local_intersect(&r);
+
+            assert_eq!(xs.len(), 1);
+            assert_eq!(xs[0].t, 1.0);
+            assert!(same_shape(xs[0].object, &p));
+        }
+
+        #[test]
+        fn intersect_transformed_plane() {
+            // A wall at z = 5 facing the origin
+            let mut p = Plane::new_plane();
+            p.set_transform(Mat4::new_translation(0.0, 0.0, 5.0).rotate_x(-PI / 2.0));
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 1.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = get_intersection(&p, &r);
+
+            assert_eq!(xs.len(), 1);
+            assert_eq!(xs[0].t, 5.0);
+            assert_eq!(p.normal_at(r.position(5.0)), Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod cube_tests {
+        use super::*;
+        use super::super::matrices::Mat4;
+        use super::super::rays::get_intersection;
+
+        #[test]
+        fn ray_intersects_cube() {
+            let c = Cube::new_cube();
+
+            // (origin, direction, t1, t2) for a ray hitting each face, plus one starting inside
+            let cases = [
+                (Vec4::new_point(5.0, 0.5, 0.0), Vec4::new_vec(-1.0, 0.0, 0.0), 4.0, 6.0),
+                (Vec4::new_point(-5.0, 0.5, 0.0), Vec4::new_vec(1.0, 0.0, 0.0), 4.0, 6.0),
+                (Vec4::new_point(0.5, 5.0, 0.0), Vec4::new_vec(0.0, -1.0, 0.0), 4.0, 6.0),
+                (Vec4::new_point(0.5, -5.0, 0.0), Vec4::new_vec(0.0, 1.0, 0.0), 4.0, 6.0),
+                (Vec4::new_point(0.5, 0.0, 5.0), Vec4::new_vec(0.0, 0.0, -1.0), 4.0, 6.0),
+                (Vec4::new_point(0.5, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 4.0, 6.0),
+                (Vec4::new_point(0.0, 0.5, 0.0), Vec4::new_vec(0.0, 0.0, 1.0), -1.0, 1.0),
+            ];
+
+            for (origin, direction, t1, t2) in cases.iter() {
+                let r = Ray::new_ray(*origin, *direction);
+                let xs = c.local_intersect(&r);
+
+                assert_eq!(xs.len(), 2);
+                assert_eq!(xs[0].t, *t1);
+                assert_eq!(xs[1].t, *t2);
+            }
+        }
+
+        #[test]
+        fn ray_misses_cube() {
+            let c = Cube::new_cube();
+
+            let cases = [
+                (Vec4::new_point(-2.0, 0.0, 0.0), Vec4::new_vec(0.2673, 0.5345, 0.8018)),
+                (Vec4::new_point(0.0, -2.0, 0.0), Vec4::new_vec(0.8018, 0.2673, 0.5345)),
+                (Vec4::new_point(0.0, 0.0, -2.0), Vec4::new_vec(0.5345, 0.8018, 0.2673)),
+                (Vec4::new_point(2.0, 0.0, 2.0), Vec4::new_vec(0.0, 0.0, -1.0)),
+                (Vec4::new_point(0.0, 2.0, 2.0), Vec4::new_vec(0.0, -1.0, 0.0)),
+                (Vec4::new_point(2.0, 2.0, 0.0), Vec4::new_vec(-1.0, 0.0, 0.0)),
+            ];
+
+            for (origin, direction) in cases.iter() {
+                let r = Ray::new_ray(*origin, *direction);
+                assert!(c.local_intersect(&r).is_empty());
+            }
+        }
+
+        #[test]
+        fn cube_normals() {
+            let c = Cube::new_cube();
+
+            let cases = [
+                (Vec4::new_point(1.0, 0.5, -0.8), Vec4::new_vec(1.0, 0.0, 0.0)),
+                (Vec4::new_point(-1.0, -0.2, 0.9), Vec4::new_vec(-1.0, 0.0, 0.0)),
+                (Vec4::new_point(-0.4, 1.0, -0.1), Vec4::new_vec(0.0, 1.0, 0.0)),
+                (Vec4::new_point(0.3, -1.0, -0.7), Vec4::new_vec(0.0, -1.0, 0.0)),
+                (Vec4::new_point(-0.6, 0.3, 1.0), Vec4::new_vec(0.0, 0.0, 1.0)),
+                (Vec4::new_point(0.4, 0.4, -1.0), Vec4::new_vec(0.0, 0.0, -1.0)),
+                // Corners resolve to the x face
+                (Vec4::new_point(1.0, 1.0, 1.0), Vec4::new_vec(1.0, 0.0, 0.0)),
+                (Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_vec(-1.0, 0.0, 0.0)),
+            ];
+
+            for (point, normal) in cases.iter() {
+                assert_eq!(c.local_normal_at(*point), *normal);
+            }
+        }
+
+        #[test]
+        fn intersect_transformed_cube() {
+            let mut c = Cube::new_cube();
+            c.set_transform(Mat4::new_translation(0.0, 0.0, 10.0).scale(2.0, 2.0, 2.0));
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = get_intersection(&c, &r);
+
+            assert_eq!(xs.len(), 2);
+            assert_eq!(xs[0].t, 8.0);
+            assert_eq!(xs[1].t, 12.0);
+            assert_eq!(c.normal_at(r.position(8.0)), Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod cylinder_tests {
+        use super::*;
+        use super::super::rt_prelude::equal_approx;
+
+        #[test]
+        fn default_cylinder() {
+            let cyl = Cylinder::new_cylinder();
+
+            assert_eq!(cyl.minimum, f64::NEG_INFINITY);
+            assert_eq!(cyl.maximum, f64::INFINITY);
+            assert!(!cyl.closed);
+        }
+
+        #[test]
+        fn ray_misses_cylinder() {
+            let cyl = Cylinder::new_cylinder();
+
+            let cases = [
+                (Vec4::new_point(1.0, 0.0, 0.0), Vec4::new_vec(0.0, 1.0, 0.0)),
+                (Vec4::new_point(0.0, 0.0, 0.0), Vec4::new_vec(0.0, 1.0, 0.0)),
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(1.0, 1.0, 1.0)),
+            ];
+
+            for (origin, direction) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                assert!(cyl.local_intersect(&r).is_empty());
+            }
+        }
+
+        #[test]
+        fn ray_hits_cylinder() {
+            let cyl = Cylinder::new_cylinder();
+
+            let cases = [
+                (Vec4::new_point(1.0, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 5.0, 5.0),
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 4.0, 6.0),
+                (Vec4::new_point(0.5, 0.0, -5.0), Vec4::new_vec(0.1, 1.0, 1.0), 6.80798, 7.08872),
+            ];
+
+            for (origin, direction, t0, t1) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                let xs = cyl.local_intersect(&r);
+
+                assert_eq!(xs.len(), 2);
+                assert!(equal_approx(xs[0].t, *t0));
+                assert!(equal_approx(xs[1].t, *t1));
+            }
+        }
+
+        #[test]
+        fn cylinder_normals() {
+            let cyl = Cylinder::new_cylinder();
+
+            let cases = [
+                (Vec4::new_point(1.0, 0.0, 0.0), Vec4::new_vec(1.0, 0.0, 0.0)),
+                (Vec4::new_point(0.0, 5.0, -1.0), Vec4::new_vec(0.0, 0.0, -1.0)),
+                (Vec4::new_point(0.0, -2.0, 1.0), Vec4::new_vec(0.0, 0.0, 1.0)),
+                (Vec4::new_point(-1.0, 1.0, 0.0), Vec4::new_vec(-1.0, 0.0, 0.0)),
+            ];
+
+            for (point, normal) in cases.iter() {
+                assert_eq!(cyl.local_normal_at(*point), *normal);
+            }
+        }
+
+        #[test]
+        fn intersect_truncated_cylinder() {
+            let mut cyl = Cylinder::new_cylinder();
+            cyl.minimum = 1.0;
+            cyl.maximum = 2.0;
+
+            let cases = [
+                (Vec4::new_point(0.0, 1.5, 0.0), Vec4::new_vec(0.1, 1.0, 0.0), 0),
+                (Vec4::new_point(0.0, 3.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 0),
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 0),
+                // The ends are exclusive
+                (Vec4::new_point(0.0, 2.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 0),
+                (Vec4::new_point(0.0, 1.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 0),
+                (Vec4::new_point(0.0, 1.5, -2.0), Vec4::new_vec(0.0, 0.0, 1.0), 2),
+            ];
+
+            for (origin, direction, count) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                assert_eq!(cyl.local_intersect(&r).len(), *count);
+            }
+        }
+
+        #[test]
+        fn intersect_closed_cylinder_caps() {
+            let mut cyl = Cylinder::new_cylinder();
+            cyl.minimum = 1.0;
+            cyl.maximum = 2.0;
+            cyl.closed = true;
+
+            let cases = [
+                (Vec4::new_point(0.0, 3.0, 0.0), Vec4::new_vec(0.0, -1.0, 0.0), 2),
+                (Vec4::new_point(0.0, 3.0, -2.0), Vec4::new_vec(0.0, -1.0, 2.0), 2),
+                // Corner cases where the ray exits through the edge of a cap
+                (Vec4::new_point(0.0, 4.0, -2.0), Vec4::new_vec(0.0, -1.0, 1.0), 2),
+                (Vec4::new_point(0.0, 0.0, -2.0), Vec4::new_vec(0.0, 1.0, 2.0), 2),
+                (Vec4::new_point(0.0, -1.0, -2.0), Vec4::new_vec(0.0, 1.0, 1.0), 2),
+            ];
+
+            for (origin, direction, count) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                assert_eq!(cyl.local_intersect(&r).len(), *count);
+            }
+        }
+
+        #[test]
+        fn closed_cylinder_cap_normals() {
+            let mut cyl = Cylinder::new_cylinder();
+            cyl.minimum = 1.0;
+            cyl.maximum = 2.0;
+            cyl.closed = true;
+
+            let cases = [
+                (Vec4::new_point(0.0, 1.0, 0.0), Vec4::new_vec(0.0, -1.0, 0.0)),
+                (Vec4::new_point(0.5, 1.0, 0.0), Vec4::new_vec(0.0, -1.0, 0.0)),
+                (Vec4::new_point(0.0, 1.0, 0.5), Vec4::new_vec(0.0, -1.0, 0.0)),
+                (Vec4::new_point(0.0, 2.0, 0.0), Vec4::new_vec(0.0, 1.0, 0.0)),
+                (Vec4::new_point(0.5, 2.0, 0.0), Vec4::new_vec(0.0, 1.0, 0.0)),
+                (Vec4::new_point(0.0, 2.0, 0.5), Vec4::new_vec(0.0, 1.0, 0.0)),
+            ];
+
+            for (point, normal) in cases.iter() {
+                assert_eq!(cyl.local_normal_at(*point), *normal);
+            }
+        }
+
+        #[test]
+        fn open_cylinder_normals_near_rim() {
+            let mut cyl = Cylinder::new_cylinder();
+            cyl.minimum = 1.0;
+            cyl.maximum = 2.0;
+
+            // Just inside the wall at either end, where a closed cylinder would report its caps
+            assert_eq!(cyl.local_normal_at(Vec4::new_point(0.9999, 2.0, 0.0)), Vec4::new_vec(0.9999, 0.0, 0.0));
+            assert_eq!(cyl.local_normal_at(Vec4::new_point(0.0, 1.0, -0.9999)), Vec4::new_vec(0.0, 0.0, -0.9999));
+        }
+    }
+
+    #[cfg(test)]
+    mod cone_tests {
+        use super::*;
+        use super::super::rt_prelude::equal_approx;
+
+        #[test]
+        fn ray_hits_cone() {
+            let shape = Cone::new_cone();
+
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), 5.0, 5.0),
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(1.0, 1.0, 1.0), 8.66025, 8.66025),
+                (Vec4::new_point(1.0, 1.0, -5.0), Vec4::new_vec(-0.5, -1.0, 1.0), 4.55006, 49.44994),
+            ];
+
+            for (origin, direction, t0, t1) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                let xs = shape.local_intersect(&r);
+
+                assert_eq!(xs.len(), 2);
+                assert!(equal_approx(xs[0].t, *t0));
+                assert!(equal_approx(xs[1].t, *t1));
+            }
+        }
+
+        #[test]
+        fn ray_parallel_to_one_half() {
+            let shape = Cone::new_cone();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -1.0),
+                                 Vec4::new_vec(0.0, 1.0, 1.0).normalized());
+
+            let xs = shape.local_intersect(&r);
+
+            assert_eq!(xs.len(), 1);
+            assert!(equal_approx(xs[0].t, 0.35355));
+        }
+
+        #[test]
+        fn intersect_closed_cone_caps() {
+            let mut shape = Cone::new_cone();
+            shape.minimum = -0.5;
+            shape.maximum = 0.5;
+            shape.closed = true;
+
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, -5.0), Vec4::new_vec(0.0, 1.0, 0.0), 0),
+                (Vec4::new_point(0.0, 0.0, -0.25), Vec4::new_vec(0.0, 1.0, 1.0), 2),
+                (Vec4::new_point(0.0, 0.0, -0.25), Vec4::new_vec(0.0, 1.0, 0.0), 4),
+            ];
+
+            for (origin, direction, count) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                assert_eq!(shape.local_intersect(&r).len(), *count);
+            }
+        }
+
+        #[test]
+        fn cone_normals() {
+            let shape = Cone::new_cone();
+
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, 0.0), Vec4::new_vec(0.0, 0.0, 0.0)),
+                (Vec4::new_point(1.0, 1.0, 1.0), Vec4::new_vec(1.0, -(2.0_f64.sqrt()), 1.0)),
+                (Vec4::new_point(-1.0, -1.0, 0.0), Vec4::new_vec(-1.0, 1.0, 0.0)),
+            ];
+
+            for (point, normal) in cases.iter() {
+                assert_eq!(shape.local_normal_at(*point), *normal);
+            }
+        }
+
+        #[test]
+        fn closed_cone_cap_normals() {
+            let mut shape = Cone::new_cone();
+            shape.minimum = -1.0;
+            shape.maximum = 2.0;
+            shape.closed = true;
+
+            assert_eq!(shape.local_normal_at(Vec4::new_point(0.5, 2.0, 0.5)), Vec4::new_vec(0.0, 1.0, 0.0));
+            assert_eq!(shape.local_normal_at(Vec4::new_point(0.5, -1.0, 0.0)), Vec4::new_vec(0.0, -1.0, 0.0));
+            // Points on the body near a cap are not mistaken for the cap
+            assert_eq!(shape.local_normal_at(Vec4::new_point(2.0, 2.0, 0.0)), Vec4::new_vec(2.0, -2.0, 0.0));
+        }
+
+        #[test]
+        fn open_cone_normals_near_rim() {
+            let mut shape = Cone::new_cone();
+            shape.minimum = -1.0;
+            shape.maximum = 2.0;
+
+            assert_eq!(shape.local_normal_at(Vec4::new_point(1.9999, 2.0, 0.0)), Vec4::new_vec(1.9999, -1.9999, 0.0));
+            assert_eq!(shape.local_normal_at(Vec4::new_point(0.9999, -1.0, 0.0)), Vec4::new_vec(0.9999, 0.9999, 0.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod triangle_tests {
+        use super::*;
+        use super::super::rt_prelude::equal_approx;
+        use super::super::rays::{get_intersection, same_shape};
+        use super::super::world::prepare_computations;
+
+        fn test_triangle() -> Triangle {
+            Triangle::new_triangle(Vec4::new_point(0.0, 1.0, 0.0),
+                                   Vec4::new_point(-1.0, 0.0, 0.0),
+                                   Vec4::new_point(1.0, 0.0, 0.0))
+        }
+
+        fn test_smooth_triangle() -> SmoothTriangle {
+            SmoothTriangle::new_smooth_triangle(Vec4::new_point(0.0, 1.0, 0.0),
+                                                Vec4::new_point(-1.0, 0.0, 0.0),
+                                                Vec4::new_point(1.0, 0.0, 0.0),
+                                                Vec4::new_vec(0.0, 1.0, 0.0),
+                                                Vec4::new_vec(-1.0, 0.0, 0.0),
+                                                Vec4::new_vec(1.0, 0.0, 0.0))
+        }
+
+        #[test]
+        fn create_triangle() {
+            let t = test_triangle();
+
+            assert_eq!(t.points(), (Vec4::new_point(0.0, 1.0, 0.0),
+                                    Vec4::new_point(-1.0, 0.0, 0.0),
+                                    Vec4::new_point(1.0, 0.0, 0.0)));
+            assert_eq!(t.edges(), (Vec4::new_vec(-1.0, -1.0, 0.0), Vec4::new_vec(1.0, -1.0, 0.0)));
+            assert_eq!(t.normal(), Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn triangle_normal_is_constant() {
+            let t = test_triangle();
+
+            assert_eq!(t.local_normal_at(Vec4::new_point(0.0, 0.5, 0.0)), t.normal());
+            assert_eq!(t.local_normal_at(Vec4::new_point(-0.5, 0.75, 0.0)), t.normal());
+            assert_eq!(t.local_normal_at(Vec4::new_point(0.5, 0.25, 0.0)), t.normal());
+        }
+
+        #[test]
+        fn degenerate_triangles() {
+            // (p1, p2, p3)
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, 0.0), Vec4::new_point(1.0, 0.0, 0.0), Vec4::new_point(2.0, 0.0, 0.0)),
+                (Vec4::new_point(0.0, 1.0, 0.0), Vec4::new_point(0.0, 1.0, 0.0), Vec4::new_point(1.0, 0.0, 0.0)),
+                (Vec4::new_point(1.0, 1.0, 1.0), Vec4::new_point(1.0, 1.0, 1.0), Vec4::new_point(1.0, 1.0, 1.0)),
+            ];
+
+            for (p1, p2, p3) in cases.iter() {
+                let t = Triangle::new_triangle(*p1, *p2, *p3);
+                let s = SmoothTriangle::new_smooth_triangle(*p1, *p2, *p3,
+                                                            Vec4::new_vec(0.0, 1.0, 0.0),
+                                                            Vec4::new_vec(0.0, 1.0, 0.0),
+                                                            Vec4::new_vec(0.0, 1.0, 0.0));
+
+                // A ray straight down through p1 would be inside the triangle if it had any area
+                let r = Ray::new_ray(*p1 + Vec4::new_vec(0.0, 1.0, -1.0), Vec4::new_vec(0.0, -1.0, 1.0).normalized());
+
+                assert!(t.is_degenerate());
+                assert_eq!(t.normal(), Vec4::new_vec(0.0, 0.0, 0.0));
+                assert_eq!(t.normal_at(*p1), Vec4::new_vec(0.0, 0.0, 0.0));
+                assert!(t.local_intersect(&r).is_empty());
+
+                assert!(s.is_degenerate());
+                assert_eq!(s.local_normal_at(*p1), Vec4::new_vec(0.0, 1.0, 0.0));
+                assert!(s.local_intersect(&r).is_empty());
+            }
+
+            assert!(!test_triangle().is_degenerate());
+            assert!(!test_smooth_triangle().is_degenerate());
+        }
+
+        #[test]
+        fn ray_parallel_to_triangle() {
+            let t = test_triangle();
+            let r = Ray::new_ray(Vec4::new_point(0.0, -1.0, -2.0),
+                                 Vec4::new_vec(0.0, 1.0, 0.0));
+
+            assert!(t.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn ray_misses_triangle_edges() {
+            let t = test_triangle();
+
+            // Misses the p1-p3 edge, the p1-p2 edge and the p2-p3 edge
+            for origin in [Vec4::new_point(1.0, 1.0, -2.0),
+                           Vec4::new_point(-1.0, 1.0, -2.0),
+                           Vec4::new_point(0.0, -1.0, -2.0)].iter() {
+                let r = Ray::new_ray(*origin, Vec4::new_vec(0.0, 0.0, 1.0));
+                assert!(t.local_intersect(&r).is_empty());
+            }
+        }
+
+        #[test]
+        fn ray_hits_triangle() {
+            let t = test_triangle();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.5, -2.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            let xs = t.local_intersect(&r);
+
+            assert_eq!(xs.len(), 1);
+            assert_eq!(xs[0].t, 2.0);
+            assert!(same_shape(xs[0].object, &t));
+        }
+
+        #[test]
+        fn intersection_stores_uv() {
+            let s = test_smooth_triangle();
+            let i = Intersection::new_intersection_with_uv(3.5, &s, 0.2, 0.4);
+
+            assert_eq!(i.u, 0.2);
+            assert_eq!(i.v, 0.4);
+        }
+
+        #[test]
+        fn smooth_triangle_intersection_has_uv() {
+            let s = test_smooth_triangle();
+            let r = Ray::new_ray(Vec4::new_point(-0.2, 0.3, -2.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            let xs = s.local_intersect(&r);
+
+            assert_eq!(xs.len(), 1);
+            assert!(equal_approx(xs[0].u, 0.45));
+            assert!(equal_approx(xs[0].v, 0.25));
+        }
+
+        #[test]
+        fn smooth_triangle_interpolates_normal() {
+            let s = test_smooth_triangle();
+            let i = Intersection::new_intersection_with_uv(1.0, &s, 0.45, 0.25);
+
+            let n = s.normal_at_hit(Vec4::new_point(0.0, 0.0, 0.0), &i);
+
+            assert_eq!(n, Vec4::new_vec(-0.5547, 0.83205, 0.0));
+        }
+
+        #[test]
+        fn smooth_triangle_normal_without_hit() {
+            let s = test_smooth_triangle();
+
+            // The point the previous test's u and v correspond to
+            let n = s.normal_at(Vec4::new_point(-0.2, 0.3, 0.0));
+
+            assert_eq!(n, Vec4::new_vec(-0.5547, 0.83205, 0.0));
+        }
+
+        #[test]
+        fn prepare_smooth_triangle_normal() {
+            let s = test_smooth_triangle();
+            let r = Ray::new_ray(Vec4::new_point(-0.2, 0.3, -2.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            let xs = get_intersection(&s, &r);
+            let comps = prepare_computations(&xs[0], &r, &xs);
+
+            assert_eq!(comps.normalv, Vec4::new_vec(-0.5547, 0.83205, 0.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod group_tests {
+        use super::*;
+        use super::super::matrices::Mat4;
+        use super::super::rays::{Sphere, same_shape};
+        use std::f64::consts::PI;
+
+        #[test]
+        fn create_group() {
+            let g = Group::new_group();
+
+            assert_eq!(*g.transform(), Mat4::id());
+            assert!(g.children().is_empty());
+        }
+
+        #[test]
+        fn add_child_to_group() {
+            let mut g = Group::new_group();
+            g.set_transform(Mat4::new_translation(1.0, 2.0, 3.0));
+
+            g.add_child(Sphere::new_sphere(0));
+
+            assert_eq!(g.children().len(), 1);
+            assert_eq!(*g.children()[0].parent_transform(), Mat4::new_translation(1.0, 2.0, 3.0));
+        }
+
+        #[test]
+        fn intersect_empty_group() {
+            let g = Group::new_group();
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert!(g.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn intersect_nonempty_group() {
+            let mut g = Group::new_group();
+
+            let s1 = Sphere::new_sphere(1);
+            let mut s2 = Sphere::new_sphere(2);
+            s2.set_transform(Mat4::new_translation(0.0, 0.0, -3.0));
+            let mut s3 = Sphere::new_sphere(3);
+            s3.set_transform(Mat4::new_translation(5.0, 0.0, 0.0));
+
+            g.add_child(s1);
+            g.add_child(s2);
+            g.add_child(s3);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = g.local_intersect(&r);
+
+            // Sorted by t across every child, and s3 is missed
+            assert_eq!(xs.len(), 4);
+            assert!(same_shape(xs[0].object, g.children()[1].as_ref()));
+            assert!(same_shape(xs[1].object, g.children()[1].as_ref()));
+            assert!(same_shape(xs[2].object, g.children()[0].as_ref()));
+            assert!(same_shape(xs[3].object, g.children()[0].as_ref()));
+        }
+
+        #[test]
+        fn intersect_transformed_group() {
+            let mut g = Group::new_group();
+            g.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(5.0, 0.0, 0.0));
+            g.add_child(s);
+
+            let r = Ray::new_ray(Vec4::new_point(10.0, 0.0, -10.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert_eq!(get_intersection(&g, &r).len(), 2);
+        }
+
+        // A sphere nested two groups deep. The inner group is scaled by the given amounts.
+        fn nested_sphere_groups(x: f64, y: f64, z: f64) -> Group {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(5.0, 0.0, 0.0));
+
+            let mut g2 = Group::new_group();
+            g2.set_transform(Mat4::new_scaling(x, y, z));
+            g2.add_child(s);
+
+            let mut g1 = Group::new_group();
+            g1.set_transform(Mat4::new_rotation_y(PI / 2.0));
+            g1.add_child(g2);
+
+            g1
+        }
+
+        // Finds the nested sphere by shooting a ray at its center
+        fn hit_nested_sphere(g: &Group) -> Intersection<'_> {
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, -1.0));
+
+            get_intersection(g, &r)[0]
+        }
+
+        #[test]
+        fn nested_world_to_object() {
+            let g = nested_sphere_groups(2.0, 2.0, 2.0);
+            let hit = hit_nested_sphere(&g);
+
+            assert_eq!(hit.t, 8.0);
+            assert_eq!(hit.object.world_to_object(Vec4::new_point(-2.0, 0.0, -10.0)),
+                       Vec4::new_point(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn nested_normal_to_world() {
+            let g = nested_sphere_groups(1.0, 2.0, 3.0);
+            let hit = hit_nested_sphere(&g);
+
+            let a = 3.0_f64.sqrt() / 3.0;
+            assert_eq!(hit.object.normal_to_world(Vec4::new_vec(a, a, a)),
+                       Vec4::new_vec(0.28571, 0.42857, -0.85714));
+        }
+
+        #[test]
+        fn nested_normal_at() {
+            let g = nested_sphere_groups(1.0, 2.0, 3.0);
+            let hit = hit_nested_sphere(&g);
+
+            assert_eq!(hit.object.normal_at(Vec4::new_point(1.7321, 1.1547, -5.5774)),
+                       Vec4::new_vec(0.2857, 0.42854, -0.85716));
+        }
+
+        #[test]
+        fn transform_group_after_adding_children() {
+            let mut g = nested_sphere_groups(2.0, 2.0, 2.0);
+
+            // Moving the whole assembly moves the sphere with it
+            g.set_transform(Mat4::new_translation(0.0, 0.0, 1.0).rotate_y(PI / 2.0));
+            let hit = hit_nested_sphere(&g);
+
+            assert_eq!(hit.t, 7.0);
+            assert_eq!(hit.object.normal_at(Vec4::new_point(0.0, 0.0, -7.0)),
+                       Vec4::new_vec(0.0, 0.0, 1.0));
+        }
+
+        #[test]
+        fn group_has_no_normal_of_its_own() {
+            let g = nested_sphere_groups(1.0, 2.0, 3.0);
+
+            assert_eq!(g.normal_at(Vec4::new_point(1.7321, 1.1547, -5.5774)), Vec4::new_vec(0.0, 0.0, 0.0));
+        }
+    }
+
+    #[cfg(test)]
+    mod bvh_tests {
+        use super::*;
+        use super::super::matrices::Mat4;
+        use super::super::rays::Sphere;
+
+        fn sphere_at(x: f64, y: f64, z: f64) -> Sphere {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(x, y, z));
+
+            s
+        }
+
+        // A bumpy sheet of triangles, n by n squares in size
+        fn triangle_sheet(n: usize) -> Group {
+            let mut g = Group::new_group();
+
+            for i in 0..n {
+                for j in 0..n {
+                    let (x, z) = (i as f64, j as f64);
+                    let y = ((i * 7 + j * 3) % 5) as f64 * 0.1;
+
+                    g.add_child(Triangle::new_triangle(Vec4::new_point(x, y, z),
+                                                       Vec4::new_point(x + 1.0, 0.0, z),
+                                                       Vec4::new_point(x, 0.0, z + 1.0)));
+                    g.add_child(Triangle::new_triangle(Vec4::new_point(x + 1.0, 0.0, z),
+                                                       Vec4::new_point(x + 1.0, y, z + 1.0),
+                                                       Vec4::new_point(x, 0.0, z + 1.0)));
+                }
+            }
+
+            g.set_transform(Mat4::new_rotation_y(0.3).scale(0.5, 1.0, 0.5));
+
+            g
+        }
+
+        #[test]
+        fn ray_misses_group_bounds() {
+            let mut g = Group::new_group();
+            g.add_child(sphere_at(0.0, 0.0, 0.0));
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 2.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert!(g.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn split_separated_clusters() {
+            let mut g = Group::new_group();
+            g.add_child(sphere_at(-10.0, 0.0, 0.0));
+            g.add_child(sphere_at(10.0, 0.0, 0.0));
+            g.add_child(sphere_at(-10.0, 3.0, 0.0));
+            g.add_child(sphere_at(10.0, 3.0, 0.0));
+            let bounds = g.bounds();
+
+            g.build_bvh();
+
+            assert_eq!(g.bounds(), bounds);
+            assert_eq!(g.children().len(), 2);
+            assert_eq!(g.children()[0].bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-11.0, -1.0, -1.0), Vec4::new_point(-9.0, 4.0, 1.0)));
+            assert_eq!(g.children()[1].bounds(),
+                       Aabb::new_aabb(Vec4::new_point(9.0, -1.0, -1.0), Vec4::new_point(11.0, 4.0, 1.0)));
+        }
+
+        #[test]
+        fn infinite_children_stay_at_top() {
+            let mut g = Group::new_group();
+            g.add_child(Plane::new_plane());
+            g.add_child(sphere_at(-10.0, 0.0, 0.0));
+            g.add_child(sphere_at(-10.0, 3.0, 0.0));
+            g.add_child(sphere_at(10.0, 0.0, 0.0));
+            g.add_child(sphere_at(10.0, 3.0, 0.0));
+
+            g.build_bvh();
+
+            assert_eq!(g.children().len(), 3);
+            assert!(!g.children()[0].bounds().is_finite());
+        }
+
+        #[test]
+        fn overlapping_children_are_not_split() {
+            let mut g = Group::new_group();
+            g.add_child(sphere_at(1.0, 2.0, 3.0));
+            g.add_child(sphere_at(1.0, 2.0, 3.0));
+            g.add_child(sphere_at(1.0, 2.0, 3.0));
+
+            g.build_bvh();
+
+            assert_eq!(g.children().len(), 3);
+        }
+
+        #[test]
+        fn bvh_finds_same_intersections() {
+            let g = triangle_sheet(12);
+            let mut bvh = triangle_sheet(12);
+            bvh.build_bvh();
+
+            assert!(bvh.children().len() < g.children().len());
+
+            for i in 0..10 {
+                for j in 0..10 {
+                    let origin = Vec4::new_point(i as f64 * 0.7 - 1.0, 5.0, j as f64 * 0.7 - 1.0);
+                    let r = Ray::new_ray(origin, Vec4::new_vec(0.1, -1.0, 0.2));
+
+                    let expected: Vec<f64> = get_intersection(&g, &r).iter().map(|i| i.t).collect();
+                    let actual: Vec<f64> = get_intersection(&bvh, &r).iter().map(|i| i.t).collect();
+
+                    assert_eq!(actual, expected);
+
+                    // The hit triangles still know about the group's transform
+                    if let Some(hit) = get_intersection(&bvh, &r).first() {
+                        let point = r.position(hit.t);
+                        let expected_normal = get_intersection(&g, &r)[0].object.normal_at(point);
+                        assert_eq!(hit.object.normal_at(point), expected_normal);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn build_bvh_inside_csg() {
+            let mut c = Csg::new_csg(CsgOperation::Union, triangle_sheet(4), sphere_at(0.0, 0.0, 0.0));
+            let r = Ray::new_ray(Vec4::new_point(0.7, 5.0, 0.3), Vec4::new_vec(0.0, -1.0, 0.0));
+            let expected: Vec<f64> = c.local_intersect(&r).iter().map(|i| i.t).collect();
+
+            c.build_bvh();
+            let actual: Vec<f64> = c.local_intersect(&r).iter().map(|i| i.t).collect();
+
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[cfg(test)]
+    mod csg_tests {
+        use super::*;
+        use super::super::matrices::Mat4;
+        use super::super::rays::{Sphere, same_shape};
+
+        #[test]
+        fn create_csg() {
+            let c = Csg::new_csg(CsgOperation::Union, Sphere::new_sphere(0), Cube::new_cube());
+
+            assert_eq!(c.operation, CsgOperation::Union);
+            assert!(c.includes(c.left()));
+            assert!(c.includes(c.right()));
+            assert!(!c.left().includes(c.right()));
+        }
+
+        #[test]
+        fn csg_operation_rules() {
+            // (operation, lhit, inl, inr, result)
+            let cases = [
+                (CsgOperation::Union, true, true, true, false),
+                (CsgOperation::Union, true, true, false, true),
+                (CsgOperation::Union, true, false, true, false),
+                (CsgOperation::Union, true, false, false, true),
+                (CsgOperation::Union, false, true, true, false),
+                (CsgOperation::Union, false, true, false, false),
+                (CsgOperation::Union, false, false, true, true),
+                (CsgOperation::Union, false, false, false, true),
+                (CsgOperation::Intersection, true, true, true, true),
+                (CsgOperation::Intersection, true, true, false, false),
+                (CsgOperation::Intersection, true, false, true, true),
+                (CsgOperation::Intersection, true, false, false, false),
+                (CsgOperation::Intersection, false, true, true, true),
+                (CsgOperation::Intersection, false, true, false, true),
+                (CsgOperation::Intersection, false, false, true, false),
+                (CsgOperation::Intersection, false, false, false, false),
+                (CsgOperation::Difference, true, true, true, false),
+                (CsgOperation::Difference, true, true, false, true),
+                (CsgOperation::Difference, true, false, true, false),
+                (CsgOperation::Difference, true, false, false, true),
+                (CsgOperation::Difference, false, true, true, true),
+                (CsgOperation::Difference, false, true, false, true),
+                (CsgOperation::Difference, false, false, true, false),
+                (CsgOperation::Difference, false, false, false, false),
+            ];
+
+            for (operation, lhit, inl, inr, result) in cases.iter() {
+                assert_eq!(intersection_allowed(*operation, *lhit, *inl, *inr), *result);
+            }
+        }
+
+        #[test]
+        fn filter_intersections() {
+            // (operation, index of first kept intersection, index of second kept intersection)
+            let cases = [
+                (CsgOperation::Union, 0, 3),
+                (CsgOperation::Intersection, 1, 2),
+                (CsgOperation::Difference, 0, 1),
+            ];
+
+            for (operation, x0, x1) in cases.iter() {
+                let c = Csg::new_csg(*operation, Sphere::new_sphere(0), Cube::new_cube());
+
+                let xs = [Intersection::new_intersection(1.0, c.left()),
+                          Intersection::new_intersection(2.0, c.right()),
+                          Intersection::new_intersection(3.0, c.left()),
+                          Intersection::new_intersection(4.0, c.right())];
+
+                let result = c.filter_intersections(&xs);
+
+                assert_eq!(result.len(), 2);
+                assert_eq!(result[0].t, xs[*x0].t);
+                assert_eq!(result[1].t, xs[*x1].t);
+            }
+        }
+
+        #[test]
+        fn ray_misses_csg() {
+            let c = Csg::new_csg(CsgOperation::Union, Sphere::new_sphere(0), Cube::new_cube());
+            let r = Ray::new_ray(Vec4::new_point(0.0, 2.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+
+            assert!(c.local_intersect(&r).is_empty());
+        }
+
+        #[test]
+        fn ray_hits_csg() {
+            let mut s2 = Sphere::new_sphere(1);
+            s2.set_transform(Mat4::new_translation(0.0, 0.0, 0.5));
+            let c = Csg::new_csg(CsgOperation::Union, Sphere::new_sphere(0), s2);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = c.local_intersect(&r);
+
+            assert_eq!(xs.len(), 2);
+            assert_eq!(xs[0].t, 4.0);
+            assert!(same_shape(xs[0].object, c.left()));
+            assert_eq!(xs[1].t, 6.5);
+            assert!(same_shape(xs[1].object, c.right()));
+        }
+
+        #[test]
+        fn csg_with_group_children() {
+            // A hit on a shape nested inside a group still counts as a hit on that side
+            let mut g = Group::new_group();
+            g.add_child(Sphere::new_sphere(0));
+
+            let mut s2 = Sphere::new_sphere(1);
+            s2.set_transform(Mat4::new_translation(0.0, 0.0, 0.5));
+            let c = Csg::new_csg(CsgOperation::Difference, g, s2);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, -5.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = c.local_intersect(&r);
+
+            // The left sphere with a bite taken out of its back
+            assert_eq!(xs.len(), 2);
+            assert_eq!(xs[0].t, 4.0);
+            assert_eq!(xs[1].t, 4.5);
+        }
+
+        #[test]
+        fn transformed_csg() {
+            let mut c = Csg::new_csg(CsgOperation::Intersection, Sphere::new_sphere(0), Cube::new_cube());
+            c.set_transform(Mat4::new_translation(0.0, 0.0, 10.0));
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 0.0, 0.0),
+                                 Vec4::new_vec(0.0, 0.0, 1.0));
+            let xs = get_intersection(&c, &r);
+
+            assert_eq!(xs.len(), 2);
+            assert_eq!(xs[0].t, 9.0);
+            assert_eq!(xs[0].object.normal_at(r.position(9.0)), Vec4::new_vec(0.0, 0.0, -1.0));
+            assert_eq!(c.normal_at(r.position(9.0)), Vec4::new_vec(0.0, 0.0, 0.0));
+        }
+    }
+}
+
+pub mod obj_parser {
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+
+    use super::rt_prelude::Vec4;
+    use super::rays::Shape;
+    use super::shapes::{Triangle, SmoothTriangle, Group};
+
+    // The contents of a Wavefront OBJ file
+    pub struct ObjFile {
+        pub vertices: Vec<Vec4>,
+        pub normals: Vec<Vec4>,
+        pub texture_coords: Vec<(f64, f64)>,
+        // Triangles that appear before any named group
+        pub default_group: Vec<Box<dyn Shape>>,
+        // Named groups in the order they first appear in the file
+        pub groups: Vec<(String, Vec<Box<dyn Shape>>)>,
+        // Line numbers (starting at 1) of lines that were unsupported or could not be parsed
+        pub ignored_lines: Vec<usize>,
+    }
+
+    impl ObjFile {
+        fn new_obj_file() -> ObjFile {
+            ObjFile {
+                vertices: Vec::new(),
+                normals: Vec::new(),
+                texture_coords: Vec::new(),
+                default_group: Vec::new(),
+                groups: Vec::new(),
+                ignored_lines: Vec::new(),
+            }
+        }
+
+        pub fn group(&self, name: &str) -> Option<&Vec<Box<dyn Shape>>> {
+            self.groups.iter()
+                .find(|(group_name, _)| group_name == name)
+                .map(|(_, shapes)| shapes)
+        }
+
+        pub fn triangle_count(&self) -> usize {
+            self.default_group.len() + self.groups.iter().map(|(_, shapes)| shapes.len()).sum::<usize>()
+        }
+
+        // Builds a single group for the whole file. Each named OBJ group becomes a child group, and the
+        // triangles are split into a bounding volume hierarchy so that large meshes are cheap to intersect.
+        pub fn into_group(self) -> Group {
+            let mut group = Group::new_group();
+
+            for triangle in self.default_group {
+                group.add_boxed_child(triangle);
+            }
+
+            for (_, triangles) in self.groups {
+                let mut child = Group::new_group();
+
+                for triangle in triangles {
+                    child.add_boxed_child(triangle);
+                }
+
+                group.add_child(child);
+            }
+
+            group.build_bvh();
+
+            group
+        }
+    }
+
+    // OBJ indices start at 1. Negative indices count backwards from the most recent element.
+    fn resolve_index(index: i64, len: usize) -> Option<usize> {
+        if index > 0 && (index as usize) <= len {
+            Some(index as usize - 1)
+        } else if index < 0 && (index.unsigned_abs() as usize) <= len {
+            Some(len - index.unsigned_abs() as usize)
+        } else {
+            None
+        }
+    }
+
+    fn parse_floats(values: &[&str]) -> Option<Vec<f64>> {
+        values.iter().map(|v| v.parse::<f64>().ok()).collect()
+    }
+
+    // Parses a face vertex in any of the forms v, v/vt, v//vn or v/vt/vn.
+    // Returns the vertex and, if present, its normal.
+    fn parse_face_vertex(obj: &ObjFile, token: &str) -> Option<(Vec4, Option<Vec4>)> {
+        let mut parts = token.split('/');
+
+        let vertex_index = parts.next()?.parse::<i64>().ok()?;
+        let vertex = obj.vertices[resolve_index(vertex_index, obj.vertices.len())?];
+
+        // The texture coordinate has to be valid if it is given, even though triangles do not use it
+        if let Some(vt) = parts.next() {
+            if !vt.is_empty() {
+                resolve_index(vt.parse::<i64>().ok()?, obj.texture_coords.len())?;
+            }
+        }
+
+        let normal = match parts.next() {
+            Some(vn) if !vn.is_empty() => {
+                let normal_index = vn.parse::<i64>().ok()?;
+                Some(obj.normals[resolve_index(normal_index, obj.normals.len())?])
+            }
+            _ => None,
+        };
+
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some((vertex, normal))
+    }
+
+    // Splits a polygon into a fan of triangles around its first vertex. Triangles without any area are left out.
+    fn fan_triangulation(face: &[(Vec4, Option<Vec4>)]) -> Vec<Box<dyn Shape>> {
+        let mut triangles: Vec<Box<dyn Shape>> = Vec::new();
+
+        for i in 1..(face.len() - 1) {
+            let (p1, n1) = face[0];
+            let (p2, n2) = face[i];
+            let (p3, n3) = face[i + 1];
+
+            // Only use vertex normals if every vertex of the triangle has one
+            match (n1, n2, n3) {
+                (Some(n1), Some(n2), Some(n3)) => {
+                    let triangle = SmoothTriangle::new_smooth_triangle(p1, p2, p3, n1, n2, n3);
+
+                    if !triangle.is_degenerate() {
+                        triangles.push(Box::new(triangle));
+                    }
+                }
+                _ => {
+                    let triangle = Triangle::new_triangle(p1, p2, p3);
+
+                    if !triangle.is_degenerate() {
+                        triangles.push(Box::new(triangle));
+                    }
+                }
+            }
+        }
+
+        triangles
+    }
+
+    pub fn parse_obj(source: &str) -> ObjFile {
+        let mut obj = ObjFile::new_obj_file();
+
+        // Index into obj.groups of the group faces are currently added to. None is the default group.
+        let mut current_group: Option<usize> = None;
+
+        for (line_index, line) in source.lines().enumerate() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            // Blank lines and comments are not worth reporting
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                continue;
+            }
+
+            let parsed = match (tokens[0], &tokens[1..]) {
+                ("v", values) if values.len() == 3 || values.len() == 4 => {
+                    parse_floats(&values[..3]).map(|v| obj.vertices.push(Vec4::new_point(v[0], v[1], v[2])))
+                }
+                ("vn", values) if values.len() == 3 => {
+                    parse_floats(values).map(|v| obj.normals.push(Vec4::new_vec(v[0], v[1], v[2])))
+                }
+                ("vt", values) if !values.is_empty() && values.len() <= 3 => {
+                    // The v coordinate is optional and defaults to 0
+                    parse_floats(values).map(|v| obj.texture_coords.push((v[0], *v.get(1).unwrap_or(&0.0))))
+                }
+                ("f", values) if values.len() >= 3 => {
+                    let face: Option<Vec<(Vec4, Option<Vec4>)>> = values.iter()
+                        .map(|token| parse_face_vertex(&obj, token))
+                        .collect();
+
+                    face.and_then(|face| {
+                        let mut triangles = fan_triangulation(&face);
+
+                        // Every vertex is on one line, so the face cannot be drawn
+                        if triangles.is_empty() {
+                            return None;
+                        }
+
+                        match current_group {
+                            Some(group_index) => obj.groups[group_index].1.append(&mut triangles),
+                            None => obj.default_group.append(&mut triangles),
+                        }
+
+                        Some(())
+                    })
+                }
+                ("g", names) if !names.is_empty() => {
+                    let name = names.join(" ");
+
+                    // Reopening a group adds to the existing one
+                    current_group = match obj.groups.iter().position(|(group_name, _)| *group_name == name) {
+                        Some(group_index) => Some(group_index),
+                        None => {
+                            obj.groups.push((name, Vec::new()));
+                            Some(obj.groups.len() - 1)
+                        }
+                    };
+
+                    Some(())
+                }
+                _ => None,
+            };
+
+            if parsed.is_none() {
+                obj.ignored_lines.push(line_index + 1);
+            }
+        }
+
+        obj
+    }
+
+    pub fn parse_obj_file<P: AsRef<Path>>(path: P) -> io::Result<ObjFile> {
+        Ok(parse_obj(&fs::read_to_string(path)?))
+    }
+
+    #[cfg(test)]
+    mod obj_parser_tests {
+        use super::*;
+        use super::super::rays::Ray;
+
+        #[test]
+        fn ignore_unrecognized_lines() {
+            let gibberish = "There was a young lady named Bright\n\
+                             who traveled much faster than light.\n\
+                             She set out one day\n\
+                             in a relative way,\n\
+                             and came back the previous night.\n";
+
+            let obj = parse_obj(gibberish);
+
+            assert_eq!(obj.ignored_lines, vec![1, 2, 3, 4, 5]);
+            assert_eq!(obj.triangle_count(), 0);
+        }
+
+        #[test]
+        fn report_malformed_lines() {
+            let source = "v 1 2 3\n\
+                          # comments and blank lines are fine\n\
+                          \n\
+                          v 1 two 3\n\
+                          vn 0 1\n\
+                          f 1 1\n\
+                          f 1 2 3\n\
+                          f 1 1/1 1\n\
+                          f -9223372036854775808 1 2\n";
+
+            let obj = parse_obj(source);
+
+            // Bad numbers, too few values, missing vertices and a missing texture coordinate
+            assert_eq!(obj.ignored_lines, vec![4, 5, 6, 7, 8, 9]);
+            assert_eq!(obj.vertices.len(), 1);
+        }
+
+        #[test]
+        fn vertex_records() {
+            let source = "v -1 1 0\n\
+                          v -1.0000 0.5000 0.0000\n\
+                          v 1 0 0\n\
+                          v 1 1 0 1.0\n";
+
+            let obj = parse_obj(source);
+
+            assert_eq!(obj.vertices, vec![Vec4::new_point(-1.0, 1.0, 0.0),
+                                          Vec4::new_point(-1.0, 0.5, 0.0),
+                                          Vec4::new_point(1.0, 0.0, 0.0),
+                                          Vec4::new_point(1.0, 1.0, 0.0)]);
+            assert!(obj.ignored_lines.is_empty());
+        }
+
+        #[test]
+        fn vertex_normal_and_texture_records() {
+            let source = "vn 0 0 1\n\
+                          vn 0.707 0 -0.707\n\
+                          vn 1 2 3\n\
+                          vt 0.5 0.25\n\
+                          vt 0.75\n";
+
+            let obj = parse_obj(source);
+
+            assert_eq!(obj.normals, vec![Vec4::new_vec(0.0, 0.0, 1.0),
+                                         Vec4::new_vec(0.707, 0.0, -0.707),
+                                         Vec4::new_vec(1.0, 2.0, 3.0)]);
+            assert_eq!(obj.texture_coords, vec![(0.5, 0.25), (0.75, 0.0)]);
+        }
+
+        #[test]
+        fn triangle_faces() {
+            let source = "v -1 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          v 1 1 0\n\
+                          \n\
+                          f 1 2 3\n\
+                          f 1 3 4\n";
+
+            let obj = parse_obj(source);
+
+            assert_eq!(obj.default_group.len(), 2);
+
+            // Both triangles face the same way
+            let r = Ray::new_ray(Vec4::new_point(0.5, 0.5, -2.0), Vec4::new_vec(0.0, 0.0, 1.0));
+            assert!(obj.default_group[0].local_intersect(&r).is_empty());
+            assert_eq!(obj.default_group[1].local_intersect(&r).len(), 1);
+            assert_eq!(obj.default_group[1].local_normal_at(Vec4::new_point(0.5, 0.5, 0.0)),
+                       Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn skip_degenerate_faces() {
+            let source = "v 0 0 0\n\
+                          v 1 0 0\n\
+                          v 2 0 0\n\
+                          v 1 1 0\n\
+                          \n\
+                          f 1 2 3\n\
+                          f 1 1 4\n\
+                          f 1 2 3 4\n";
+
+            let obj = parse_obj(source);
+
+            // The first two faces have no area. Only the second half of the quad's fan does.
+            assert_eq!(obj.ignored_lines, vec![6, 7]);
+            assert_eq!(obj.default_group.len(), 1);
+            assert_eq!(obj.default_group[0].local_normal_at(Vec4::new_point(1.5, 0.5, 0.0)),
+                       Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn triangulate_polygons() {
+            let source = "v -1 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          v 1 1 0\n\
+                          v 0 2 0\n\
+                          \n\
+                          f 1 2 3 4 5\n";
+
+            let obj = parse_obj(source);
+
+            // A pentagon becomes a fan of three triangles
+            assert_eq!(obj.default_group.len(), 3);
+
+            let r = Ray::new_ray(Vec4::new_point(0.0, 1.5, -2.0), Vec4::new_vec(0.0, 0.0, 1.0));
+            let hits: usize = obj.default_group.iter().map(|t| t.local_intersect(&r).len()).sum();
+            assert_eq!(hits, 1);
+        }
+
+        #[test]
+        fn negative_indices() {
+            let source = "v -1 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          f -3 -2 -1\n";
+
+            let obj = parse_obj(source);
+
+            assert_eq!(obj.default_group.len(), 1);
+            assert!(obj.ignored_lines.is_empty());
+        }
+
+        #[test]
+        fn named_groups() {
+            let source = "v -1 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          v 1 1 0\n\
+                          g FirstGroup\n\
+                          f 1 2 3\n\
+                          g SecondGroup\n\
+                          f 1 3 4\n\
+                          g FirstGroup\n\
+                          f 2 3 4\n";
+
+            let obj = parse_obj(source);
+
+            assert!(obj.default_group.is_empty());
+            assert_eq!(obj.groups.len(), 2);
+            assert_eq!(obj.groups[0].0, "FirstGroup");
+            assert_eq!(obj.group("FirstGroup").unwrap().len(), 2);
+            assert_eq!(obj.group("SecondGroup").unwrap().len(), 1);
+            assert!(obj.group("ThirdGroup").is_none());
+            assert_eq!(obj.triangle_count(), 3);
+        }
+
+        #[test]
+        fn faces_with_normals() {
+            let source = "v 0 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          \n\
+                          vn -1 0 0\n\
+                          vn 1 0 0\n\
+                          vn 0 1 0\n\
+                          vt 0 0\n\
+                          \n\
+                          f 1//3 2//1 3//2\n\
+                          f 1/1/3 2/1/1 3/1/2\n\
+                          f 1/1 2/1 3/1\n";
+
+            let obj = parse_obj(source);
+
+            assert!(obj.ignored_lines.is_empty());
+            assert_eq!(obj.default_group.len(), 3);
+
+            // Faces with normals are smooth, so the normal changes across the surface
+            let r = Ray::new_ray(Vec4::new_point(-0.2, 0.3, -2.0), Vec4::new_vec(0.0, 0.0, 1.0));
+            for triangle in obj.default_group[..2].iter() {
+                let xs = triangle.local_intersect(&r);
+                assert_eq!(triangle.local_normal_at_hit(Vec4::new_point(-0.2, 0.3, 0.0), &xs[0]),
+                           Vec4::new_vec(-0.2, 0.3, 0.0));
+            }
+
+            // Faces without normals are flat
+            assert_eq!(obj.default_group[2].local_normal_at(Vec4::new_point(-0.2, 0.3, 0.0)),
+                       Vec4::new_vec(0.0, 0.0, -1.0));
+        }
+
+        #[test]
+        fn convert_obj_to_group() {
+            let source = "v -1 1 0\n\
+                          v -1 0 0\n\
+                          v 1 0 0\n\
+                          v 1 1 0\n\
+                          f 1 2 3\n\
+                          g FirstGroup\n\
+                          f 1 2 3\n\
+                          g SecondGroup\n\
+                          f 1 3 4\n";
+
+            let g = parse_obj(source).into_group();
+
+            // The default group's triangle, then one child group per named group
+            assert_eq!(g.children().len(), 3);
+
+            let r = Ray::new_ray(Vec4::new_point(0.5, 0.5, -2.0), Vec4::new_vec(0.0, 0.0, 1.0));
+            assert_eq!(g.local_intersect(&r).len(), 1);
+        }
+
+        #[test]
+        fn large_meshes_become_bvhs() {
+            // A 20 by 20 grid of squares, each split into two triangles
+            let mut source = String::new();
+
+            for i in 0..21 {
+                for j in 0..21 {
+                    source.push_str(&format!("v {} 0 {}\n", i, j));
+                }
+            }
+
+            for i in 0..20 {
+                for j in 0..20 {
+                    let v = i * 21 + j + 1;
+                    source.push_str(&format!("f {} {} {} {}\n", v, v + 1, v + 22, v + 21));
+                }
+            }
+
+            let mut flat = Group::new_group();
+            for triangle in parse_obj(&source).default_group {
+                flat.add_boxed_child(triangle);
+            }
+
+            let g = parse_obj(&source).into_group();
+
+            // Rays only have to visit the nested groups whose boxes they hit, not all 800 triangles
+            assert_eq!(flat.children().len(), 800);
+            assert_eq!(g.children().len(), 2);
+            assert_eq!(g.bounds(), flat.bounds());
+
+            for i in 0..20 {
+                let r = Ray::new_ray(Vec4::new_point(i as f64 + 0.3, 1.0, 19.6 - i as f64),
+                                     Vec4::new_vec(0.0, -1.0, 0.0));
+
+                let expected: Vec<f64> = flat.local_intersect(&r).iter().map(|i| i.t).collect();
+                let actual: Vec<f64> = g.local_intersect(&r).iter().map(|i| i.t).collect();
+
+                assert_eq!(actual, vec![1.0]);
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+}
+
+pub mod bounds {
+    use super::rt_prelude::{Vec4, EPSILON};
+    use super::matrices::Mat4;
+    use super::rays::Ray;
+
+    // Finds where a ray enters and leaves the slab between min and max along a single axis
+    fn check_axis(origin: f64, direction: f64, min: f64, max: f64) -> (f64, f64) {
+        let tmin_numerator = min - origin;
+        let tmax_numerator = max - origin;
+
+        // A ray parallel to the slab either never enters it or never leaves it
+        let (tmin, tmax) = if direction.abs() >= EPSILON {
+            (tmin_numerator / direction, tmax_numerator / direction)
+        } else {
+            (tmin_numerator * f64::INFINITY, tmax_numerator * f64::INFINITY)
+        };
+
+        if tmin > tmax {
+            (tmax, tmin)
+        } else {
+            (tmin, tmax)
+        }
+    }
+
+    // An axis-aligned bounding box. A box that contains nothing has min above max on every axis.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct Aabb {
+        pub min: Vec4,
+        pub max: Vec4,
+    }
+
+    impl Aabb {
+        pub fn new_aabb(min: Vec4, max: Vec4) -> Aabb {
+            Aabb { min, max }
+        }
+
+        pub fn new_empty_aabb() -> Aabb {
+            Aabb {
+                min: Vec4::new_point(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+                max: Vec4::new_point(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+        }
+
+        // False for boxes that reach infinity on any side, like the bounds of a plane
+        pub fn is_finite(&self) -> bool {
+            self.min.x.is_finite() && self.min.y.is_finite() && self.min.z.is_finite() &&
+            self.max.x.is_finite() && self.max.y.is_finite() && self.max.z.is_finite()
+        }
+
+        pub fn add_point(&mut self, point: Vec4) {
+            self.min = Vec4::new_point(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z));
+            self.max = Vec4::new_point(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z));
+        }
+
+        pub fn add_box(&mut self, other: &Aabb) {
+            if !other.is_empty() {
+                self.add_point(other.min);
+                self.add_point(other.max);
+            }
+        }
+
+        pub fn contains_point(&self, point: Vec4) -> bool {
+            self.min.x <= point.x && point.x <= self.max.x &&
+            self.min.y <= point.y && point.y <= self.max.y &&
+            self.min.z <= point.z && point.z <= self.max.z
+        }
+
+        pub fn contains_box(&self, other: &Aabb) -> bool {
+            self.contains_point(other.min) && self.contains_point(other.max)
+        }
+
+        pub fn centroid(&self) -> Vec4 {
+            (self.min + self.max) * 0.5
+        }
+
+        pub fn surface_area(&self) -> f64 {
+            if self.is_empty() {
+                return 0.0;
+            }
+
+            let extent = self.max - self.min;
+
+            2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+        }
+
+        // The smallest box that contains this box after it has been transformed.
+        // Each axis of the new box is built from the matrix row, which avoids multiplying infinite
+        // extents by zero like transforming the eight corners would.
+        pub fn transform(&self, mat: &Mat4) -> Aabb {
+            if self.is_empty() {
+                return *self;
+            }
+
+            let min = [self.min.x, self.min.y, self.min.z];
+            let max = [self.max.x, self.max.y, self.max.z];
+            let mut new_min = [0.0; 3];
+            let mut new_max = [0.0; 3];
+
+            for row in 0..3 {
+                new_min[row] = mat.data[row][3];
+                new_max[row] = mat.data[row][3];
+
+                for col in 0..3 {
+                    let factor = mat.data[row][col];
+
+                    if factor == 0.0 {
+                        continue;
+                    }
+
+                    let a = factor * min[col];
+                    let b = factor * max[col];
+
+                    new_min[row] += a.min(b);
+                    new_max[row] += a.max(b);
+                }
+            }
+
+            Aabb::new_aabb(Vec4::new_point(new_min[0], new_min[1], new_min[2]),
+                           Vec4::new_point(new_max[0], new_max[1], new_max[2]))
+        }
+
+        // Slab test. Returns the t values where the ray enters and leaves the box, or None if it misses.
+        // Hits behind the ray's origin count, like they do for every shape.
+        pub fn intersection_range(&self, ray: &Ray) -> Option<(f64, f64)> {
+            if self.is_empty() {
+                return None;
+            }
+
+            let (xtmin, xtmax) = check_axis(ray.origin.x, ray.direction.x, self.min.x, self.max.x);
+            let (ytmin, ytmax) = check_axis(ray.origin.y, ray.direction.y, self.min.y, self.max.y);
+            let (ztmin, ztmax) = check_axis(ray.origin.z, ray.direction.z, self.min.z, self.max.z);
+
+            // The ray is inside the box after it has entered every slab and before it has left any of them
+            let tmin = xtmin.max(ytmin).max(ztmin);
+            let tmax = xtmax.min(ytmax).min(ztmax);
+
+            if tmin > tmax {
+                None
+            } else {
+                Some((tmin, tmax))
+            }
+        }
+
+        pub fn intersects(&self, ray: &Ray) -> bool {
+            self.intersection_range(ray).is_some()
+        }
+    }
+
+    #[cfg(test)]
+    mod bounds_tests {
+        use super::*;
+        use std::f64::consts::PI;
+        use super::super::rays::{Shape, Sphere};
+        use super::super::shapes::{Plane, Cube, Cylinder, Cone, Triangle, Group, Csg, CsgOperation};
+
+        #[test]
+        fn empty_bounding_box() {
+            let b = Aabb::new_empty_aabb();
+
+            assert!(b.is_empty());
+            assert_eq!(b.surface_area(), 0.0);
+        }
+
+        #[test]
+        fn add_points_to_box() {
+            let mut b = Aabb::new_empty_aabb();
+            b.add_point(Vec4::new_point(-5.0, 2.0, 0.0));
+            b.add_point(Vec4::new_point(7.0, 0.0, -3.0));
+
+            assert_eq!(b.min, Vec4::new_point(-5.0, 0.0, -3.0));
+            assert_eq!(b.max, Vec4::new_point(7.0, 2.0, 0.0));
+        }
+
+        #[test]
+        fn add_box_to_box() {
+            let mut b1 = Aabb::new_aabb(Vec4::new_point(-5.0, -2.0, 0.0), Vec4::new_point(7.0, 4.0, 4.0));
+            let b2 = Aabb::new_aabb(Vec4::new_point(8.0, -7.0, -2.0), Vec4::new_point(14.0, 2.0, 8.0));
+            b1.add_box(&b2);
+            b1.add_box(&Aabb::new_empty_aabb());
+
+            assert_eq!(b1.min, Vec4::new_point(-5.0, -7.0, -2.0));
+            assert_eq!(b1.max, Vec4::new_point(14.0, 4.0, 8.0));
+        }
+
+        #[test]
+        fn box_contains_point() {
+            let b = Aabb::new_aabb(Vec4::new_point(5.0, -2.0, 0.0), Vec4::new_point(11.0, 4.0, 7.0));
+
+            // (point, result)
+            let cases = [
+                (Vec4::new_point(5.0, -2.0, 0.0), true),
+                (Vec4::new_point(11.0, 4.0, 7.0), true),
+                (Vec4::new_point(8.0, 1.0, 3.0), true),
+                (Vec4::new_point(3.0, 0.0, 3.0), false),
+                (Vec4::new_point(8.0, -4.0, 3.0), false),
+                (Vec4::new_point(8.0, 1.0, -1.0), false),
+                (Vec4::new_point(13.0, 1.0, 3.0), false),
+                (Vec4::new_point(8.0, 5.0, 3.0), false),
+                (Vec4::new_point(8.0, 1.0, 8.0), false),
+            ];
+
+            for (point, result) in cases.iter() {
+                assert_eq!(b.contains_point(*point), *result);
+            }
+        }
+
+        #[test]
+        fn box_contains_box() {
+            let b = Aabb::new_aabb(Vec4::new_point(5.0, -2.0, 0.0), Vec4::new_point(11.0, 4.0, 7.0));
+
+            // (min, max, result)
+            let cases = [
+                (Vec4::new_point(5.0, -2.0, 0.0), Vec4::new_point(11.0, 4.0, 7.0), true),
+                (Vec4::new_point(6.0, -1.0, 1.0), Vec4::new_point(10.0, 3.0, 6.0), true),
+                (Vec4::new_point(4.0, -3.0, -1.0), Vec4::new_point(10.0, 3.0, 6.0), false),
+                (Vec4::new_point(6.0, -1.0, 1.0), Vec4::new_point(12.0, 5.0, 8.0), false),
+            ];
+
+            for (min, max, result) in cases.iter() {
+                assert_eq!(b.contains_box(&Aabb::new_aabb(*min, *max)), *result);
+            }
+        }
+
+        #[test]
+        fn surface_area() {
+            let b = Aabb::new_aabb(Vec4::new_point(-1.0, -2.0, -3.0), Vec4::new_point(1.0, 2.0, 3.0));
+
+            assert_eq!(b.surface_area(), 2.0 * (2.0 * 4.0 + 4.0 * 6.0 + 6.0 * 2.0));
+            assert_eq!(b.centroid(), Vec4::new_point(0.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn transform_box() {
+            let b = Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0));
+            let m = Mat4::new_rotation_x(PI / 4.0) * Mat4::new_rotation_y(PI / 4.0);
+            let b2 = b.transform(&m);
+
+            let sqrt_2 = 2.0_f64.sqrt();
+
+            assert_eq!(b2.min, Vec4::new_point(-sqrt_2, -1.0 - sqrt_2 / 2.0, -1.0 - sqrt_2 / 2.0));
+            assert_eq!(b2.max, Vec4::new_point(sqrt_2, 1.0 + sqrt_2 / 2.0, 1.0 + sqrt_2 / 2.0));
+        }
+
+        #[test]
+        fn transform_infinite_box() {
+            let b = Plane::new_plane().bounds();
+            let b2 = b.transform(&Mat4::new_rotation_x(PI / 2.0).translate(0.0, 0.0, 5.0));
+
+            assert!(!b2.is_finite());
+            assert!(!b2.min.x.is_nan() && !b2.min.y.is_nan() && !b2.min.z.is_nan());
+            assert_eq!(b2.min.x, f64::NEG_INFINITY);
+            assert_eq!(b2.max.x, f64::INFINITY);
+        }
+
+        #[test]
+        fn shape_bounds() {
+            let mut cylinder = Cylinder::new_cylinder();
+            cylinder.minimum = -5.0;
+            cylinder.maximum = 3.0;
+
+            let mut cone = Cone::new_cone();
+            cone.minimum = -5.0;
+            cone.maximum = 3.0;
+
+            let triangle = Triangle::new_triangle(Vec4::new_point(-3.0, 7.0, 2.0),
+                                                  Vec4::new_point(6.0, 2.0, -4.0),
+                                                  Vec4::new_point(2.0, -1.0, -1.0));
+
+            assert_eq!(Sphere::new_sphere(0).bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0)));
+            assert_eq!(Cube::new_cube().bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-1.0, -1.0, -1.0), Vec4::new_point(1.0, 1.0, 1.0)));
+            assert_eq!(cylinder.bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-1.0, -5.0, -1.0), Vec4::new_point(1.0, 3.0, 1.0)));
+            assert_eq!(cone.bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-5.0, -5.0, -5.0), Vec4::new_point(5.0, 3.0, 5.0)));
+            assert_eq!(triangle.bounds(),
+                       Aabb::new_aabb(Vec4::new_point(-3.0, -1.0, -4.0), Vec4::new_point(6.0, 7.0, 2.0)));
+
+            // Vec4 equality is approximate, which does not work for infinities
+            let plane = Plane::new_plane().bounds();
+            assert_eq!((plane.min.x, plane.min.y, plane.min.z), (f64::NEG_INFINITY, 0.0, f64::NEG_INFINITY));
+            assert_eq!((plane.max.x, plane.max.y, plane.max.z), (f64::INFINITY, 0.0, f64::INFINITY));
+        }
+
+        #[test]
+        fn parent_space_bounds() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(1.0, -3.0, 5.0).scale(0.5, 2.0, 4.0));
+            let b = s.parent_space_bounds();
+
+            assert_eq!(b.min, Vec4::new_point(0.5, -5.0, 1.0));
+            assert_eq!(b.max, Vec4::new_point(1.5, -1.0, 9.0));
+        }
+
+        #[test]
+        fn group_bounds_contain_children() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_translation(2.0, 5.0, -3.0).scale(2.0, 2.0, 2.0));
+
+            let mut c = Cylinder::new_cylinder();
+            c.minimum = -2.0;
+            c.maximum = 2.0;
+            c.set_transform(Mat4::new_translation(-4.0, -1.0, 4.0).scale(0.5, 1.0, 0.5));
+
+            let mut g = Group::new_group();
+            g.add_child(s);
+            g.add_child(c);
+
+            assert_eq!(g.bounds().min, Vec4::new_point(-4.5, -3.0, -5.0));
+            assert_eq!(g.bounds().max, Vec4::new_point(4.0, 7.0, 4.5));
+        }
+
+        #[test]
+        fn csg_bounds_contain_children() {
+            let mut right = Sphere::new_sphere(1);
+            right.set_transform(Mat4::new_translation(2.0, 3.0, 4.0));
+            let c = Csg::new_csg(CsgOperation::Difference, Sphere::new_sphere(0), right);
+
+            assert_eq!(c.bounds().min, Vec4::new_point(-1.0, -1.0, -1.0));
+            assert_eq!(c.bounds().max, Vec4::new_point(3.0, 4.0, 5.0));
+        }
+
+        #[test]
+        fn ray_intersects_box() {
+            let b = Aabb::new_aabb(Vec4::new_point(5.0, -2.0, 0.0), Vec4::new_point(11.0, 4.0, 7.0));
+
+            // (origin, direction, result)
+            let cases = [
+                (Vec4::new_point(15.0, 1.0, 2.0), Vec4::new_vec(-1.0, 0.0, 0.0), true),
+                (Vec4::new_point(-5.0, -1.0, 4.0), Vec4::new_vec(1.0, 0.0, 0.0), true),
+                (Vec4::new_point(7.0, 6.0, 5.0), Vec4::new_vec(0.0, -1.0, 0.0), true),
+                (Vec4::new_point(9.0, -5.0, 6.0), Vec4::new_vec(0.0, 1.0, 0.0), true),
+                (Vec4::new_point(8.0, 2.0, 12.0), Vec4::new_vec(0.0, 0.0, -1.0), true),
+                (Vec4::new_point(6.0, 0.0, -5.0), Vec4::new_vec(0.0, 0.0, 1.0), true),
+                (Vec4::new_point(8.0, 1.0, 3.5), Vec4::new_vec(0.0, 0.0, 1.0), true),
+                (Vec4::new_point(9.0, -1.0, -8.0), Vec4::new_vec(2.0, 4.0, 6.0), false),
+                (Vec4::new_point(8.0, 3.0, -4.0), Vec4::new_vec(6.0, 2.0, 4.0), false),
+                (Vec4::new_point(9.0, -1.0, -2.0), Vec4::new_vec(4.0, 6.0, 2.0), false),
+                (Vec4::new_point(4.0, 0.0, 9.0), Vec4::new_vec(0.0, 0.0, -1.0), false),
+                (Vec4::new_point(8.0, 6.0, -1.0), Vec4::new_vec(0.0, -1.0, 0.0), false),
+                (Vec4::new_point(12.0, 5.0, 4.0), Vec4::new_vec(-1.0, 0.0, 0.0), false),
+            ];
+
+            for (origin, direction, result) in cases.iter() {
+                let r = Ray::new_ray(*origin, direction.normalized());
+                assert_eq!(b.intersects(&r), *result);
+            }
+        }
+
+        #[test]
+        fn ray_intersects_flat_box() {
+            // Triangles lying in an axis-aligned plane have boxes with no thickness
+            let b = Aabb::new_aabb(Vec4::new_point(-1.0, 0.0, -1.0), Vec4::new_point(1.0, 0.0, 1.0));
+            let r = Ray::new_ray(Vec4::new_point(0.5, 2.0, 0.5), Vec4::new_vec(0.0, -1.0, 0.0));
+
+            assert!(b.intersects(&r));
+            assert!(!Aabb::new_empty_aabb().intersects(&r));
+        }
+    }
+}
+
+pub mod patterns {
+    use std::fmt;
+    use std::fmt::Debug;
+    use std::f64::consts::PI;
+    use super::rt_prelude::{Vec4, Color, Canvas};
+    use super::matrices::Mat4;
+    use super::rays::Shape;
+    use super::noise::Noise;
+
+    // Transform handling shared by every pattern. The inverted transform is cached like it is for shapes.
+    #[derive(Copy, Clone, Debug)]
+    pub struct PatternData {
+        transform: Mat4,
+        transform_inverted: Option<Mat4>,
+    }
+
+    impl PatternData {
+        pub fn new_pattern_data() -> PatternData {
+            PatternData { transform: Mat4::id(), transform_inverted: Some(Mat4::id()) }
+        }
+
+        pub fn set_transform(&mut self, mat: Mat4) {
+            self.transform = mat;
+            self.transform_inverted = mat.inverted();
+        }
+    }
+
+    pub trait Pattern: Debug {
+        fn data(&self) -> &PatternData;
+        fn data_mut(&mut self) -> &mut PatternData;
+
+        // Color at a point that has already been transformed into pattern space
+        fn local_pattern_at(&self, point: Vec4) -> Color;
+
+        fn transform(&self) -> &Mat4 {
+            &self.data().transform
+        }
+
+        fn set_transform(&mut self, mat: Mat4) {
+            self.data_mut().set_transform(mat);
+        }
+
+        // Color at a point in the space the pattern is attached to. That is the object's space,
+        // or the space of the pattern wrapping this one.
+        fn pattern_at(&self, point: Vec4) -> Color {
+            let pattern_point = self.data().transform_inverted
+                .expect("pattern transform is not invertible") * point;
+
+            self.local_pattern_at(pattern_point)
+        }
+
+        // Color at a world space point on the object. The point is moved into the object's space
+        // and then into the pattern's, so the pattern moves with the object.
+        fn pattern_at_shape(&self, object: &dyn Shape, world_point: Vec4) -> Color {
+            self.pattern_at(object.world_to_object(world_point))
+        }
+    }
+
+    // True for every other unit step along an axis, starting from 0
+    fn is_even(value: f64) -> bool {
+        value.floor() % 2.0 == 0.0
+    }
+
+    // Alternates between a and b every unit along the x axis
+    #[derive(Debug)]
+    pub struct StripePattern {
+        data: PatternData,
+        pub a: Color,
+        pub b: Color,
+    }
+
+    impl StripePattern {
+        pub fn new_stripe_pattern(a: Color, b: Color) -> StripePattern {
+            StripePattern { data: PatternData::new_pattern_data(), a, b }
+        }
+    }
+
+    impl Pattern for StripePattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            if is_even(point.x) { self.a } else { self.b }
+        }
+    }
+
+    // Blends from a to b along the x axis, starting over every unit
+    #[derive(Debug)]
+    pub struct GradientPattern {
+        data: PatternData,
+        pub a: Color,
+        pub b: Color,
+    }
+
+    impl GradientPattern {
+        pub fn new_gradient_pattern(a: Color, b: Color) -> GradientPattern {
+            GradientPattern { data: PatternData::new_pattern_data(), a, b }
+        }
+    }
+
+    impl Pattern for GradientPattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            let fraction = point.x - point.x.floor();
+
+            self.a + (self.b - self.a) * fraction
+        }
+    }
+
+    // Concentric rings around the y axis, alternating between a and b every unit
+    #[derive(Debug)]
+    pub struct RingPattern {
+        data: PatternData,
+        pub a: Color,
+        pub b: Color,
+    }
+
+    impl RingPattern {
+        pub fn new_ring_pattern(a: Color, b: Color) -> RingPattern {
+            RingPattern { data: PatternData::new_pattern_data(), a, b }
+        }
+    }
+
+    impl Pattern for RingPattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            let distance = (point.x.powi(2) + point.z.powi(2)).sqrt();
+
+            if is_even(distance) { self.a } else { self.b }
+        }
+    }
+
+    // Unit cubes alternating between a and b in all three dimensions
+    #[derive(Debug)]
+    pub struct CheckersPattern {
+        data: PatternData,
+        pub a: Color,
+        pub b: Color,
+    }
+
+    impl CheckersPattern {
+        pub fn new_checkers_pattern(a: Color, b: Color) -> CheckersPattern {
+            CheckersPattern { data: PatternData::new_pattern_data(), a, b }
+        }
+    }
+
+    impl Pattern for CheckersPattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            let sum = point.x.floor() + point.y.floor() + point.z.floor();
+
+            if is_even(sum) { self.a } else { self.b }
+        }
+    }
+
+    // Maps a point on the unit sphere to u and v. u goes around the y axis and v from the bottom to the top.
+    pub fn spherical_map(point: Vec4) -> (f64, f64) {
+        // Azimuthal angle between -pi and pi, going the wrong way around
+        let theta = point.x.atan2(point.z);
+        let radius = Vec4::new_vec(point.x, point.y, point.z).magnitude();
+        // Polar angle between 0 and pi
+        let phi = (point.y / radius).acos();
+
+        let raw_u = theta / (2.0 * PI);
+
+        (1.0 - (raw_u + 0.5), 1.0 - phi / PI)
+    }
+
+    // Maps a point on the xz plane to u and v, repeating every unit
+    pub fn planar_map(point: Vec4) -> (f64, f64) {
+        (point.x.rem_euclid(1.0), point.z.rem_euclid(1.0))
+    }
+
+    // Maps a point on the unit cylinder to u and v. u goes around the y axis and v repeats every unit along it.
+    pub fn cylindrical_map(point: Vec4) -> (f64, f64) {
+        let theta = point.x.atan2(point.z);
+        let raw_u = theta / (2.0 * PI);
+
+        (1.0 - (raw_u + 0.5), point.y.rem_euclid(1.0))
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum CubeFace {
+        Left,
+        Right,
+        Front,
+        Back,
+        Up,
+        Down,
+    }
+
+    // The face of the unit cube a point is on, chosen by its largest component
+    pub fn cube_face(point: Vec4) -> CubeFace {
+        let abs_x = point.x.abs();
+        let abs_y = point.y.abs();
+        let abs_z = point.z.abs();
+        let max_c = abs_x.max(abs_y).max(abs_z);
+
+        if max_c == point.x {
+            CubeFace::Right
+        } else if max_c == -point.x {
+            CubeFace::Left
+        } else if max_c == point.y {
+            CubeFace::Up
+        } else if max_c == -point.y {
+            CubeFace::Down
+        } else if max_c == point.z {
+            CubeFace::Front
+        } else {
+            CubeFace::Back
+        }
+    }
+
+    // u and v on a single face of the unit cube, as seen from outside the cube
+    pub fn cube_face_uv(face: CubeFace, point: Vec4) -> (f64, f64) {
+        let (u, v) = match face {
+            CubeFace::Front => (point.x + 1.0, point.y + 1.0),
+            CubeFace::Back => (1.0 - point.x, point.y + 1.0),
+            CubeFace::Left => (point.z + 1.0, point.y + 1.0),
+            CubeFace::Right => (1.0 - point.z, point.y + 1.0),
+            CubeFace::Up => (point.x + 1.0, 1.0 - point.z),
+            CubeFace::Down => (point.x + 1.0, point.z + 1.0),
+        };
+
+        (u.rem_euclid(2.0) / 2.0, v.rem_euclid(2.0) / 2.0)
+    }
+
+    // Maps a point on the unit cube into an unfolded cross that is 4 faces wide and 3 high:
+    //
+    //     [  ][Up][  ][  ]
+    //     [Lt][Ft][Rt][Bk]
+    //     [  ][Dn][  ][  ]
+    pub fn cube_map(point: Vec4) -> (f64, f64) {
+        let face = cube_face(point);
+        let (u, v) = cube_face_uv(face, point);
+
+        // Column from the left and row from the bottom of the face within the cross
+        let (column, row) = match face {
+            CubeFace::Left => (0.0, 1.0),
+            CubeFace::Front => (1.0, 1.0),
+            CubeFace::Right => (2.0, 1.0),
+            CubeFace::Back => (3.0, 1.0),
+            CubeFace::Up => (1.0, 2.0),
+            CubeFace::Down => (1.0, 0.0),
+        };
+
+        ((column + u) / 4.0, (row + v) / 3.0)
+    }
+
+    // How a point on a shape is turned into texture coordinates
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum UvMapping {
+        Spherical,
+        Planar,
+        Cylindrical,
+        Cube,
+    }
+
+    impl UvMapping {
+        pub fn map(&self, point: Vec4) -> (f64, f64) {
+            match self {
+                UvMapping::Spherical => spherical_map(point),
+                UvMapping::Planar => planar_map(point),
+                UvMapping::Cylindrical => cylindrical_map(point),
+                UvMapping::Cube => cube_map(point),
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum TextureFilter {
+        // The closest pixel
+        Nearest,
+        // A blend of the four closest pixels
+        Bilinear,
+    }
+
+    // Looks up colors in an image. u runs from the left edge to the right edge of the canvas
+    // and v from the bottom edge to the top edge.
+    pub struct TexturePattern {
+        data: PatternData,
+        canvas: Canvas,
+        pub mapping: UvMapping,
+        pub filter: TextureFilter,
+    }
+
+    impl TexturePattern {
+        pub fn new_texture_pattern(canvas: Canvas, mapping: UvMapping) -> TexturePattern {
+            TexturePattern { data: PatternData::new_pattern_data(), canvas, mapping, filter: TextureFilter::Bilinear }
+        }
+
+        pub fn canvas(&self) -> &Canvas {
+            &self.canvas
+        }
+
+        pub fn uv_pattern_at(&self, u: f64, v: f64) -> Color {
+            // There is nothing to sample in an empty canvas
+            if self.canvas.width == 0 || self.canvas.height == 0 {
+                return Color::new(0.0, 0.0, 0.0);
+            }
+
+            // Flip v, since the canvas starts at the top
+            let x = u.clamp(0.0, 1.0) * (self.canvas.width - 1) as f64;
+            let y = (1.0 - v.clamp(0.0, 1.0)) * (self.canvas.height - 1) as f64;
+
+            match self.filter {
+                TextureFilter::Nearest => self.canvas.read_pixel(x.round() as usize, y.round() as usize),
+                TextureFilter::Bilinear => {
+                    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+                    let x1 = (x0 + 1).min(self.canvas.width - 1);
+                    let y1 = (y0 + 1).min(self.canvas.height - 1);
+                    let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+
+                    let top = self.canvas.read_pixel(x0, y0) * (1.0 - tx) + self.canvas.read_pixel(x1, y0) * tx;
+                    let bottom = self.canvas.read_pixel(x0, y1) * (1.0 - tx) + self.canvas.read_pixel(x1, y1) * tx;
+
+                    top * (1.0 - ty) + bottom * ty
+                }
+            }
+        }
+    }
+
+    // Canvases are too big to print
+    impl Debug for TexturePattern {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("TexturePattern")
+                .field("canvas", &(self.canvas.width, self.canvas.height))
+                .field("mapping", &self.mapping)
+                .field("filter", &self.filter)
+                .finish()
+        }
+    }
+
+    impl Pattern for TexturePattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            let (u, v) = self.mapping.map(point);
+
+            self.uv_pattern_at(u, v)
+        }
+    }
+
+    // Offsets for the noise lookups of the y and z jitter, so that all three axes move differently
+    const PERTURB_Y_OFFSET: (f64, f64, f64) = (5.2, 1.3, 7.1);
+    const PERTURB_Z_OFFSET: (f64, f64, f64) = (1.7, 9.2, 3.4);
+
+    // Jitters the lookup point of another pattern with gradient noise. Perturbed stripes look like
+    // marble and perturbed rings look like wood grain.
+    #[derive(Debug)]
+    pub struct PerturbedPattern {
+        data: PatternData,
+        pattern: Box<dyn Pattern>,
+        noise: Box<dyn Noise>,
+        // How far the lookup point can be moved along each axis
+        pub scale: f64,
+        // Octaves of noise summed for each axis. More octaves add finer detail.
+        pub octaves: usize,
+    }
+
+    impl PerturbedPattern {
+        pub fn new_perturbed_pattern<P: Pattern + 'static, N: Noise + 'static>(pattern: P, noise: N,
+                                                                              scale: f64) -> PerturbedPattern {
+            PerturbedPattern {
+                data: PatternData::new_pattern_data(),
+                pattern: Box::new(pattern),
+                noise: Box::new(noise),
+                scale,
+                octaves: 1,
+            }
+        }
+
+        pub fn pattern(&self) -> &dyn Pattern {
+            self.pattern.as_ref()
+        }
+    }
+
+    impl Pattern for PerturbedPattern {
+        fn data(&self) -> &PatternData {
+            &self.data
+        }
+
+        fn data_mut(&mut self) -> &mut PatternData {
+            &mut self.data
+        }
+
+        fn local_pattern_at(&self, point: Vec4) -> Color {
+            let (yx, yy, yz) = PERTURB_Y_OFFSET;
+            let (zx, zy, zz) = PERTURB_Z_OFFSET;
+
+            let offset = Vec4::new_vec(self.noise.fbm(point, self.octaves),
+                                       self.noise.fbm(point + Vec4::new_vec(yx, yy, yz), self.octaves),
+                                       self.noise.fbm(point + Vec4::new_vec(zx, zy, zz), self.octaves));
+
+            self.pattern.pattern_at(point + offset * self.scale)
+        }
+    }
+
+    #[cfg(test)]
+    mod pattern_tests {
+        use super::*;
+        use super::super::rays::Sphere;
+        use super::super::shapes::Group;
+        use super::super::noise::{PerlinNoise, SimplexNoise};
+        use super::super::rt_prelude::equal_approx;
+
+        // A pattern that returns the point it was given as a color
+        #[derive(Debug)]
+        struct TestPattern {
+            data: PatternData,
+        }
+
+        impl TestPattern {
+            fn new_test_pattern() -> TestPattern {
+                TestPattern { data: PatternData::new_pattern_data() }
+            }
+        }
+
+        impl Pattern for TestPattern {
+            fn data(&self) -> &PatternData {
+                &self.data
+            }
+
+            fn data_mut(&mut self) -> &mut PatternData {
+                &mut self.data
+            }
+
+            fn local_pattern_at(&self, point: Vec4) -> Color {
+                Color::new(point.x, point.y, point.z)
+            }
+        }
+
+        fn white() -> Color {
+            Color::new(1.0, 1.0, 1.0)
+        }
+
+        fn black() -> Color {
+            Color::new(0.0, 0.0, 0.0)
+        }
+
+        #[test]
+        fn default_pattern_transform() {
+            let p = TestPattern::new_test_pattern();
+
+            assert_eq!(*p.transform(), Mat4::id());
+        }
+
+        #[test]
+        fn assign_pattern_transform() {
+            let mut p = TestPattern::new_test_pattern();
+            p.set_transform(Mat4::new_translation(1.0, 2.0, 3.0));
+
+            assert_eq!(*p.transform(), Mat4::new_translation(1.0, 2.0, 3.0));
+        }
+
+        #[test]
+        fn pattern_with_object_transform() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            let p = TestPattern::new_test_pattern();
+
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(2.0, 3.0, 4.0)), Color::new(1.0, 1.5, 2.0));
+        }
+
+        #[test]
+        fn pattern_with_pattern_transform() {
+            let s = Sphere::new_sphere(0);
+            let mut p = TestPattern::new_test_pattern();
+            p.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(2.0, 3.0, 4.0)), Color::new(1.0, 1.5, 2.0));
+        }
+
+        #[test]
+        fn pattern_with_object_and_pattern_transform() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            let mut p = TestPattern::new_test_pattern();
+            p.set_transform(Mat4::new_translation(0.5, 1.0, 1.5));
+
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(2.5, 3.0, 3.5)), Color::new(0.75, 0.5, 0.25));
+        }
+
+        #[test]
+        fn pattern_on_object_in_group() {
+            let mut g = Group::new_group();
+            g.set_transform(Mat4::new_translation(10.0, 0.0, 0.0));
+            g.add_child(Sphere::new_sphere(0));
+            let p = TestPattern::new_test_pattern();
+
+            // The group's transform moves the pattern along with the sphere
+            let s = g.children()[0].as_ref();
+            assert_eq!(p.pattern_at_shape(s, Vec4::new_point(11.0, 0.0, 0.0)), Color::new(1.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn stripe_pattern() {
+            let p = StripePattern::new_stripe_pattern(white(), black());
+
+            assert_eq!(p.a, white());
+            assert_eq!(p.b, black());
+
+            // Constant in y and z
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 1.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 2.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 1.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 2.0)), white());
+
+            // Alternates in x
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.9, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(1.0, 0.0, 0.0)), black());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(-0.1, 0.0, 0.0)), black());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(-1.0, 0.0, 0.0)), black());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(-1.1, 0.0, 0.0)), white());
+        }
+
+        #[test]
+        fn stripes_with_transforms() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            let mut p = StripePattern::new_stripe_pattern(white(), black());
+
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(1.5, 0.0, 0.0)), white());
+
+            p.set_transform(Mat4::new_translation(0.5, 0.0, 0.0));
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(2.5, 0.0, 0.0)), white());
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(3.5, 0.0, 0.0)), black());
+        }
+
+        #[test]
+        fn gradient_pattern() {
+            let p = GradientPattern::new_gradient_pattern(white(), black());
+
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.25, 0.0, 0.0)), Color::new(0.75, 0.75, 0.75));
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.5, 0.0, 0.0)), Color::new(0.5, 0.5, 0.5));
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.75, 0.0, 0.0)), Color::new(0.25, 0.25, 0.25));
+        }
+
+        #[test]
+        fn ring_pattern() {
+            let p = RingPattern::new_ring_pattern(white(), black());
+
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(1.0, 0.0, 0.0)), black());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 1.0)), black());
+            // 0.708 is just slightly more than sqrt(2) / 2
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.708, 0.0, 0.708)), black());
+        }
+
+        #[test]
+        fn checkers_pattern() {
+            let p = CheckersPattern::new_checkers_pattern(white(), black());
+
+            // Repeats in x
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.99, 0.0, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(1.01, 0.0, 0.0)), black());
+
+            // Repeats in y
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.99, 0.0)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 1.01, 0.0)), black());
+
+            // Repeats in z
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 0.99)), white());
+            assert_eq!(p.local_pattern_at(Vec4::new_point(0.0, 0.0, 1.01)), black());
+        }
+
+        #[test]
+        fn spherical_mapping() {
+            let a = 2.0_f64.sqrt() / 2.0;
+
+            // (point, u, v)
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, -1.0), 0.0, 0.5),
+                (Vec4::new_point(1.0, 0.0, 0.0), 0.25, 0.5),
+                (Vec4::new_point(0.0, 0.0, 1.0), 0.5, 0.5),
+                (Vec4::new_point(-1.0, 0.0, 0.0), 0.75, 0.5),
+                (Vec4::new_point(0.0, 1.0, 0.0), 0.5, 1.0),
+                (Vec4::new_point(0.0, -1.0, 0.0), 0.5, 0.0),
+                (Vec4::new_point(a, a, 0.0), 0.25, 0.75),
+            ];
+
+            for (point, u, v) in cases.iter() {
+                let (mu, mv) = spherical_map(*point);
+                assert!(equal_approx(mu, *u) && equal_approx(mv, *v));
+            }
+        }
+
+        #[test]
+        fn planar_mapping() {
+            // (point, u, v)
+            let cases = [
+                (Vec4::new_point(0.25, 0.0, 0.5), 0.25, 0.5),
+                (Vec4::new_point(0.25, 0.0, -0.25), 0.25, 0.75),
+                (Vec4::new_point(0.25, 0.5, -0.25), 0.25, 0.75),
+                (Vec4::new_point(1.25, 0.0, 0.5), 0.25, 0.5),
+                (Vec4::new_point(0.25, 0.0, -1.75), 0.25, 0.25),
+                (Vec4::new_point(1.0, 0.0, -1.0), 0.0, 0.0),
+                (Vec4::new_point(0.0, 0.0, 0.0), 0.0, 0.0),
+            ];
+
+            for (point, u, v) in cases.iter() {
+                assert_eq!(planar_map(*point), (*u, *v));
+            }
+        }
+
+        #[test]
+        fn cylindrical_mapping() {
+            let a = 2.0_f64.sqrt() / 2.0;
+
+            // (point, u, v)
+            let cases = [
+                (Vec4::new_point(0.0, 0.0, -1.0), 0.0, 0.0),
+                (Vec4::new_point(0.0, 0.5, -1.0), 0.0, 0.5),
+                (Vec4::new_point(0.0, 1.0, -1.0), 0.0, 0.0),
+                (Vec4::new_point(a, 0.5, -a), 0.125, 0.5),
+                (Vec4::new_point(1.0, 0.5, 0.0), 0.25, 0.5),
+                (Vec4::new_point(a, 0.5, a), 0.375, 0.5),
+                (Vec4::new_point(0.0, -0.25, 1.0), 0.5, 0.75),
+                (Vec4::new_point(-a, 0.5, a), 0.625, 0.5),
+                (Vec4::new_point(-1.0, 1.25, 0.0), 0.75, 0.25),
+                (Vec4::new_point(-a, 0.5, -a), 0.875, 0.5),
+            ];
+
+            for (point, u, v) in cases.iter() {
+                let (mu, mv) = cylindrical_map(*point);
+                assert!(equal_approx(mu, *u) && equal_approx(mv, *v));
+            }
+        }
+
+        #[test]
+        fn cube_faces() {
+            // (point, face)
+            let cases = [
+                (Vec4::new_point(-1.0, 0.5, -0.25), CubeFace::Left),
+                (Vec4::new_point(1.1, -0.75, 0.8), CubeFace::Right),
+                (Vec4::new_point(0.1, 0.6, 0.9), CubeFace::Front),
+                (Vec4::new_point(-0.7, 0.0, -2.0), CubeFace::Back),
+                (Vec4::new_point(0.5, 1.0, 0.9), CubeFace::Up),
+                (Vec4::new_point(-0.2, -1.3, 1.1), CubeFace::Down),
+            ];
+
+            for (point, face) in cases.iter() {
+                assert_eq!(cube_face(*point), *face);
+            }
+        }
+
+        #[test]
+        fn cube_face_mapping() {
+            // (face, point, u, v). Each face has a point in its upper left and lower right quarter.
+            let cases = [
+                (CubeFace::Front, Vec4::new_point(-0.5, 0.5, 1.0), 0.25, 0.75),
+                (CubeFace::Front, Vec4::new_point(0.5, -0.5, 1.0), 0.75, 0.25),
+                (CubeFace::Back, Vec4::new_point(0.5, 0.5, -1.0), 0.25, 0.75),
+                (CubeFace::Back, Vec4::new_point(-0.5, -0.5, -1.0), 0.75, 0.25),
+                (CubeFace::Left, Vec4::new_point(-1.0, 0.5, -0.5), 0.25, 0.75),
+                (CubeFace::Left, Vec4::new_point(-1.0, -0.5, 0.5), 0.75, 0.25),
+                (CubeFace::Right, Vec4::new_point(1.0, 0.5, 0.5), 0.25, 0.75),
+                (CubeFace::Right, Vec4::new_point(1.0, -0.5, -0.5), 0.75, 0.25),
+                (CubeFace::Up, Vec4::new_point(-0.5, 1.0, -0.5), 0.25, 0.75),
+                (CubeFace::Up, Vec4::new_point(0.5, 1.0, 0.5), 0.75, 0.25),
+                (CubeFace::Down, Vec4::new_point(-0.5, -1.0, 0.5), 0.25, 0.75),
+                (CubeFace::Down, Vec4::new_point(0.5, -1.0, -0.5), 0.75, 0.25),
+            ];
+
+            for (face, point, u, v) in cases.iter() {
+                assert_eq!(cube_face_uv(*face, *point), (*u, *v));
+            }
+        }
+
+        #[test]
+        fn cube_cross_mapping() {
+            // The center of each face lands in the center of its square in the cross
+            assert_eq!(cube_map(Vec4::new_point(0.0, 0.0, 1.0)), (1.5 / 4.0, 1.5 / 3.0));
+            assert_eq!(cube_map(Vec4::new_point(0.0, 1.0, 0.0)), (1.5 / 4.0, 2.5 / 3.0));
+            assert_eq!(cube_map(Vec4::new_point(0.0, -1.0, 0.0)), (1.5 / 4.0, 0.5 / 3.0));
+            assert_eq!(cube_map(Vec4::new_point(-1.0, 0.0, 0.0)), (0.5 / 4.0, 1.5 / 3.0));
+            assert_eq!(cube_map(Vec4::new_point(1.0, 0.0, 0.0)), (2.5 / 4.0, 1.5 / 3.0));
+            assert_eq!(cube_map(Vec4::new_point(0.0, 0.0, -1.0)), (3.5 / 4.0, 1.5 / 3.0));
+        }
+
+        // 2 by 2 pixels, red and green on top, blue and white on the bottom
+        fn test_canvas() -> Canvas {
+            let mut c = Canvas::new(2, 2, black());
+            c.write_pixel(0, 0, &Color::new(1.0, 0.0, 0.0));
+            c.write_pixel(1, 0, &Color::new(0.0, 1.0, 0.0));
+            c.write_pixel(0, 1, &Color::new(0.0, 0.0, 1.0));
+            c.write_pixel(1, 1, &white());
+
+            c
+        }
+
+        #[test]
+        fn nearest_texture_lookup() {
+            let mut p = TexturePattern::new_texture_pattern(test_canvas(), UvMapping::Planar);
+            p.filter = TextureFilter::Nearest;
+
+            assert_eq!(p.uv_pattern_at(0.0, 1.0), Color::new(1.0, 0.0, 0.0));
+            assert_eq!(p.uv_pattern_at(0.9, 0.9), Color::new(0.0, 1.0, 0.0));
+            assert_eq!(p.uv_pattern_at(0.1, 0.2), Color::new(0.0, 0.0, 1.0));
+            assert_eq!(p.uv_pattern_at(1.0, 0.0), white());
+            // Coordinates outside the texture use its edges
+            assert_eq!(p.uv_pattern_at(-0.5, 1.5), Color::new(1.0, 0.0, 0.0));
+        }
+
+        #[test]
+        fn bilinear_texture_lookup() {
+            let p = TexturePattern::new_texture_pattern(test_canvas(), UvMapping::Planar);
+
+            assert_eq!(p.filter, TextureFilter::Bilinear);
+            assert_eq!(p.uv_pattern_at(0.0, 1.0), Color::new(1.0, 0.0, 0.0));
+            assert_eq!(p.uv_pattern_at(0.5, 1.0), Color::new(0.5, 0.5, 0.0));
+            assert_eq!(p.uv_pattern_at(0.0, 0.5), Color::new(0.5, 0.0, 0.5));
+            assert_eq!(p.uv_pattern_at(0.5, 0.5), Color::new(0.5, 0.5, 0.5));
+            assert_eq!(p.uv_pattern_at(1.0, 0.25), Color::new(0.75, 1.0, 0.75));
+        }
+
+        #[test]
+        fn empty_texture_is_black() {
+            for (width, height) in [(0, 4), (4, 0), (0, 0)].iter() {
+                for filter in [TextureFilter::Nearest, TextureFilter::Bilinear].iter() {
+                    let mut p = TexturePattern::new_texture_pattern(Canvas::new(*width, *height, white()), UvMapping::Planar);
+                    p.filter = *filter;
+
+                    assert_eq!(p.uv_pattern_at(0.5, 0.5), Color::new(0.0, 0.0, 0.0));
+                }
+            }
+        }
+
+        #[test]
+        fn texture_on_sphere() {
+            let mut s = Sphere::new_sphere(0);
+            s.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            let mut p = TexturePattern::new_texture_pattern(test_canvas(), UvMapping::Spherical);
+            p.filter = TextureFilter::Nearest;
+
+            // The top of the sphere is the top of the texture and the bottom is the bottom
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(0.0, 2.0, 0.0)), Color::new(0.0, 1.0, 0.0));
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(0.0, -2.0, 0.0)), white());
+            assert_eq!(p.pattern_at_shape(&s, Vec4::new_point(0.5, -1.9, -0.1)), Color::new(0.0, 0.0, 1.0));
+        }
+
+        #[test]
+        fn nested_pattern_transform() {
+            let mut inner = TestPattern::new_test_pattern();
+            inner.set_transform(Mat4::new_scaling(2.0, 2.0, 2.0));
+            let p = PerturbedPattern::new_perturbed_pattern(inner, PerlinNoise::new_perlin_noise(0), 0.0);
+
+            // The wrapped pattern's own transform still applies
+            assert_eq!(p.pattern_at(Vec4::new_point(2.0, 4.0, 6.0)), Color::new(1.0, 2.0, 3.0));
+        }
+
+        #[test]
+        fn unperturbed_pattern() {
+            let p = PerturbedPattern::new_perturbed_pattern(StripePattern::new_stripe_pattern(white(), black()),
+                                                            PerlinNoise::new_perlin_noise(7), 0.0);
+
+            for i in 0..20 {
+                let point = Vec4::new_point(i as f64 * 0.37 - 3.0, 0.5, 0.25);
+                assert_eq!(p.local_pattern_at(point), p.pattern().local_pattern_at(point));
+            }
+        }
+
+        #[test]
+        fn perturbed_pattern_moves_lookup() {
+            let mut p = PerturbedPattern::new_perturbed_pattern(TestPattern::new_test_pattern(),
+                                                                SimplexNoise::new_simplex_noise(3), 0.5);
+            p.octaves = 3;
+
+            let mut moved = 0;
+
+            for i in 0..20 {
+                let point = Vec4::new_point(i as f64 * 0.37 - 3.0, 0.5, 0.25);
+                let color = p.local_pattern_at(point);
+
+                // Never further than the scale along any axis
+                assert!((color.r - point.x).abs() <= 0.5);
+                assert!((color.g - point.y).abs() <= 0.5);
+                assert!((color.b - point.z).abs() <= 0.5);
+
+                if color != Color::new(point.x, point.y, point.z) {
+                    moved += 1;
+                }
+            }
+
+            assert!(moved > 10);
+        }
+
+        #[test]
+        fn perturbed_pattern_is_reproducible() {
+            let make = |seed| {
+                PerturbedPattern::new_perturbed_pattern(RingPattern::new_ring_pattern(white(), black()),
+                                                        PerlinNoise::new_perlin_noise(seed), 0.3)
+            };
+            let (a, b, c) = (make(11), make(11), make(12));
+
+            let mut differences = 0;
+
+            for i in 0..200 {
+                let point = Vec4::new_point(i as f64 * 0.173, 0.0, i as f64 * 0.091);
+
+                assert_eq!(a.local_pattern_at(point), b.local_pattern_at(point));
+
+                if a.local_pattern_at(point) != c.local_pattern_at(point) {
+                    differences += 1;
+                }
+            }
+
+            assert!(differences > 0);
+        }
+    }
+}
+
+pub mod noise {
+    use std::fmt::Debug;
+    use super::rt_prelude::Vec4;
+
+    // Each octave of fractal noise has twice the frequency and half the amplitude of the one before
+    const LACUNARITY: f64 = 2.0;
+    const GAIN: f64 = 0.5;
+
+    // Deterministic gradient noise in three dimensions
+    pub trait Noise: Debug {
+        // Smoothly varying noise between -1 and 1 (Perlin noise can overshoot very slightly)
+        fn noise(&self, point: Vec4) -> f64;
+
+        // Fractal Brownian motion. Sums octaves of noise, keeping the result between -1 and 1.
+        fn fbm(&self, point: Vec4, octaves: usize) -> f64 {
+            let mut total = 0.0;
+            let mut frequency = 1.0;
+            let mut amplitude = 1.0;
+            let mut max_amplitude = 0.0;
+
+            for _ in 0..octaves {
+                total += self.noise(point * frequency) * amplitude;
+                max_amplitude += amplitude;
+                frequency *= LACUNARITY;
+                amplitude *= GAIN;
+            }
+
+            if max_amplitude > 0.0 { total / max_amplitude } else { 0.0 }
+        }
+
+        // Like fbm, but sums the absolute value of each octave, giving creases where the noise
+        // crosses 0. Between 0 and 1.
+        fn turbulence(&self, point: Vec4, octaves: usize) -> f64 {
+            let mut total = 0.0;
+            let mut frequency = 1.0;
+            let mut amplitude = 1.0;
+            let mut max_amplitude = 0.0;
+
+            for _ in 0..octaves {
+                total += self.noise(point * frequency).abs() * amplitude;
+                max_amplitude += amplitude;
+                frequency *= LACUNARITY;
+                amplitude *= GAIN;
+            }
+
+            if max_amplitude > 0.0 { total / max_amplitude } else { 0.0 }
+        }
+    }
+
+    // SplitMix64. Only used to shuffle the permutation tables, so it just needs to be reproducible.
+    fn next_random(state: &mut u64) -> u64 {
+        *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        z ^ (z >> 31)
+    }
+
+    // A shuffled list of 0 to 255, repeated twice so lookups can add offsets without wrapping
+    fn permutation_table(seed: u64) -> Vec<usize> {
+        let mut table: Vec<usize> = (0..256).collect();
+        let mut state = seed;
+
+        // Fisher-Yates shuffle
+        for i in (1..256).rev() {
+            let j = (next_random(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        table.extend_from_within(..);
+
+        table
+    }
+
+    // The lattice cell a coordinate is in, wrapped to the size of the permutation table
+    fn lattice_index(value: f64) -> usize {
+        (value as i64 & 255) as usize
+    }
+
+    fn lerp(t: f64, a: f64, b: f64) -> f64 {
+        a + t * (b - a)
+    }
+
+    // Ken Perlin's improved noise
+    #[derive(Clone, Debug)]
+    pub struct PerlinNoise {
+        permutation: Vec<usize>,
+    }
+
+    impl PerlinNoise {
+        pub fn new_perlin_noise(seed: u64) -> PerlinNoise {
+            PerlinNoise { permutation: permutation_table(seed) }
+        }
+    }
+
+    // Eases coordinates towards whole numbers so the noise has no visible creases at cell edges
+    fn fade(t: f64) -> f64 {
+        t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+    }
+
+    // Dot product of the offset with one of 12 gradient directions picked by the hash
+    fn grad(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 {
+            y
+        } else if h == 12 || h == 14 {
+            x
+        } else {
+            z
+        };
+
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    }
+
+    impl Noise for PerlinNoise {
+        fn noise(&self, point: Vec4) -> f64 {
+            let p = &self.permutation;
+
+            let (xf, yf, zf) = (point.x.floor(), point.y.floor(), point.z.floor());
+            let (xi, yi, zi) = (lattice_index(xf), lattice_index(yf), lattice_index(zf));
+
+            // Position within the cell
+            let (x, y, z) = (point.x - xf, point.y - yf, point.z - zf);
+            let (u, v, w) = (fade(x), fade(y), fade(z));
+
+            // Hashes of the eight corners of the cell
+            let a = p[xi] + yi;
+            let aa = p[a] + zi;
+            let ab = p[a + 1] + zi;
+            let b = p[xi + 1] + yi;
+            let ba = p[b] + zi;
+            let bb = p[b + 1] + zi;
+
+            lerp(w, lerp(v, lerp(u, grad(p[aa], x, y, z),
+                                    grad(p[ba], x - 1.0, y, z)),
+                            lerp(u, grad(p[ab], x, y - 1.0, z),
+                                    grad(p[bb], x - 1.0, y - 1.0, z))),
+                    lerp(v, lerp(u, grad(p[aa + 1], x, y, z - 1.0),
+                                    grad(p[ba + 1], x - 1.0, y, z - 1.0)),
+                            lerp(u, grad(p[ab + 1], x, y - 1.0, z - 1.0),
+                                    grad(p[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+        }
+    }
+
+    // Skewing factors between the simplex grid and regular space in three dimensions
+    const SIMPLEX_SKEW: f64 = 1.0 / 3.0;
+    const SIMPLEX_UNSKEW: f64 = 1.0 / 6.0;
+
+    // Directions to the edge midpoints of a cube
+    const SIMPLEX_GRADIENTS: [[f64; 3]; 12] = [
+        [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+        [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+        [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0],
+    ];
+
+    // Simplex noise. Cheaper than Perlin noise in three dimensions and without its axis-aligned artifacts.
+    #[derive(Clone, Debug)]
+    pub struct SimplexNoise {
+        permutation: Vec<usize>,
+    }
+
+    impl SimplexNoise {
+        pub fn new_simplex_noise(seed: u64) -> SimplexNoise {
+            SimplexNoise { permutation: permutation_table(seed) }
+        }
+
+        // Contribution of one corner of the simplex, which falls off to 0 at a distance of about 0.77
+        fn corner(&self, hash: usize, x: f64, y: f64, z: f64) -> f64 {
+            let t = 0.6 - x * x - y * y - z * z;
+
+            if t < 0.0 {
+                return 0.0;
+            }
+
+            let g = SIMPLEX_GRADIENTS[hash % 12];
+
+            t.powi(4) * (g[0] * x + g[1] * y + g[2] * z)
+        }
+    }
+
+    impl Noise for SimplexNoise {
+        fn noise(&self, point: Vec4) -> f64 {
+            let p = &self.permutation;
+
+            // Find which simplex cell the point is in
+            let skew = (point.x + point.y + point.z) * SIMPLEX_SKEW;
+            let i = (point.x + skew).floor();
+            let j = (point.y + skew).floor();
+            let k = (point.z + skew).floor();
+
+            // Offsets from the cell's origin corner
+            let unskew = (i + j + k) * SIMPLEX_UNSKEW;
+            let x0 = point.x - (i - unskew);
+            let y0 = point.y - (j - unskew);
+            let z0 = point.z - (k - unskew);
+
+            // The cell is split into six tetrahedra. Which one the point is in decides the
+            // second and third corners.
+            let (i1, j1, k1, i2, j2, k2) = if x0 >= y0 {
+                if y0 >= z0 {
+                    (1, 0, 0, 1, 1, 0)
+                } else if x0 >= z0 {
+                    (1, 0, 0, 1, 0, 1)
+                } else {
+                    (0, 0, 1, 1, 0, 1)
+                }
+            } else if y0 < z0 {
+                (0, 0, 1, 0, 1, 1)
+            } else if x0 < z0 {
+                (0, 1, 0, 0, 1, 1)
+            } else {
+                (0, 1, 0, 1, 1, 0)
+            };
+
+            let x1 = x0 - i1 as f64 + SIMPLEX_UNSKEW;
+            let y1 = y0 - j1 as f64 + SIMPLEX_UNSKEW;
+            let z1 = z0 - k1 as f64 + SIMPLEX_UNSKEW;
+            let x2 = x0 - i2 as f64 + 2.0 * SIMPLEX_UNSKEW;
+            let y2 = y0 - j2 as f64 + 2.0 * SIMPLEX_UNSKEW;
+            let z2 = z0 - k2 as f64 + 2.0 * SIMPLEX_UNSKEW;
+            let x3 = x0 - 1.0 + 3.0 * SIMPLEX_UNSKEW;
+            let y3 = y0 - 1.0 + 3.0 * SIMPLEX_UNSKEW;
+            let z3 = z0 - 1.0 + 3.0 * SIMPLEX_UNSKEW;
+
+            let (ii, jj, kk) = (lattice_index(i), lattice_index(j), lattice_index(k));
+
+            let n0 = self.corner(p[ii + p[jj + p[kk]]], x0, y0, z0);
+            let n1 = self.corner(p[ii + i1 + p[jj + j1 + p[kk + k1]]], x1, y1, z1);
+            let n2 = self.corner(p[ii + i2 + p[jj + j2 + p[kk + k2]]], x2, y2, z2);
+            let n3 = self.corner(p[ii + 1 + p[jj + 1 + p[kk + 1]]], x3, y3, z3);
+
+            // Scales the result to cover -1 to 1
+            32.0 * (n0 + n1 + n2 + n3)
+        }
+    }
+
+    #[cfg(test)]
+    mod noise_tests {
+        use super::*;
+
+        fn sample_points() -> Vec<Vec4> {
+            (0..500).map(|i| {
+                let i = i as f64;
+                Vec4::new_point(i * 0.137 - 30.0, i * 0.291 - 70.0, i * 0.053 + 5.0)
+            }).collect()
+        }
+
+        fn noise_sources(seed: u64) -> [Box<dyn Noise>; 2] {
+            [Box::new(PerlinNoise::new_perlin_noise(seed)), Box::new(SimplexNoise::new_simplex_noise(seed))]
+        }
+
+        #[test]
+        fn permutation_table_is_shuffled() {
+            let table = permutation_table(42);
+
+            assert_eq!(table.len(), 512);
+            assert_eq!(table[..256], table[256..]);
+
+            let mut sorted = table[..256].to_vec();
+            sorted.sort();
+            assert_eq!(sorted, (0..256).collect::<Vec<usize>>());
+            assert_ne!(table[..256], sorted[..]);
+        }
+
+        #[test]
+        fn same_seed_gives_same_noise() {
+            for (a, b) in noise_sources(5).iter().zip(noise_sources(5).iter()) {
+                for point in sample_points() {
+                    assert_eq!(a.noise(point), b.noise(point));
+                }
+            }
+        }
+
+        #[test]
+        fn different_seeds_give_different_noise() {
+            for (a, b) in noise_sources(5).iter().zip(noise_sources(6).iter()) {
+                assert!(sample_points().iter().any(|point| a.noise(*point) != b.noise(*point)));
+            }
+        }
+
+        #[test]
+        fn perlin_noise_is_zero_on_lattice() {
+            let n = PerlinNoise::new_perlin_noise(1);
+
+            assert_eq!(n.noise(Vec4::new_point(0.0, 0.0, 0.0)), 0.0);
+            assert_eq!(n.noise(Vec4::new_point(3.0, -7.0, 12.0)), 0.0);
+            assert_eq!(n.noise(Vec4::new_point(-300.0, 41.0, 2.0)), 0.0);
+        }
+
+        #[test]
+        fn noise_stays_in_range() {
+            for n in noise_sources(9).iter() {
+                let values: Vec<f64> = sample_points().iter().map(|point| n.noise(*point)).collect();
+
+                assert!(values.iter().all(|v| v.abs() <= 1.05));
+
+                // The noise actually varies
+                assert!(values.iter().any(|v| *v > 0.2));
+                assert!(values.iter().any(|v| *v < -0.2));
+            }
+        }
+
+        #[test]
+        fn noise_is_continuous() {
+            for n in noise_sources(2).iter() {
+                for point in sample_points() {
+                    let nearby = point + Vec4::new_vec(0.001, -0.001, 0.001);
+                    assert!((n.noise(point) - n.noise(nearby)).abs() < 0.02);
+                }
+            }
+        }
+
+        #[test]
+        fn single_octave_fbm_is_noise() {
+            for n in noise_sources(4).iter() {
+                for point in sample_points() {
+                    assert_eq!(n.fbm(point, 1), n.noise(point));
+                    assert_eq!(n.turbulence(point, 1), n.noise(point).abs());
+                }
+            }
+        }
+
+        #[test]
+        fn fbm_and_turbulence_stay_in_range() {
+            for n in noise_sources(8).iter() {
+                for point in sample_points() {
+                    assert!(n.fbm(point, 5).abs() <= 1.05);
+
+                    let turbulence = n.turbulence(point, 5);
+                    assert!((0.0..=1.05).contains(&turbulence));
+                }
+
+                assert_eq!(n.fbm(Vec4::new_point(0.5, 0.5, 0.5), 0), 0.0);
+            }
+        }
+    }
+}
+
+pub mod ppm {
+    use std::error::Error;
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::io::{Write, BufWriter};
+    use std::path::Path;
+
+    use super::rt_prelude::{Color, Canvas, Rounding};
+    use super::quantization::Quantizer;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum PpmFormat {
+        // ASCII samples separated by whitespace
+        P3,
+        // Binary samples
+        P6,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum BitDepth {
+        Eight,
+        Sixteen,
+    }
+
+    impl BitDepth {
+        pub fn max_value(&self) -> u16 {
+            match self {
+                BitDepth::Eight => 255,
+                BitDepth::Sixteen => 65535,
+            }
+        }
+
+    }
+
+    // Lines in an ASCII PPM file shouldn't be longer than 70 characters
+    const MAX_PPM_LINE_LENGTH: usize = 70;
+
+    #[derive(Debug)]
+    pub enum PpmError {
+        Io(io::Error),
+        // The file does not start with P3 or P6
+        UnsupportedFormat(String),
+        // The named header field is missing, not a number or out of range
+        InvalidHeader(&'static str),
+        // A sample in an ASCII file is not a number or is above the maximum value. index counts samples from 0.
+        InvalidSample { index: usize },
+        // The file ends before every sample has been read
+        TruncatedData { expected: usize, found: usize },
+    }
+
+    impl fmt::Display for PpmError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PpmError::Io(e) => write!(f, "could not read PPM file: {}", e),
+                PpmError::UnsupportedFormat(magic) => write!(f, "unsupported PPM format {:?}, expected P3 or P6", magic),
+                PpmError::InvalidHeader(field) => write!(f, "invalid or missing {} in PPM header", field),
+                PpmError::InvalidSample { index } => write!(f, "invalid PPM sample at index {}", index),
+                PpmError::TruncatedData { expected, found } => {
+                    write!(f, "PPM data is truncated, expected {} samples but found {}", expected, found)
+                }
+            }
+        }
+    }
+
+    impl Error for PpmError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                PpmError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for PpmError {
+        fn from(e: io::Error) -> PpmError {
+            PpmError::Io(e)
+        }
+    }
+
+    // Splits the text parts of a PPM or PFM file into whitespace separated tokens, skipping comments
+    pub(crate) struct Tokens<'a> {
+        pub(crate) data: &'a [u8],
+        pub(crate) pos: usize,
+    }
+
+    impl<'a> Tokens<'a> {
+        fn skip_whitespace_and_comments(&mut self) {
+            while self.pos < self.data.len() {
+                let byte = self.data[self.pos];
+
+                if byte == b'#' {
+                    // Comments run to the end of the line
+                    while self.pos < self.data.len() && self.data[self.pos] != b'\n' && self.data[self.pos] != b'\r' {
+                        self.pos += 1;
+                    }
+                } else if byte.is_ascii_whitespace() {
+                    self.pos += 1;
+                } else {
+                    return;
+                }
+            }
+        }
+
+        pub(crate) fn next_token(&mut self) -> Option<&'a [u8]> {
+            self.skip_whitespace_and_comments();
+
+            let start = self.pos;
+            while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() && self.data[self.pos] != b'#' {
+                self.pos += 1;
+            }
+
+            if start == self.pos { None } else { Some(&self.data[start..self.pos]) }
+        }
+
+        pub(crate) fn next_number(&mut self) -> Option<u32> {
+            std::str::from_utf8(self.next_token()?).ok()?.parse().ok()
+        }
+    }
+
+    impl Canvas {
+        // Reads an ASCII (P3) or binary (P6) PPM image. Samples are scaled by the maximum value,
+        // so every channel ends up between 0 and 1.
+        pub fn from_ppm(data: &[u8]) -> Result<Canvas, PpmError> {
+            let mut tokens = Tokens { data, pos: 0 };
+
+            let magic = tokens.next_token().unwrap_or(&[]);
+            let binary = match magic {
+                b"P3" => false,
+                b"P6" => true,
+                _ => return Err(PpmError::UnsupportedFormat(String::from_utf8_lossy(magic).into_owned())),
+            };
+
+            let width = tokens.next_number().filter(|w| *w > 0).ok_or(PpmError::InvalidHeader("width"))? as usize;
+            let height = tokens.next_number().filter(|h| *h > 0).ok_or(PpmError::InvalidHeader("height"))? as usize;
+            let max_value = tokens.next_number()
+                .filter(|m| (1..=65535).contains(m))
+                .ok_or(PpmError::InvalidHeader("maximum value"))? as f64;
+
+            let sample_count = width.checked_mul(height)
+                .and_then(|pixels| pixels.checked_mul(3))
+                .ok_or(PpmError::InvalidHeader("image size"))?;
+            let mut samples: Vec<f64> = Vec::new();
+
+            if binary {
+                // A single whitespace character separates the header from the binary data
+                let start = tokens.pos + 1;
+                let bytes_per_sample = if max_value < 256.0 { 1 } else { 2 };
+                let body = data.get(start..).unwrap_or(&[]);
+
+                // The header can claim more samples than the file holds
+                samples.reserve(sample_count.min(body.len() / bytes_per_sample));
+
+                for sample in body.chunks_exact(bytes_per_sample).take(sample_count) {
+                    // 16 bit samples are stored most significant byte first
+                    let value = sample.iter().fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+
+                    if value as f64 > max_value {
+                        return Err(PpmError::InvalidSample { index: samples.len() });
+                    }
+
+                    samples.push(value as f64);
+                }
+            } else {
+                while samples.len() < sample_count {
+                    let index = samples.len();
+
+                    match tokens.next_token() {
+                        Some(token) => {
+                            let value: f64 = std::str::from_utf8(token).ok()
+                                .and_then(|t| t.parse::<u32>().ok())
+                                .filter(|v| *v as f64 <= max_value)
+                                .ok_or(PpmError::InvalidSample { index })? as f64;
+
+                            samples.push(value);
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            if samples.len() < sample_count {
+                return Err(PpmError::TruncatedData { expected: sample_count, found: samples.len() });
+            }
+
+            let mut canvas = Canvas::new(width, height, Color::new(0.0, 0.0, 0.0));
+
+            for (i, rgb) in samples.chunks_exact(3).enumerate() {
+                let color = Color::new(rgb[0] / max_value, rgb[1] / max_value, rgb[2] / max_value);
+                canvas.write_pixel(i % width, i / width, &color);
+            }
+
+            Ok(canvas)
+        }
+
+        pub fn from_ppm_file<P: AsRef<Path>>(path: P) -> Result<Canvas, PpmError> {
+            Canvas::from_ppm(&fs::read(path)?)
+        }
+
+        // Writes the canvas as a PPM image one row at a time, so the whole file is never held in memory
+        // Truncates every sample. Use write_ppm_with to round or dither.
+        pub fn write_ppm<W: Write>(&self, writer: W, format: PpmFormat, depth: BitDepth) -> io::Result<()> {
+            self.write_ppm_with(writer, format, depth, &Quantizer::new_quantizer(Rounding::Truncate))
+        }
+
+        pub fn write_ppm_with<W: Write>(&self, mut writer: W, format: PpmFormat, depth: BitDepth, quantizer: &Quantizer) -> io::Result<()> {
+            let magic = match format {
+                PpmFormat::P3 => "P3",
+                PpmFormat::P6 => "P6",
+            };
+
+            write!(writer, "{}\n{} {}\n{}\n", magic, self.width, self.height, depth.max_value())?;
+
+            let mut row: Vec<u8> = Vec::new();
+
+            for y in 0..self.height {
+                row.clear();
+                let mut line_length = 0;
+
+                for x in 0..self.width {
+                    for sample in quantizer.quantize(&self.read_pixel(x, y), depth.max_value(), x, y).iter() {
+                        match format {
+                            PpmFormat::P6 => match depth {
+                                BitDepth::Eight => row.push(*sample as u8),
+                                // Most significant byte first
+                                BitDepth::Sixteen => row.extend_from_slice(&sample.to_be_bytes()),
+                            },
+                            PpmFormat::P3 => {
+                                let text = sample.to_string();
+
+                                // Samples are never split across lines
+                                if line_length > 0 && line_length + 1 + text.len() > MAX_PPM_LINE_LENGTH {
+                                    row.push(b'\n');
+                                    line_length = 0;
+                                } else if line_length > 0 {
+                                    row.push(b' ');
+                                    line_length += 1;
+                                }
+
+                                row.extend_from_slice(text.as_bytes());
+                                line_length += text.len();
+                            }
+                        }
+                    }
+                }
+
+                // Every row of an ASCII image starts on a new line
+                if format == PpmFormat::P3 {
+                    row.push(b'\n');
+                }
+
+                writer.write_all(&row)?;
+            }
+
+            writer.flush()
+        }
+
+        pub fn write_ppm_file<P: AsRef<Path>>(&self, path: P, format: PpmFormat, depth: BitDepth) -> io::Result<()> {
+            self.write_ppm(BufWriter::new(fs::File::create(path)?), format, depth)
+        }
+
+        pub fn write_ppm_file_with<P: AsRef<Path>>(&self, path: P, format: PpmFormat, depth: BitDepth, quantizer: &Quantizer) -> io::Result<()> {
+            self.write_ppm_with(BufWriter::new(fs::File::create(path)?), format, depth, quantizer)
+        }
+
+        // ASCII PPM with 8 bits per channel. Use write_ppm to stream larger images or other formats.
+        pub fn to_ppm(&self) -> String {
+            let mut buffer: Vec<u8> = Vec::new();
+            self.write_ppm(&mut buffer, PpmFormat::P3, BitDepth::Eight).expect("writing to a Vec cannot fail");
+
+            String::from_utf8(buffer).expect("P3 PPM files are ASCII")
+        }
+    }
+
+    #[cfg(test)]
+    mod ppm_tests {
+        use super::*;
+
+        #[test]
+        fn reject_unsupported_format() {
+            let result = Canvas::from_ppm(b"P32\n1 1\n255\n0 0 0\n");
+
+            assert!(matches!(result, Err(PpmError::UnsupportedFormat(ref magic)) if magic == "P32"));
+            assert!(matches!(Canvas::from_ppm(b""), Err(PpmError::UnsupportedFormat(_))));
+        }
+
+        #[test]
+        fn reject_huge_sizes() {
+            // width * height * 3 does not fit in a usize
+            assert!(matches!(Canvas::from_ppm(b"P6 4000000000 4000000000 255\n\0\0\0"),
+                             Err(PpmError::InvalidHeader("image size"))));
+
+            // Fits, but the data is nowhere near that big
+            assert!(matches!(Canvas::from_ppm(b"P6 60000 60000 255\n\0\0\0"),
+                             Err(PpmError::TruncatedData { expected: 10_800_000_000, found: 3 })));
+            assert!(matches!(Canvas::from_ppm(b"P3 60000 60000 255\n0 0 0\n"),
+                             Err(PpmError::TruncatedData { expected: 10_800_000_000, found: 3 })));
+        }
+
+        #[test]
+        fn read_ascii_ppm_size() {
+            let c = Canvas::from_ppm(b"P3\n10 2\n255\n").err();
+
+            // Only the header is there, so the size is known but the pixels are missing
+            assert!(matches!(c, Some(PpmError::TruncatedData { expected: 60, found: 0 })));
+
+            let c = Canvas::from_ppm(b"P3\n2 1\n255\n0 0 0 0 0 0\n").unwrap();
+            assert_eq!(c.width, 2);
+            assert_eq!(c.height, 1);
+        }
+
+        #[test]
+        fn read_ascii_ppm_pixels() {
+            let ppm = b"P3\n4 3\n255\n\
+                        255 127 0  0 127 255  127 255 0  255 255 255\n\
+                        0 0 0  255 0 0  0 255 0  0 0 255\n\
+                        255 255 0  0 255 255  255 0 255  127 127 127\n";
+            let c = Canvas::from_ppm(ppm).unwrap();
+
+            // (x, y, color)
+            let cases = [
+                (0, 0, Color::new(1.0, 127.0 / 255.0, 0.0)),
+                (1, 0, Color::new(0.0, 127.0 / 255.0, 1.0)),
+                (2, 0, Color::new(127.0 / 255.0, 1.0, 0.0)),
+                (3, 0, Color::new(1.0, 1.0, 1.0)),
+                (0, 1, Color::new(0.0, 0.0, 0.0)),
+                (1, 1, Color::new(1.0, 0.0, 0.0)),
+                (2, 1, Color::new(0.0, 1.0, 0.0)),
+                (3, 1, Color::new(0.0, 0.0, 1.0)),
+                (0, 2, Color::new(1.0, 1.0, 0.0)),
+                (1, 2, Color::new(0.0, 1.0, 1.0)),
+                (2, 2, Color::new(1.0, 0.0, 1.0)),
+                (3, 2, Color::new(127.0 / 255.0, 127.0 / 255.0, 127.0 / 255.0)),
+            ];
+
+            for (x, y, color) in cases.iter() {
+                assert_eq!(c.read_pixel(*x, *y), *color);
+            }
+        }
+
+        #[test]
+        fn ignore_comments() {
+            let ppm = b"P3\n# this is a comment\n2 1\n# this, too\n255\n# another comment\n255 255 255\n# oh, no, comments in the pixel data!\n255 0 255\n";
+            let c = Canvas::from_ppm(ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(1.0, 1.0, 1.0));
+            assert_eq!(c.read_pixel(1, 0), Color::new(1.0, 0.0, 1.0));
+        }
+
+        #[test]
+        fn samples_may_span_lines() {
+            let ppm = b"P3 1 1\t255\n51\n153\n\n204\n";
+            let c = Canvas::from_ppm(ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(0.2, 0.6, 0.8));
+        }
+
+        #[test]
+        fn scale_by_maximum_value() {
+            let ppm = b"P3\n2 2\n100\n100 100 100  50 50 50\n75 50 25  0 0 0\n";
+            let c = Canvas::from_ppm(ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 1), Color::new(0.75, 0.5, 0.25));
+
+            let ppm = b"P3\n1 1\n65535\n65535 32768 0\n";
+            let c = Canvas::from_ppm(ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(1.0, 32768.0 / 65535.0, 0.0));
+        }
+
+        #[test]
+        fn read_binary_ppm() {
+            let mut ppm = b"P6\n# binary\n2 1\n255\n".to_vec();
+            ppm.extend_from_slice(&[255, 0, 51, 0, 255, 102]);
+            let c = Canvas::from_ppm(&ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(1.0, 0.0, 0.2));
+            assert_eq!(c.read_pixel(1, 0), Color::new(0.0, 1.0, 0.4));
+        }
+
+        #[test]
+        fn read_binary_ppm_with_whitespace_value() {
+            // The first sample is a newline byte, which must not be taken as part of the header
+            let mut ppm = b"P6 1 1 255\n".to_vec();
+            ppm.extend_from_slice(&[b'\n', b' ', 255]);
+            let c = Canvas::from_ppm(&ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(10.0 / 255.0, 32.0 / 255.0, 1.0));
+        }
+
+        #[test]
+        fn read_16_bit_binary_ppm() {
+            let mut ppm = b"P6\n1 1\n65535\n".to_vec();
+            ppm.extend_from_slice(&[0xff, 0xff, 0x80, 0x00, 0x00, 0x01]);
+            let c = Canvas::from_ppm(&ppm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(1.0, 32768.0 / 65535.0, 1.0 / 65535.0));
+        }
+
+        #[test]
+        fn reject_invalid_header() {
+            assert!(matches!(Canvas::from_ppm(b"P3\n"), Err(PpmError::InvalidHeader("width"))));
+            assert!(matches!(Canvas::from_ppm(b"P3\nten 2\n255\n"), Err(PpmError::InvalidHeader("width"))));
+            assert!(matches!(Canvas::from_ppm(b"P3\n0 2\n255\n"), Err(PpmError::InvalidHeader("width"))));
+            assert!(matches!(Canvas::from_ppm(b"P6\n2 -1\n255\n"), Err(PpmError::InvalidHeader("height"))));
+            assert!(matches!(Canvas::from_ppm(b"P3\n2 2\n"), Err(PpmError::InvalidHeader("maximum value"))));
+            assert!(matches!(Canvas::from_ppm(b"P3\n2 2\n0\n"), Err(PpmError::InvalidHeader("maximum value"))));
+            assert!(matches!(Canvas::from_ppm(b"P3\n2 2\n65536\n"), Err(PpmError::InvalidHeader("maximum value"))));
+        }
+
+        #[test]
+        fn reject_invalid_samples() {
+            assert!(matches!(Canvas::from_ppm(b"P3\n1 1\n255\n0 256 0\n"), Err(PpmError::InvalidSample { index: 1 })));
+            assert!(matches!(Canvas::from_ppm(b"P3\n1 1\n255\n0 0 x\n"), Err(PpmError::InvalidSample { index: 2 })));
+
+            let mut ppm = b"P6\n1 1\n100\n".to_vec();
+            ppm.extend_from_slice(&[0, 101, 0]);
+            assert!(matches!(Canvas::from_ppm(&ppm), Err(PpmError::InvalidSample { index: 1 })));
+        }
+
+        #[test]
+        fn reject_truncated_data() {
+            let result = Canvas::from_ppm(b"P3\n2 1\n255\n0 0 0 0\n");
+            assert!(matches!(result, Err(PpmError::TruncatedData { expected: 6, found: 4 })));
+
+            let mut ppm = b"P6\n2 1\n65535\n".to_vec();
+            ppm.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            assert!(matches!(Canvas::from_ppm(&ppm), Err(PpmError::TruncatedData { expected: 6, found: 5 })));
+        }
+
+        #[test]
+        fn read_what_to_ppm_writes() {
+            let mut c = Canvas::new(10, 10, Color::new(0.0, 0.0, 0.0));
+            c.write_pixel(3, 4, &Color::new(1.0, 0.6, 0.2));
+
+            let c2 = Canvas::from_ppm(c.to_ppm().as_bytes()).unwrap();
+
+            assert_eq!(c2.width, 10);
+            assert_eq!(c2.height, 10);
+            assert_eq!(c2.read_pixel(3, 4), Color::new(1.0, 153.0 / 255.0, 51.0 / 255.0));
+            assert_eq!(c2.read_pixel(0, 0), Color::new(0.0, 0.0, 0.0));
+        }
+
+        // Header followed by the raw pixel data
+        fn split_header(ppm: &[u8], header_length: usize) -> (&str, &[u8]) {
+            (std::str::from_utf8(&ppm[..header_length]).unwrap(), &ppm[header_length..])
+        }
+
+        #[test]
+        fn write_binary_ppm() {
+            let mut c = Canvas::new(2, 1, Color::new(0.0, 0.0, 0.0));
+            c.write_pixel(0, 0, &Color::new(1.0, 0.5, -0.5));
+            c.write_pixel(1, 0, &Color::new(0.2, 1.5, 0.0));
+
+            let mut ppm: Vec<u8> = Vec::new();
+            c.write_ppm(&mut ppm, PpmFormat::P6, BitDepth::Eight).unwrap();
+
+            let (header, pixels) = split_header(&ppm, 11);
+            assert_eq!(header, "P6\n2 1\n255\n");
+            assert_eq!(pixels, &[255, 127, 0, 51, 255, 0]);
+        }
+
+        #[test]
+        fn write_ppm_with_rounding() {
+            let c = Canvas::new(1, 1, Color::new(0.5, 0.999, 0.2));
+
+            let mut ppm: Vec<u8> = Vec::new();
+            c.write_ppm_with(&mut ppm, PpmFormat::P6, BitDepth::Eight, &Quantizer::new_quantizer(Rounding::Nearest)).unwrap();
+
+            let (_, pixels) = split_header(&ppm, 11);
+            assert_eq!(pixels, &[128, 255, 51]);
+        }
+
+        #[test]
+        fn write_16_bit_binary_ppm() {
+            let mut c = Canvas::new(1, 1, Color::new(0.0, 0.0, 0.0));
+            c.write_pixel(0, 0, &Color::new(1.0, 0.5, 0.0));
+
+            let mut ppm: Vec<u8> = Vec::new();
+            c.write_ppm(&mut ppm, PpmFormat::P6, BitDepth::Sixteen).unwrap();
+
+            let (header, pixels) = split_header(&ppm, 13);
+            assert_eq!(header, "P6\n1 1\n65535\n");
+            assert_eq!(pixels, &[0xff, 0xff, 0x7f, 0xff, 0x00, 0x00]);
+        }
+
+        #[test]
+        fn write_16_bit_ascii_ppm() {
+            let c = Canvas::new(3, 2, Color::new(1.0, 0.5, 0.0));
+
+            let mut ppm: Vec<u8> = Vec::new();
+            c.write_ppm(&mut ppm, PpmFormat::P3, BitDepth::Sixteen).unwrap();
+
+            // Each row is 9 samples of up to 5 digits, which needs two lines
+            assert_eq!(String::from_utf8(ppm).unwrap(),
+                       "P3\n3 2\n65535\n\
+                        65535 32767 0 65535 32767 0 65535 32767 0\n\
+                        65535 32767 0 65535 32767 0 65535 32767 0\n");
+
+            let c = Canvas::new(5, 1, Color::new(1.0, 1.0, 1.0));
+            let mut ppm: Vec<u8> = Vec::new();
+            c.write_ppm(&mut ppm, PpmFormat::P3, BitDepth::Sixteen).unwrap();
+
+            let text = String::from_utf8(ppm).unwrap();
+            assert!(text.lines().all(|line| line.len() <= 70));
+            assert_eq!(text.lines().skip(3).collect::<Vec<&str>>(),
+                       vec!["65535 65535 65535 65535 65535 65535 65535 65535 65535 65535 65535",
+                            "65535 65535 65535 65535"]);
+        }
+
+        #[test]
+        fn ppm_round_trip() {
+            let mut c = Canvas::new(4, 3, Color::new(0.0, 0.0, 0.0));
+            for x in 0..4 {
+                for y in 0..3 {
+                    c.write_pixel(x, y, &Color::new(x as f64 / 3.0, y as f64 / 2.0, 0.25));
+                }
+            }
+
+            for format in [PpmFormat::P3, PpmFormat::P6].iter() {
+                for depth in [BitDepth::Eight, BitDepth::Sixteen].iter() {
+                    let mut ppm: Vec<u8> = Vec::new();
+                    c.write_ppm(&mut ppm, *format, *depth).unwrap();
+                    let c2 = Canvas::from_ppm(&ppm).unwrap();
+
+                    // Writing truncates, so colors come back up to one step darker
+                    let step = 1.0 / depth.max_value() as f64;
+
+                    for x in 0..4 {
+                        for y in 0..3 {
+                            let (a, b) = (c.read_pixel(x, y), c2.read_pixel(x, y));
+                            assert!(a.r - b.r >= -1e-9 && a.r - b.r < step);
+                            assert!(a.g - b.g >= -1e-9 && a.g - b.g < step);
+                            assert!(a.b - b.b >= -1e-9 && a.b - b.b < step);
+                        }
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn read_missing_file() {
+            let result = Canvas::from_ppm_file("this/file/does/not/exist.ppm");
+
+            assert!(matches!(result, Err(PpmError::Io(_))));
+        }
+    }
+}
+
+pub mod png {
+    use std::fs;
+    use std::io;
+    use std::io::{Write, BufWriter};
+    use std::path::Path;
+
+    use super::rt_prelude::Canvas;
+    use super::rt_prelude::Rounding;
+    use super::ppm::BitDepth;
+    use super::quantization::Quantizer;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum PngColorType {
+        Rgb,
+        // Canvases have no transparency, so every pixel is written fully opaque
+        Rgba,
+    }
+
+    impl PngColorType {
+        fn channels(&self) -> usize {
+            match self {
+                PngColorType::Rgb => 3,
+                PngColorType::Rgba => 4,
+            }
+        }
+
+        // The color type field of the IHDR chunk
+        fn code(&self) -> u8 {
+            match self {
+                PngColorType::Rgb => 2,
+                PngColorType::Rgba => 6,
+            }
+        }
+    }
+
+    const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+    // Image dimensions and chunk lengths are stored as 31 bit numbers
+    const PNG_MAX_LENGTH: usize = 0x7fff_ffff;
+
+    const fn make_crc_table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut n = 0;
+
+        while n < 256 {
+            let mut c = n as u32;
+            let mut k = 0;
+
+            while k < 8 {
+                c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+                k += 1;
+            }
+
+            table[n] = c;
+            n += 1;
+        }
+
+        table
+    }
+
+    const CRC_TABLE: [u32; 256] = make_crc_table();
+
+    // The CRC-32 used by PNG chunks (and zip and gzip)
+    pub fn crc32(data: &[u8]) -> u32 {
+        !data.iter().fold(0xffff_ffff, |crc: u32, byte| CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+    }
+
+    // The checksum at the end of a zlib stream
+    pub fn adler32(data: &[u8]) -> u32 {
+        const MOD_ADLER: u32 = 65521;
+
+        let mut a: u32 = 1;
+        let mut b: u32 = 0;
+
+        // 5552 is the most bytes that can be summed before b could overflow
+        for chunk in data.chunks(5552) {
+            for byte in chunk.iter() {
+                a += *byte as u32;
+                b += a;
+            }
+
+            a %= MOD_ADLER;
+            b %= MOD_ADLER;
+        }
+
+        (b << 16) | a
+    }
+
+    // Packs bits into bytes starting from the least significant bit, as DEFLATE requires
+    struct BitWriter {
+        bytes: Vec<u8>,
+        buffer: u32,
+        count: u32,
+    }
+
+    impl BitWriter {
+        fn new_bit_writer() -> BitWriter {
+            BitWriter { bytes: Vec::new(), buffer: 0, count: 0 }
+        }
+
+        // Writes the lowest n bits of value
+        fn write_bits(&mut self, value: u32, n: u32) {
+            self.buffer |= value << self.count;
+            self.count += n;
+
+            while self.count >= 8 {
+                self.bytes.push(self.buffer as u8);
+                self.buffer >>= 8;
+                self.count -= 8;
+            }
+        }
+
+        // Huffman codes are packed starting from their most significant bit
+        fn write_code(&mut self, code: u32, length: u32) {
+            self.write_bits(code.reverse_bits() >> (32 - length), length);
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            if self.count > 0 {
+                self.bytes.push(self.buffer as u8);
+            }
+
+            self.bytes
+        }
+    }
+
+    // Stores the data without compressing it, in blocks of up to 65535 bytes
+    pub fn deflate_stored(data: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(data.len() + 5 * (data.len() / 65535 + 1));
+        let block_count = data.len().div_ceil(65535).max(1);
+
+        for i in 0..block_count {
+            let block = &data[i * 65535..((i + 1) * 65535).min(data.len())];
+            let length = block.len() as u16;
+
+            // The final block flag, with a block type of 0 and padding up to the next byte
+            out.push(if i == block_count - 1 { 1 } else { 0 });
+            out.extend_from_slice(&length.to_le_bytes());
+            out.extend_from_slice(&(!length).to_le_bytes());
+            out.extend_from_slice(block);
+        }
+
+        out
+    }
+
+    // Smallest match length for each length code from 257, and the extra bits that follow the code
+    const LENGTH_BASES: [usize; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+                                       35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+    const LENGTH_EXTRA_BITS: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+                                          3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+    // Smallest distance for each distance code, and the extra bits that follow the code
+    const DISTANCE_BASES: [usize; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                                         257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                                         8193, 12289, 16385, 24577];
+    const DISTANCE_EXTRA_BITS: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+                                            7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+    const WINDOW_SIZE: usize = 32768;
+    const MIN_MATCH: usize = 3;
+    const MAX_MATCH: usize = 258;
+    // How many earlier positions with the same hash are checked for a match
+    const MAX_CHAIN: usize = 64;
+    const HASH_SIZE: usize = 1 << 15;
+    const NO_POSITION: usize = usize::MAX;
+
+    // Writes a literal byte, a match length (257 to 285) or the end of block (256) with the fixed Huffman code
+    fn write_fixed_symbol(writer: &mut BitWriter, symbol: u32) {
+        match symbol {
+            0..=143 => writer.write_code(0x30 + symbol, 8),
+            144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+            256..=279 => writer.write_code(symbol - 256, 7),
+            _ => writer.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+        let length_index = LENGTH_BASES.iter().rposition(|base| *base <= length).expect("match is too short");
+        write_fixed_symbol(writer, 257 + length_index as u32);
+        writer.write_bits((length - LENGTH_BASES[length_index]) as u32, LENGTH_EXTRA_BITS[length_index]);
+
+        // Fixed distance codes are all 5 bits long
+        let distance_index = DISTANCE_BASES.iter().rposition(|base| *base <= distance).expect("distance is 0");
+        writer.write_code(distance_index as u32, 5);
+        writer.write_bits((distance - DISTANCE_BASES[distance_index]) as u32, DISTANCE_EXTRA_BITS[distance_index]);
+    }
+
+    fn hash3(data: &[u8], pos: usize) -> usize {
+        (((data[pos] as usize) << 10) ^ ((data[pos + 1] as usize) << 5) ^ data[pos + 2] as usize) & (HASH_SIZE - 1)
+    }
+
+    // Compresses the data as a single block using LZ77 matches and the fixed Huffman codes
+    pub fn deflate_fixed(data: &[u8]) -> Vec<u8> {
+        let mut writer = BitWriter::new_bit_writer();
+
+        // Final block, block type 1
+        writer.write_bits(1, 1);
+        writer.write_bits(1, 2);
+
+        // Most recent position for each hash, and the position before it with the same hash
+        let mut head = vec![NO_POSITION; HASH_SIZE];
+        let mut prev = vec![NO_POSITION; WINDOW_SIZE];
+
+        let insert = |head: &mut [usize], prev: &mut [usize], pos: usize| {
+            if pos + MIN_MATCH <= data.len() {
+                let hash = hash3(data, pos);
+                prev[pos % WINDOW_SIZE] = head[hash];
+                head[hash] = pos;
+            }
+        };
+
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            let mut best_length = 0;
+            let mut best_distance = 0;
+
+            if max_length >= MIN_MATCH {
+                let mut candidate = head[hash3(data, pos)];
+                let mut chain = 0;
+
+                while candidate != NO_POSITION && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                    let length = data[candidate..candidate + max_length].iter()
+                        .zip(data[pos..pos + max_length].iter())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+
+                    if length > best_length {
+                        best_length = length;
+                        best_distance = pos - candidate;
+
+                        if length == max_length {
+                            break;
+                        }
+                    }
+
+                    // Entries older than the window may have been overwritten by newer positions
+                    let next = prev[candidate % WINDOW_SIZE];
+                    if next == NO_POSITION || next >= candidate {
+                        break;
+                    }
+
+                    candidate = next;
+                    chain += 1;
+                }
+            }
+
+            if best_length >= MIN_MATCH {
+                write_match(&mut writer, best_length, best_distance);
+
+                for p in pos..pos + best_length {
+                    insert(&mut head, &mut prev, p);
+                }
+
+                pos += best_length;
+            } else {
+                write_fixed_symbol(&mut writer, data[pos] as u32);
+                insert(&mut head, &mut prev, pos);
+                pos += 1;
+            }
+        }
+
+        write_fixed_symbol(&mut writer, 256);
+
+        writer.finish()
+    }
+
+    // Wraps DEFLATE data in a zlib stream. Uses whichever of the fixed Huffman and stored encodings is smaller.
+    pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+        let fixed = deflate_fixed(data);
+        let compressed = if fixed.len() <= data.len() + 5 { fixed } else { deflate_stored(data) };
+
+        // 32K window, no preset dictionary, and a check value that makes the header a multiple of 31
+        let mut out = vec![0x78, 0x01];
+        out.extend_from_slice(&compressed);
+        out.extend_from_slice(&adler32(data).to_be_bytes());
+
+        out
+    }
+
+    fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+        let p = a as i16 + b as i16 - c as i16;
+        let pa = (p - a as i16).abs();
+        let pb = (p - b as i16).abs();
+        let pc = (p - c as i16).abs();
+
+        if pa <= pb && pa <= pc {
+            a
+        } else if pb <= pc {
+            b
+        } else {
+            c
+        }
+    }
+
+    // Prefixes each row with a filter type and filters it. The filter with the smallest sum of
+    // absolute differences is used, which is a good guess at what compresses best.
+    fn filter_rows(raw: &[u8], row_length: usize, bytes_per_pixel: usize) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::with_capacity(raw.len() + raw.len() / row_length.max(1));
+        let zero_row = vec![0u8; row_length];
+        let mut candidate = vec![0u8; row_length];
+        let mut best = vec![0u8; row_length];
+
+        for (y, row) in raw.chunks_exact(row_length).enumerate() {
+            let prior = if y == 0 { &zero_row[..] } else { &raw[(y - 1) * row_length..y * row_length] };
+            let mut best_filter = 0;
+            let mut best_score = u64::MAX;
+
+            for filter in 0..5u8 {
+                for i in 0..row_length {
+                    let a = if i >= bytes_per_pixel { row[i - bytes_per_pixel] } else { 0 };
+                    let b = prior[i];
+                    let c = if i >= bytes_per_pixel { prior[i - bytes_per_pixel] } else { 0 };
+
+                    let predictor = match filter {
+                        0 => 0,
+                        1 => a,
+                        2 => b,
+                        3 => ((a as u16 + b as u16) / 2) as u8,
+                        _ => paeth_predictor(a, b, c),
+                    };
+
+                    candidate[i] = row[i].wrapping_sub(predictor);
+                }
+
+                // Small negative differences count as small too
+                let score: u64 = candidate.iter().map(|v| (*v as i8).unsigned_abs() as u64).sum();
+
+                if score < best_score {
+                    best_score = score;
+                    best_filter = filter;
+                    best.copy_from_slice(&candidate);
+                }
+            }
+
+            out.push(best_filter);
+            out.extend_from_slice(&best);
+        }
+
+        out
+    }
+
+    fn write_chunk<W: Write>(writer: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        if data.len() > PNG_MAX_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "PNG chunks can hold at most 2^31 - 1 bytes"));
+        }
+
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+
+        // The CRC covers the chunk type and data, but not the length
+        let mut crc_data = chunk_type.to_vec();
+        crc_data.extend_from_slice(data);
+
+        writer.write_all(&crc_data)?;
+        writer.write_all(&crc32(&crc_data).to_be_bytes())
+    }
+
+    // Splits the compressed image across as many IDAT chunks as it needs. Decoders join them back together.
+    fn write_image_data<W: Write>(writer: &mut W, data: &[u8], max_chunk_length: usize) -> io::Result<()> {
+        for part in data.chunks(max_chunk_length) {
+            write_chunk(writer, b"IDAT", part)?;
+        }
+
+        Ok(())
+    }
+
+    impl Canvas {
+        // Truncates every sample. Use write_png_with to round or dither.
+        pub fn write_png<W: Write>(&self, writer: W, color_type: PngColorType, depth: BitDepth) -> io::Result<()> {
+            self.write_png_with(writer, color_type, depth, &Quantizer::new_quantizer(Rounding::Truncate))
+        }
+
+        pub fn write_png_with<W: Write>(&self, mut writer: W, color_type: PngColorType, depth: BitDepth, quantizer: &Quantizer) -> io::Result<()> {
+            if self.width == 0 || self.height == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "PNG images must be at least 1 pixel wide and high"));
+            }
+
+            if self.width > PNG_MAX_LENGTH || self.height > PNG_MAX_LENGTH {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "PNG images can be at most 2^31 - 1 pixels wide and high"));
+            }
+
+            let channels = color_type.channels();
+            let bytes_per_sample = match depth {
+                BitDepth::Eight => 1,
+                BitDepth::Sixteen => 2,
+            };
+            let bytes_per_pixel = channels * bytes_per_sample;
+            let row_length = self.width * bytes_per_pixel;
+
+            // Samples are stored most significant byte first
+            let mut raw: Vec<u8> = Vec::with_capacity(row_length * self.height);
+
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let mut samples = quantizer.quantize(&self.read_pixel(x, y), depth.max_value(), x, y).to_vec();
+                    if color_type == PngColorType::Rgba {
+                        samples.push(depth.max_value());
+                    }
+
+                    for sample in samples.iter() {
+                        match depth {
+                            BitDepth::Eight => raw.push(*sample as u8),
+                            BitDepth::Sixteen => raw.extend_from_slice(&sample.to_be_bytes()),
+                        }
+                    }
+                }
+            }
+
+            let mut header: Vec<u8> = Vec::new();
+            header.extend_from_slice(&(self.width as u32).to_be_bytes());
+            header.extend_from_slice(&(self.height as u32).to_be_bytes());
+            // Bit depth, color type, then deflate compression, adaptive filtering and no interlacing
+            header.extend_from_slice(&[(bytes_per_sample * 8) as u8, color_type.code(), 0, 0, 0]);
+
+            writer.write_all(&PNG_SIGNATURE)?;
+            write_chunk(&mut writer, b"IHDR", &header)?;
+            write_image_data(&mut writer, &zlib_compress(&filter_rows(&raw, row_length, bytes_per_pixel)), PNG_MAX_LENGTH)?;
+            write_chunk(&mut writer, b"IEND", &[])?;
+
+            writer.flush()
+        }
+
+        pub fn write_png_file<P: AsRef<Path>>(&self, path: P, color_type: PngColorType, depth: BitDepth) -> io::Result<()> {
+            self.write_png(BufWriter::new(fs::File::create(path)?), color_type, depth)
+        }
+
+        pub fn write_png_file_with<P: AsRef<Path>>(&self, path: P, color_type: PngColorType, depth: BitDepth, quantizer: &Quantizer) -> io::Result<()> {
+            self.write_png_with(BufWriter::new(fs::File::create(path)?), color_type, depth, quantizer)
+        }
+    }
+
+    #[cfg(test)]
+    mod png_tests {
+        use super::*;
+        use super::super::rt_prelude::Color;
+        use super::super::quantization::Dither;
+
+        // Reads bits starting from the least significant bit of each byte
+        struct BitReader<'a> {
+            data: &'a [u8],
+            pos: usize,
+        }
+
+        impl BitReader<'_> {
+            fn read_bits(&mut self, n: u32) -> u32 {
+                let mut value = 0;
+
+                for i in 0..n {
+                    let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                    value |= (bit as u32) << i;
+                    self.pos += 1;
+                }
+
+                value
+            }
+
+            // Reads a Huffman code one bit at a time, most significant bit first
+            fn read_code_bit(&mut self, code: u32) -> u32 {
+                (code << 1) | self.read_bits(1)
+            }
+
+            fn read_fixed_symbol(&mut self) -> u32 {
+                let mut code = 0;
+                for _ in 0..7 {
+                    code = self.read_code_bit(code);
+                }
+                if code <= 0x17 {
+                    return code + 256;
+                }
+
+                code = self.read_code_bit(code);
+                if (0x30..=0xbf).contains(&code) {
+                    return code - 0x30;
+                }
+                if (0xc0..=0xc7).contains(&code) {
+                    return code - 0xc0 + 280;
+                }
+
+                code = self.read_code_bit(code);
+                code - 0x190 + 144
+            }
+        }
+
+        // A minimal zlib decoder for stored and fixed Huffman blocks, to check what the encoder writes
+        fn zlib_decompress(stream: &[u8]) -> Vec<u8> {
+            assert_eq!((stream[0] as u32 * 256 + stream[1] as u32) % 31, 0);
+
+            let mut reader = BitReader { data: &stream[2..stream.len() - 4], pos: 0 };
+            let mut out: Vec<u8> = Vec::new();
+
+            loop {
+                let last = reader.read_bits(1) == 1;
+
+                match reader.read_bits(2) {
+                    0 => {
+                        reader.pos = reader.pos.div_ceil(8) * 8;
+                        let start = reader.pos / 8;
+                        let length = u16::from_le_bytes([reader.data[start], reader.data[start + 1]]) as usize;
+                        let inverse = u16::from_le_bytes([reader.data[start + 2], reader.data[start + 3]]);
+                        assert_eq!(!(length as u16), inverse);
+
+                        out.extend_from_slice(&reader.data[start + 4..start + 4 + length]);
+                        reader.pos = (start + 4 + length) * 8;
+                    }
+                    1 => loop {
+                        let symbol = reader.read_fixed_symbol();
+
+                        if symbol < 256 {
+                            out.push(symbol as u8);
+                        } else if symbol == 256 {
+                            break;
+                        } else {
+                            let i = (symbol - 257) as usize;
+                            let length = LENGTH_BASES[i] + reader.read_bits(LENGTH_EXTRA_BITS[i]) as usize;
+
+                            let mut code = 0;
+                            for _ in 0..5 {
+                                code = reader.read_code_bit(code);
+                            }
+                            let d = code as usize;
+                            let distance = DISTANCE_BASES[d] + reader.read_bits(DISTANCE_EXTRA_BITS[d]) as usize;
+
+                            for _ in 0..length {
+                                out.push(out[out.len() - distance]);
+                            }
+                        }
+                    },
+                    block_type => panic!("unexpected block type {}", block_type),
+                }
+
+                if last {
+                    break;
+                }
+            }
+
+            let checksum = &stream[stream.len() - 4..];
+            assert_eq!(adler32(&out).to_be_bytes(), checksum);
+
+            out
+        }
+
+        fn unfilter_rows(filtered: &[u8], row_length: usize, bytes_per_pixel: usize) -> Vec<u8> {
+            let mut out: Vec<u8> = Vec::new();
+
+            for (y, row) in filtered.chunks_exact(row_length + 1).enumerate() {
+                let start = out.len();
+
+                for i in 0..row_length {
+                    let a = if i >= bytes_per_pixel { out[start + i - bytes_per_pixel] } else { 0 };
+                    let b = if y > 0 { out[start - row_length + i] } else { 0 };
+                    let c = if y > 0 && i >= bytes_per_pixel { out[start - row_length + i - bytes_per_pixel] } else { 0 };
+
+                    let predictor = match row[0] {
+                        0 => 0,
+                        1 => a,
+                        2 => b,
+                        3 => ((a as u16 + b as u16) / 2) as u8,
+                        _ => paeth_predictor(a, b, c),
+                    };
+
+                    out.push(row[i + 1].wrapping_add(predictor));
+                }
+            }
+
+            out
+        }
+
+        // Splits a PNG file into its chunks, checking the signature and each CRC
+        fn read_chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+            assert_eq!(png[..8], PNG_SIGNATURE);
+
+            let mut chunks = Vec::new();
+            let mut pos = 8;
+
+            while pos < png.len() {
+                let length = u32::from_be_bytes([png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+                let type_and_data = &png[pos + 4..pos + 8 + length];
+                let crc = &png[pos + 8 + length..pos + 12 + length];
+
+                assert_eq!(crc32(type_and_data).to_be_bytes(), crc);
+
+                chunks.push((String::from_utf8(type_and_data[..4].to_vec()).unwrap(), type_and_data[4..].to_vec()));
+                pos += 12 + length;
+            }
+
+            chunks
+        }
+
+        fn test_canvas() -> Canvas {
+            let mut c = Canvas::new(7, 5, Color::new(0.0, 0.0, 0.0));
+
+            for x in 0..7 {
+                for y in 0..5 {
+                    c.write_pixel(x, y, &Color::new(x as f64 / 6.0, y as f64 / 4.0, ((x * y) % 3) as f64 / 2.0));
+                }
+            }
+
+            c
+        }
+
+        #[test]
+        fn crc32_check_values() {
+            assert_eq!(crc32(b""), 0);
+            assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+            // The CRC of every PNG's IEND chunk
+            assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        }
+
+        #[test]
+        fn adler32_check_values() {
+            assert_eq!(adler32(b""), 1);
+            assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+            // Long enough for the sums to wrap around several times
+            let data = vec![255u8; 100_000];
+            let (mut a, mut b) = (1u64, 0u64);
+            for byte in data.iter() {
+                a = (a + *byte as u64) % 65521;
+                b = (b + a) % 65521;
+            }
+            assert_eq!(adler32(&data), ((b << 16) | a) as u32);
+        }
+
+        #[test]
+        fn stored_blocks_round_trip() {
+            let data: Vec<u8> = (0..150_000).map(|i| (i * 7 % 251) as u8).collect();
+            let mut stream = vec![0x78, 0x01];
+            stream.extend_from_slice(&deflate_stored(&data));
+            stream.extend_from_slice(&adler32(&data).to_be_bytes());
+
+            assert_eq!(zlib_decompress(&stream), data);
+
+            let mut empty = vec![0x78, 0x01];
+            empty.extend_from_slice(&deflate_stored(&[]));
+            empty.extend_from_slice(&adler32(&[]).to_be_bytes());
+            assert!(zlib_decompress(&empty).is_empty());
+        }
+
+        #[test]
+        fn fixed_huffman_round_trip() {
+            let inputs: Vec<Vec<u8>> = vec![
+                Vec::new(),
+                b"a".to_vec(),
+                b"abcabcabcabcabcabcabcabc".to_vec(),
+                vec![0u8; 1000],
+                (0..70_000).map(|i| ((i / 3) % 256) as u8).collect(),
+                (0..5000).map(|i| ((i * i) % 256) as u8).collect(),
+            ];
+
+            for data in inputs.iter() {
+                assert_eq!(zlib_decompress(&zlib_compress(data)), *data);
+            }
+        }
+
+        #[test]
+        fn fixed_huffman_compresses_repetition() {
+            let data = vec![42u8; 10_000];
+
+            assert!(zlib_compress(&data).len() < 200);
+        }
+
+        #[test]
+        fn incompressible_data_is_stored() {
+            // Bytes from a simple generator, which LZ77 cannot find matches in
+            let mut state: u32 = 1;
+            let data: Vec<u8> = (0..2000).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            }).collect();
+
+            let stream = zlib_compress(&data);
+
+            assert!(stream.len() <= data.len() + 11);
+            assert_eq!(zlib_decompress(&stream), data);
+        }
+
+        #[test]
+        fn filters_round_trip() {
+            let raw: Vec<u8> = (0..300).map(|i| ((i * 37) % 256) as u8).collect();
+
+            for bytes_per_pixel in [1, 3, 6].iter() {
+                let filtered = filter_rows(&raw, 30, *bytes_per_pixel);
+
+                assert_eq!(filtered.len(), raw.len() + 10);
+                assert_eq!(unfilter_rows(&filtered, 30, *bytes_per_pixel), raw);
+            }
+        }
+
+        #[test]
+        fn png_structure() {
+            let mut png: Vec<u8> = Vec::new();
+            test_canvas().write_png(&mut png, PngColorType::Rgb, BitDepth::Eight).unwrap();
+
+            let chunks = read_chunks(&png);
+            let names: Vec<&str> = chunks.iter().map(|(name, _)| name.as_str()).collect();
+
+            assert_eq!(names, vec!["IHDR", "IDAT", "IEND"]);
+            assert_eq!(chunks[0].1, vec![0, 0, 0, 7, 0, 0, 0, 5, 8, 2, 0, 0, 0]);
+            assert!(chunks[2].1.is_empty());
+        }
+
+        #[test]
+        fn reject_empty_canvas() {
+            for (width, height) in [(0, 3), (3, 0)].iter() {
+                let mut png: Vec<u8> = Vec::new();
+                let result = Canvas::new(*width, *height, Color::new(0.0, 0.0, 0.0))
+                    .write_png(&mut png, PngColorType::Rgb, BitDepth::Eight);
+
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+                assert!(png.is_empty());
+            }
+        }
+
+        #[test]
+        fn reject_oversized_canvas() {
+            for (width, height) in [(PNG_MAX_LENGTH + 1, 1), (1, PNG_MAX_LENGTH + 1)].iter() {
+                // The size is checked before any pixels are read, so they do not have to exist
+                let mut c = Canvas::new(1, 1, Color::new(0.0, 0.0, 0.0));
+                c.width = *width;
+                c.height = *height;
+
+                let mut png: Vec<u8> = Vec::new();
+                let result = c.write_png(&mut png, PngColorType::Rgb, BitDepth::Eight);
+
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+                assert!(png.is_empty());
+            }
+        }
+
+        #[test]
+        fn split_image_data() {
+            let raw: Vec<u8> = (0..300).map(|i| ((i * 37) % 256) as u8).collect();
+            let compressed = zlib_compress(&raw);
+
+            let mut png: Vec<u8> = PNG_SIGNATURE.to_vec();
+            write_image_data(&mut png, &compressed, 10).unwrap();
+
+            let chunks = read_chunks(&png);
+            let joined: Vec<u8> = chunks.iter().flat_map(|(_, data)| data.clone()).collect();
+
+            assert_eq!(chunks.len(), compressed.len().div_ceil(10));
+            assert!(chunks.iter().all(|(name, data)| name == "IDAT" && data.len() <= 10));
+            assert_eq!(zlib_decompress(&joined), raw);
+        }
+
+        #[test]
+        fn png_pixels_round_trip() {
+            let c = test_canvas();
+            let quantizer = Quantizer::new_quantizer(Rounding::Truncate);
+
+            // (color type, depth, bytes per pixel, IHDR bit depth and color type)
+            let cases = [
+                (PngColorType::Rgb, BitDepth::Eight, 3, [8, 2]),
+                (PngColorType::Rgba, BitDepth::Eight, 4, [8, 6]),
+                (PngColorType::Rgb, BitDepth::Sixteen, 6, [16, 2]),
+                (PngColorType::Rgba, BitDepth::Sixteen, 8, [16, 6]),
+            ];
+
+            for (color_type, depth, bytes_per_pixel, ihdr) in cases.iter() {
+                let mut png: Vec<u8> = Vec::new();
+                c.write_png(&mut png, *color_type, *depth).unwrap();
+
+                let chunks = read_chunks(&png);
+                assert_eq!(chunks[0].1[8..10], ihdr[..]);
+
+                let row_length = c.width * bytes_per_pixel;
+                let raw = unfilter_rows(&zlib_decompress(&chunks[1].1), row_length, *bytes_per_pixel);
+                assert_eq!(raw.len(), row_length * c.height);
+
+                let bytes_per_sample = bytes_per_pixel / color_type.channels();
+
+                for (i, pixel) in raw.chunks_exact(*bytes_per_pixel).enumerate() {
+                    let samples: Vec<u16> = pixel.chunks_exact(bytes_per_sample)
+                        .map(|s| s.iter().fold(0u16, |acc, byte| (acc << 8) | *byte as u16))
+                        .collect();
+
+                    let (x, y) = (i % c.width, i / c.width);
+                    let expected = quantizer.quantize(&c.read_pixel(x, y), depth.max_value(), x, y);
+                    assert_eq!(samples[..3], expected[..]);
+
+                    if *color_type == PngColorType::Rgba {
+                        assert_eq!(samples[3], depth.max_value());
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn write_png_with_dithering() {
+            let c = test_canvas();
+            let mut quantizer = Quantizer::new_quantizer(Rounding::Nearest);
+            quantizer.dither = Dither::Ordered;
+
+            let mut png: Vec<u8> = Vec::new();
+            c.write_png_with(&mut png, PngColorType::Rgb, BitDepth::Eight, &quantizer).unwrap();
+
+            let raw = unfilter_rows(&zlib_decompress(&read_chunks(&png)[1].1), c.width * 3, 3);
+
+            for (i, pixel) in raw.chunks_exact(3).enumerate() {
+                let (x, y) = (i % c.width, i / c.width);
+                let expected = quantizer.quantize(&c.read_pixel(x, y), 255, x, y);
+
+                assert_eq!(pixel.iter().map(|s| *s as u16).collect::<Vec<u16>>(), expected.to_vec());
+            }
+        }
+    }
+}
+
+pub mod hdr {
+    use std::error::Error;
+    use std::fmt;
+    use std::fs;
+    use std::io;
+    use std::io::{Write, BufWriter};
+    use std::path::Path;
+
+    use super::rt_prelude::{Color, Canvas};
+    use super::ppm::Tokens;
+
+    #[derive(Debug)]
+    pub enum HdrError {
+        Io(io::Error),
+        // The file is not a PFM or Radiance image, or uses a pixel format other than RGBE
+        UnsupportedFormat(String),
+        // The named header field is missing, not a number or out of range
+        InvalidHeader(&'static str),
+        // A run length encoded scanline does not add up to the image width. row counts from the first scanline in the file.
+        InvalidScanline { row: usize },
+        // The file ends early. Counts samples for PFM and scanlines for Radiance files.
+        TruncatedData { expected: usize, found: usize },
+    }
+
+    impl fmt::Display for HdrError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                HdrError::Io(e) => write!(f, "could not read HDR image: {}", e),
+                HdrError::UnsupportedFormat(format) => write!(f, "unsupported HDR image format {:?}", format),
+                HdrError::InvalidHeader(field) => write!(f, "invalid or missing {} in HDR image header", field),
+                HdrError::InvalidScanline { row } => write!(f, "invalid run length encoding in scanline {}", row),
+                HdrError::TruncatedData { expected, found } => {
+                    write!(f, "HDR image data is truncated, expected {} but found {}", expected, found)
+                }
+            }
+        }
+    }
+
+    impl Error for HdrError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                HdrError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for HdrError {
+        fn from(e: io::Error) -> HdrError {
+            HdrError::Io(e)
+        }
+    }
+
+    // Radiance scanlines can only be run length encoded when they are between 8 and 32767 pixels wide
+    const MIN_RLE_WIDTH: usize = 8;
+    const MAX_RLE_WIDTH: usize = 0x7fff;
+
+    // Runs let a Radiance file describe a huge image in a few bytes, so the size in its header is capped
+    // before anything is decoded. This is a 16384 by 16384 image, bigger than any real environment map.
+    const MAX_HDR_PIXELS: usize = 1 << 28;
+    // Shorter runs are cheaper to write as literals
+    const MIN_RUN_LENGTH: usize = 4;
+    const MAX_RUN_LENGTH: usize = 127;
+    const MAX_LITERAL_LENGTH: usize = 128;
+
+    // Stores the channels as 8 bit mantissas sharing the exponent of the brightest one. Negative and NaN
+    // channels become 0.
+    pub fn color_to_rgbe(color: &Color) -> [u8; 4] {
+        let r = color.r.max(0.0);
+        let g = color.g.max(0.0);
+        let b = color.b.max(0.0);
+        let v = r.max(g).max(b);
+
+        if v < 1e-32 {
+            return [0, 0, 0, 0];
+        }
+        if v.is_infinite() {
+            return [255, 255, 255, 255];
+        }
+
+        // v = mantissa * 2^exponent with the mantissa between 0.5 and 1
+        let mut exponent = v.log2().floor() as i32 + 1;
+        let mut mantissa = v / 2.0_f64.powi(exponent);
+        if mantissa >= 1.0 {
+            exponent += 1;
+            mantissa /= 2.0;
+        } else if mantissa < 0.5 {
+            exponent -= 1;
+            mantissa *= 2.0;
+        }
+
+        if exponent > 127 {
+            return [255, 255, 255, 255];
+        }
+
+        let scale = mantissa * 256.0 / v;
+        let quantize = |c: f64| (c * scale).round().min(255.0) as u8;
+
+        [quantize(r), quantize(g), quantize(b), (exponent + 128) as u8]
+    }
+
+    pub fn rgbe_to_color(rgbe: [u8; 4]) -> Color {
+        if rgbe[3] == 0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let factor = 2.0_f64.powi(rgbe[3] as i32 - (128 + 8));
+
+        Color::new(rgbe[0] as f64 * factor, rgbe[1] as f64 * factor, rgbe[2] as f64 * factor)
+    }
+
+    // Returns the line starting at pos without its line ending, and moves pos past it
+    fn read_line<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+        if *pos >= data.len() {
+            return None;
+        }
+
+        let start = *pos;
+        let end = data[start..].iter().position(|b| *b == b'\n').map_or(data.len(), |i| start + i);
+        *pos = (end + 1).min(data.len());
+
+        let line = &data[start..end];
+        Some(line.strip_suffix(b"\r").unwrap_or(line))
+    }
+
+    fn read_scanline(data: &[u8], pos: &mut usize, width: usize, height: usize, row: usize) -> Result<Vec<[u8; 4]>, HdrError> {
+        let truncated = || HdrError::TruncatedData { expected: height, found: row };
+        let invalid = || HdrError::InvalidScanline { row };
+
+        let rle_start = data.get(*pos..*pos + 4).filter(|p| p[0] == 2 && p[1] == 2 && p[2] & 0x80 == 0);
+
+        if let (true, Some(start)) = ((MIN_RLE_WIDTH..=MAX_RLE_WIDTH).contains(&width), rle_start) {
+            if ((start[2] as usize) << 8 | start[3] as usize) != width {
+                return Err(invalid());
+            }
+            *pos += 4;
+
+            // Each channel is stored separately, as a mix of runs and literal bytes
+            let mut pixels = vec![[0u8; 4]; width];
+
+            for channel in 0..4 {
+                let mut x = 0;
+
+                while x < width {
+                    let count = *data.get(*pos).ok_or_else(truncated)? as usize;
+                    *pos += 1;
+
+                    if count > 128 {
+                        let length = count - 128;
+                        let value = *data.get(*pos).ok_or_else(truncated)?;
+                        *pos += 1;
+
+                        if x + length > width {
+                            return Err(invalid());
+                        }
+
+                        for pixel in pixels[x..x + length].iter_mut() {
+                            pixel[channel] = value;
+                        }
+                        x += length;
+                    } else {
+                        if count == 0 || x + count > width {
+                            return Err(invalid());
+                        }
+
+                        let values = data.get(*pos..*pos + count).ok_or_else(truncated)?;
+                        *pos += count;
+
+                        for (pixel, value) in pixels[x..x + count].iter_mut().zip(values.iter()) {
+                            pixel[channel] = *value;
+                        }
+                        x += count;
+                    }
+                }
+            }
+
+            return Ok(pixels);
+        }
+
+        // Flat pixels, where a pixel of 1, 1, 1 repeats the previous one. Consecutive repeats
+        // hold successively higher bytes of the count.
+        let mut pixels: Vec<[u8; 4]> = Vec::with_capacity(width.min(data.len().saturating_sub(*pos) / 4));
+        let mut shift = 0;
+
+        while pixels.len() < width {
+            let pixel = data.get(*pos..*pos + 4).ok_or_else(truncated)?;
+            *pos += 4;
+
+            if pixel[..3] == [1, 1, 1] {
+                let previous = *pixels.last().ok_or_else(invalid)?;
+
+                // The count can only have as many bytes as a usize
+                if shift > usize::BITS - 8 {
+                    return Err(invalid());
+                }
+
+                let count = (pixel[3] as usize) << shift;
+
+                if pixels.len() + count > width {
+                    return Err(invalid());
+                }
+
+                pixels.extend(std::iter::repeat_n(previous, count));
+                shift += 8;
+            } else {
+                pixels.push([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                shift = 0;
+            }
+        }
+
+        Ok(pixels)
+    }
+
+    // Finds the next run worth encoding at or after from. Returns its start and length, or the end of the values and 0.
+    fn find_run(values: &[u8], from: usize) -> (usize, usize) {
+        let mut start = from;
+
+        while start < values.len() {
+            let length = values[start..].iter().take(MAX_RUN_LENGTH).take_while(|v| **v == values[start]).count();
+
+            if length >= MIN_RUN_LENGTH {
+                return (start, length);
+            }
+
+            start += length;
+        }
+
+        (values.len(), 0)
+    }
+
+    fn write_rle_channel(values: &[u8], out: &mut Vec<u8>) {
+        let mut pos = 0;
+
+        while pos < values.len() {
+            let (run_start, run_length) = find_run(values, pos);
+
+            while pos < run_start {
+                let count = (run_start - pos).min(MAX_LITERAL_LENGTH);
+                out.push(count as u8);
+                out.extend_from_slice(&values[pos..pos + count]);
+                pos += count;
+            }
+
+            if run_length > 0 {
+                out.push((128 + run_length) as u8);
+                out.push(values[run_start]);
+                pos = run_start + run_length;
+            }
+        }
+    }
+
+    impl Canvas {
+        // Reads a color (PF) or greyscale (Pf) Portable FloatMap. Samples are 32 bit floats, stored
+        // bottom row first.
+        pub fn from_pfm(data: &[u8]) -> Result<Canvas, HdrError> {
+            let mut tokens = Tokens { data, pos: 0 };
+
+            let magic = tokens.next_token().unwrap_or(&[]);
+            let channels = match magic {
+                b"PF" => 3,
+                b"Pf" => 1,
+                _ => return Err(HdrError::UnsupportedFormat(String::from_utf8_lossy(magic).into_owned())),
+            };
+
+            let width = tokens.next_number().filter(|w| *w > 0).ok_or(HdrError::InvalidHeader("width"))? as usize;
+            let height = tokens.next_number().filter(|h| *h > 0).ok_or(HdrError::InvalidHeader("height"))? as usize;
+
+            // Only the sign of the scale matters. Negative means little endian samples.
+            let scale: f64 = tokens.next_token()
+                .and_then(|t| std::str::from_utf8(t).ok())
+                .and_then(|t| t.parse().ok())
+                .filter(|s: &f64| *s != 0.0 && s.is_finite())
+                .ok_or(HdrError::InvalidHeader("scale"))?;
+
+            // A single whitespace character separates the header from the samples
+            let body = data.get(tokens.pos + 1..).unwrap_or(&[]);
+            let sample_count = width.checked_mul(height)
+                .and_then(|pixels| pixels.checked_mul(channels))
+                .ok_or(HdrError::InvalidHeader("image size"))?;
+
+            if body.len() / 4 < sample_count {
+                return Err(HdrError::TruncatedData { expected: sample_count, found: body.len() / 4 });
+            }
+
+            let samples: Vec<f64> = body.chunks_exact(4).take(sample_count).map(|bytes| {
+                let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                (if scale < 0.0 { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }) as f64
+            }).collect();
+
+            let mut canvas = Canvas::new(width, height, Color::new(0.0, 0.0, 0.0));
+
+            for (i, pixel) in samples.chunks_exact(channels).enumerate() {
+                let color = if channels == 3 {
+                    Color::new(pixel[0], pixel[1], pixel[2])
+                } else {
+                    Color::new(pixel[0], pixel[0], pixel[0])
+                };
+
+                canvas.write_pixel(i % width, height - 1 - i / width, &color);
+            }
+
+            Ok(canvas)
+        }
+
+        pub fn from_pfm_file<P: AsRef<Path>>(path: P) -> Result<Canvas, HdrError> {
+            Canvas::from_pfm(&fs::read(path)?)
+        }
+
+        // Writes a little endian color PFM, keeping every channel as a 32 bit float
+        pub fn write_pfm<W: Write>(&self, mut writer: W) -> io::Result<()> {
+            write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+
+            let mut row: Vec<u8> = Vec::with_capacity(self.width * 12);
+
+            for y in (0..self.height).rev() {
+                row.clear();
+
+                for x in 0..self.width {
+                    let color = self.read_pixel(x, y);
+
+                    for channel in [color.r, color.g, color.b].iter() {
+                        row.extend_from_slice(&(*channel as f32).to_le_bytes());
+                    }
+                }
+
+                writer.write_all(&row)?;
+            }
+
+            writer.flush()
+        }
+
+        pub fn write_pfm_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+            self.write_pfm(BufWriter::new(fs::File::create(path)?))
+        }
+
+        // Reads a Radiance RGBE image, with flat or run length encoded scanlines. Pixel values are
+        // divided by any EXPOSURE in the header to get back the original radiance.
+        pub fn from_hdr(data: &[u8]) -> Result<Canvas, HdrError> {
+            let mut pos = 0;
+
+            let magic = read_line(data, &mut pos).unwrap_or(&[]);
+            if !magic.starts_with(b"#?") {
+                return Err(HdrError::UnsupportedFormat(String::from_utf8_lossy(magic).into_owned()));
+            }
+
+            let mut exposure = 1.0;
+
+            // Header lines run until an empty line
+            loop {
+                let line = read_line(data, &mut pos).ok_or(HdrError::InvalidHeader("resolution"))?;
+
+                if line.is_empty() {
+                    break;
+                }
+
+                if let Some(format) = line.strip_prefix(b"FORMAT=") {
+                    if format != b"32-bit_rle_rgbe" {
+                        return Err(HdrError::UnsupportedFormat(String::from_utf8_lossy(format).into_owned()));
+                    }
+                } else if let Some(value) = line.strip_prefix(b"EXPOSURE=") {
+                    exposure *= std::str::from_utf8(value).ok()
+                        .and_then(|v| v.trim().parse::<f64>().ok())
+                        .filter(|e| *e > 0.0 && e.is_finite())
+                        .ok_or(HdrError::InvalidHeader("exposure"))?;
+                }
+            }
+
+            // Only the standard orientations are supported: rows left to right, stored top or bottom first
+            let resolution = read_line(data, &mut pos).and_then(|l| std::str::from_utf8(l).ok()).unwrap_or("");
+            let fields: Vec<&str> = resolution.split_ascii_whitespace().collect();
+
+            let top_first = match fields.first() {
+                Some(&"-Y") => true,
+                Some(&"+Y") => false,
+                _ => return Err(HdrError::InvalidHeader("resolution")),
+            };
+            if fields.len() != 4 || fields[2] != "+X" {
+                return Err(HdrError::InvalidHeader("resolution"));
+            }
+
+            let height = fields[1].parse::<usize>().ok().filter(|h| *h > 0).ok_or(HdrError::InvalidHeader("height"))?;
+            let width = fields[3].parse::<usize>().ok().filter(|w| *w > 0).ok_or(HdrError::InvalidHeader("width"))?;
+
+            if width.checked_mul(height).filter(|pixels| *pixels <= MAX_HDR_PIXELS).is_none() {
+                return Err(HdrError::InvalidHeader("image size"));
+            }
+
+            // Every scanline takes at least 4 bytes, so a header claiming more rows than that is wrong
+            let remaining = data.len() - pos;
+            if remaining / 4 < height {
+                return Err(HdrError::TruncatedData { expected: height, found: remaining / 4 });
+            }
+
+            // Rows are only kept once decoded, so the image size in the header is never allocated up front
+            let mut rows: Vec<Vec<[u8; 4]>> = Vec::new();
+            for row in 0..height {
+                rows.push(read_scanline(data, &mut pos, width, height, row)?);
+            }
+
+            let mut canvas = Canvas::new(width, height, Color::new(0.0, 0.0, 0.0));
+
+            for (row, scanline) in rows.iter().enumerate() {
+                let y = if top_first { row } else { height - 1 - row };
+
+                for (x, rgbe) in scanline.iter().enumerate() {
+                    canvas.write_pixel(x, y, &(rgbe_to_color(*rgbe) * (1.0 / exposure)));
+                }
+            }
+
+            Ok(canvas)
+        }
+
+        pub fn from_hdr_file<P: AsRef<Path>>(path: P) -> Result<Canvas, HdrError> {
+            Canvas::from_hdr(&fs::read(path)?)
+        }
+
+        // Writes a Radiance RGBE image, run length encoding the scanlines whenever the width allows it
+        pub fn write_hdr<W: Write>(&self, mut writer: W) -> io::Result<()> {
+            write!(writer, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", self.height, self.width)?;
+
+            let rle = (MIN_RLE_WIDTH..=MAX_RLE_WIDTH).contains(&self.width);
+            let mut row: Vec<u8> = Vec::new();
+            let mut channel: Vec<u8> = Vec::with_capacity(self.width);
+
+            for y in 0..self.height {
+                row.clear();
+
+                let pixels: Vec<[u8; 4]> = (0..self.width).map(|x| color_to_rgbe(&self.read_pixel(x, y))).collect();
+
+                if rle {
+                    row.extend_from_slice(&[2, 2, (self.width >> 8) as u8, (self.width & 0xff) as u8]);
+
+                    for c in 0..4 {
+                        channel.clear();
+                        channel.extend(pixels.iter().map(|p| p[c]));
+                        write_rle_channel(&channel, &mut row);
+                    }
+                } else {
+                    for pixel in pixels.iter() {
+                        row.extend_from_slice(pixel);
+                    }
+                }
+
+                writer.write_all(&row)?;
+            }
+
+            writer.flush()
+        }
+
+        pub fn write_hdr_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+            self.write_hdr(BufWriter::new(fs::File::create(path)?))
+        }
+    }
+
+    #[cfg(test)]
+    mod hdr_tests {
+        use super::*;
+
+        // RGBE keeps about 8 bits of precision relative to the brightest channel
+        fn assert_rgbe_close(actual: &Color, expected: &Color) {
+            let brightest = expected.r.max(expected.g).max(expected.b);
+
+            for (a, e) in [(actual.r, expected.r), (actual.g, expected.g), (actual.b, expected.b)].iter() {
+                assert!((a - e).abs() <= brightest / 256.0, "{:?} is not close to {:?}", actual, expected);
+            }
+        }
+
+        fn test_canvas(width: usize, height: usize) -> Canvas {
+            let mut c = Canvas::new(width, height, Color::new(0.0, 0.0, 0.0));
+
+            for x in 0..width {
+                for y in 0..height {
+                    // Includes values far above 1, which an 8 bit image would lose
+                    c.write_pixel(x, y, &Color::new(x as f64 * 3.5, y as f64 / 8.0, if x > width / 2 { 1000.0 } else { 0.25 }));
+                }
+            }
+
+            c
+        }
+
+        #[test]
+        fn write_pfm_header_and_row_order() {
+            let mut c = Canvas::new(2, 2, Color::new(0.0, 0.0, 0.0));
+            c.write_pixel(0, 0, &Color::new(1.0, 2.0, 3.0));
+            c.write_pixel(1, 1, &Color::new(-0.5, 1e6, 0.125));
+
+            let mut pfm: Vec<u8> = Vec::new();
+            c.write_pfm(&mut pfm).unwrap();
+
+            let header = b"PF\n2 2\n-1.0\n";
+            assert_eq!(pfm[..header.len()], header[..]);
+            assert_eq!(pfm.len(), header.len() + 2 * 2 * 3 * 4);
+
+            // The bottom row comes first
+            let sample = |i: usize| {
+                let start = header.len() + i * 4;
+                f32::from_le_bytes([pfm[start], pfm[start + 1], pfm[start + 2], pfm[start + 3]])
+            };
+            assert_eq!((sample(3), sample(4), sample(5)), (-0.5, 1e6, 0.125));
+            assert_eq!((sample(6), sample(7), sample(8)), (1.0, 2.0, 3.0));
+        }
+
+        #[test]
+        fn pfm_round_trip() {
+            let c = test_canvas(5, 3);
+            let mut pfm: Vec<u8> = Vec::new();
+            c.write_pfm(&mut pfm).unwrap();
+
+            let read = Canvas::from_pfm(&pfm).unwrap();
+
+            assert_eq!((read.width, read.height), (5, 3));
+            for x in 0..5 {
+                for y in 0..3 {
+                    assert_eq!(read.read_pixel(x, y), c.read_pixel(x, y));
+                }
+            }
+        }
+
+        #[test]
+        fn read_big_endian_greyscale_pfm() {
+            let mut pfm = b"Pf\n2 1\n1.0\n".to_vec();
+            pfm.extend_from_slice(&0.5f32.to_be_bytes());
+            pfm.extend_from_slice(&4.0f32.to_be_bytes());
+
+            let c = Canvas::from_pfm(&pfm).unwrap();
+
+            assert_eq!(c.read_pixel(0, 0), Color::new(0.5, 0.5, 0.5));
+            assert_eq!(c.read_pixel(1, 0), Color::new(4.0, 4.0, 4.0));
+        }
+
+        #[test]
+        fn reject_invalid_pfm() {
+            assert!(matches!(Canvas::from_pfm(b"P6\n1 1\n255\n"), Err(HdrError::UnsupportedFormat(ref m)) if m == "P6"));
+            assert!(matches!(Canvas::from_pfm(b"PF\n0 1\n-1.0\n"), Err(HdrError::InvalidHeader("width"))));
+            assert!(matches!(Canvas::from_pfm(b"PF\n1 1\n0.0\n"), Err(HdrError::InvalidHeader("scale"))));
+            assert!(matches!(Canvas::from_pfm(b"PF\n2 1\n-1.0\n\0\0\0\0\0\0\0\0"),
+                             Err(HdrError::TruncatedData { expected: 6, found: 2 })));
+
+            // width * height * 3 does not fit in a usize
+            assert!(matches!(Canvas::from_pfm(b"PF\n4000000000 4000000000\n-1.0\n\0\0\0\0"),
+                             Err(HdrError::InvalidHeader("image size"))));
+        }
+
+        #[test]
+        fn rgbe_conversions() {
+            // (color, rgbe)
+            let cases = [
+                (Color::new(1.0, 0.5, 0.25), [128, 64, 32, 129]),
+                (Color::new(0.0, 0.0, 0.0), [0, 0, 0, 0]),
+                (Color::new(-1.0, 0.0, 3.0), [0, 0, 192, 130]),
+                (Color::new(1000.0, 0.0, 0.0), [250, 0, 0, 138]),
+            ];
+
+            for (color, rgbe) in cases.iter() {
+                assert_eq!(color_to_rgbe(color), *rgbe);
+            }
+
+            assert_eq!(rgbe_to_color([128, 64, 32, 129]), Color::new(1.0, 0.5, 0.25));
+            assert_eq!(rgbe_to_color([0, 0, 0, 0]), Color::new(0.0, 0.0, 0.0));
+            assert_eq!(color_to_rgbe(&Color::new(f64::NAN, 1.0, 0.0)), [0, 128, 0, 129]);
+            assert_eq!(color_to_rgbe(&Color::new(f64::INFINITY, 1.0, 0.0)), [255, 255, 255, 255]);
+        }
+
+        #[test]
+        fn rgbe_keeps_relative_precision() {
+            for v in [1e-10, 0.001, 0.3, 0.999, 1.0, 7.5, 12345.0, 1e20].iter() {
+                let color = Color::new(*v, *v * 0.6, *v * 0.1);
+
+                assert_rgbe_close(&rgbe_to_color(color_to_rgbe(&color)), &color);
+            }
+        }
+
+        #[test]
+        fn write_hdr_header() {
+            let mut hdr: Vec<u8> = Vec::new();
+            Canvas::new(3, 2, Color::new(1.0, 0.5, 0.25)).write_hdr(&mut hdr).unwrap();
+
+            let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 3\n";
+            assert_eq!(hdr[..header.len()], header[..]);
+
+            // Too narrow to be run length encoded
+            assert_eq!(hdr[header.len()..], [128, 64, 32, 129].repeat(6)[..]);
+        }
+
+        #[test]
+        fn run_length_encode_scanlines() {
+            let mut hdr: Vec<u8> = Vec::new();
+            Canvas::new(200, 1, Color::new(1.0, 0.5, 0.25)).write_hdr(&mut hdr).unwrap();
+
+            let header_length = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 200\n".len();
+
+            // Each channel is a run of 127 and a run of 73
+            let mut expected = vec![2, 2, 0, 200];
+            for value in [128, 64, 32, 129].iter() {
+                expected.extend_from_slice(&[128 + 127, *value, 128 + 73, *value]);
+            }
+            assert_eq!(hdr[header_length..], expected[..]);
+        }
+
+        #[test]
+        fn rle_mixes_runs_and_literals() {
+            let values: Vec<u8> = vec![1, 2, 3, 7, 7, 7, 7, 7, 4, 4, 5];
+            let mut out: Vec<u8> = Vec::new();
+            write_rle_channel(&values, &mut out);
+
+            assert_eq!(out, vec![3, 1, 2, 3, 128 + 5, 7, 3, 4, 4, 5]);
+
+            // Literal runs are split at 128 bytes
+            let values: Vec<u8> = (0..300).map(|i| (i % 2) as u8).collect();
+            let mut out: Vec<u8> = Vec::new();
+            write_rle_channel(&values, &mut out);
+
+            assert_eq!(out.len(), 300 + 3);
+            assert_eq!((out[0], out[129], out[258]), (128, 128, 44));
+        }
+
+        #[test]
+        fn hdr_round_trip() {
+            for (width, height) in [(5, 3), (40, 6)].iter() {
+                let c = test_canvas(*width, *height);
+                let mut hdr: Vec<u8> = Vec::new();
+                c.write_hdr(&mut hdr).unwrap();
+
+                let read = Canvas::from_hdr(&hdr).unwrap();
+
+                assert_eq!((read.width, read.height), (*width, *height));
+                for x in 0..*width {
+                    for y in 0..*height {
+                        assert_rgbe_close(&read.read_pixel(x, y), &c.read_pixel(x, y));
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn read_hdr_bottom_up_with_exposure() {
+            let mut hdr = b"#?RGBE\n# made by hand\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=2.0\n\n+Y 2 +X 1\n".to_vec();
+            hdr.extend_from_slice(&[128, 64, 32, 129]);
+            hdr.extend_from_slice(&[128, 128, 128, 130]);
+
+            let c = Canvas::from_hdr(&hdr).unwrap();
+
+            assert_eq!(c.read_pixel(0, 1), Color::new(0.5, 0.25, 0.125));
+            assert_eq!(c.read_pixel(0, 0), Color::new(1.0, 1.0, 1.0));
+        }
+
+        #[test]
+        fn read_hdr_old_style_runs() {
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 300\n".to_vec();
+            hdr.extend_from_slice(&[128, 64, 32, 129]);
+            // 43 + (1 << 8) more copies of the first pixel
+            hdr.extend_from_slice(&[1, 1, 1, 43]);
+            hdr.extend_from_slice(&[1, 1, 1, 1]);
+
+            let c = Canvas::from_hdr(&hdr).unwrap();
+
+            for x in 0..300 {
+                assert_eq!(c.read_pixel(x, 0), Color::new(1.0, 0.5, 0.25));
+            }
+        }
+
+        #[test]
+        fn reject_invalid_hdr() {
+            assert!(matches!(Canvas::from_hdr(b"PF\n1 1\n-1.0\n"), Err(HdrError::UnsupportedFormat(_))));
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n"),
+                             Err(HdrError::UnsupportedFormat(ref f)) if f == "32-bit_rle_xyze"));
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\n\n-X 1 +Y 1\n"), Err(HdrError::InvalidHeader("resolution"))));
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\n\n-Y 2 +X 1\n\x80\x40\x20\x81"),
+                             Err(HdrError::TruncatedData { expected: 2, found: 1 })));
+
+            // The encoded width does not match the header
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+            hdr.extend_from_slice(&[2, 2, 0, 9]);
+            assert!(matches!(Canvas::from_hdr(&hdr), Err(HdrError::InvalidScanline { row: 0 })));
+
+            // A run past the end of the scanline
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+            hdr.extend_from_slice(&[2, 2, 0, 8, 128 + 9, 1]);
+            assert!(matches!(Canvas::from_hdr(&hdr), Err(HdrError::InvalidScanline { row: 0 })));
+
+            // More old style repeats in a row than a usize count can hold
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 4\n".to_vec();
+            hdr.extend_from_slice(&[128, 64, 32, 129]);
+            hdr.extend_from_slice(&[1, 1, 1, 0].repeat(9));
+            assert!(matches!(Canvas::from_hdr(&hdr), Err(HdrError::InvalidScanline { row: 0 })));
+        }
+
+        #[test]
+        fn reject_huge_hdr_sizes() {
+            // width * height does not fit in a usize
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\n\n-Y 18446744073709551615 +X 2\n\0\0\0\0"),
+                             Err(HdrError::InvalidHeader("image size"))));
+
+            // Far more scanlines than the file could hold
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\n\n-Y 16384 +X 16384\n\0\0\0\0"),
+                             Err(HdrError::TruncatedData { expected: 16384, found: 1 })));
+
+            // A single very wide scanline is read until the data runs out
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 100000000\n".to_vec();
+            hdr.extend_from_slice(&[128, 64, 32, 129]);
+            assert!(matches!(Canvas::from_hdr(&hdr), Err(HdrError::TruncatedData { expected: 1, found: 0 })));
+
+            // Old-style runs that would repeat one pixel about 10^11 times
+            let mut hdr = b"#?RADIANCE\n\n-Y 1 +X 100000000000\n".to_vec();
+            hdr.extend_from_slice(&[128, 64, 32, 129]);
+            for _ in 0..4 {
+                hdr.extend_from_slice(&[1, 1, 1, 0]);
+            }
+            hdr.extend_from_slice(&[1, 1, 1, 23]);
+            assert!(matches!(Canvas::from_hdr(&hdr), Err(HdrError::InvalidHeader("image size"))));
+
+            // Just over the largest size that is read
+            assert!(matches!(Canvas::from_hdr(b"#?RADIANCE\n\n-Y 16384 +X 16385\n\0\0\0\0"),
+                             Err(HdrError::InvalidHeader("image size"))));
+        }
+    }
+}
+
+pub mod tone_mapping {
+    use super::rt_prelude::{Color, Canvas};
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum ToneMapOperator {
+        // Cuts off everything above 1
+        Clamp,
+        // c / (1 + c), which never quite reaches 1
+        Reinhard,
+        // Reinhard scaled so that white_point and anything brighter maps to 1.
+        // Behaves like plain Reinhard if white_point is not positive and finite.
+        ExtendedReinhard { white_point: f64 },
+        // Krzysztof Narkowicz's fit of the ACES filmic curve
+        AcesFilmic,
+    }
+
+    impl ToneMapOperator {
+        // Maps a single linear channel into 0..1
+        pub fn apply(&self, c: f64) -> f64 {
+            // Also turns NaN into 0
+            let c = c.max(0.0);
+
+            let mapped = match self {
+                ToneMapOperator::Clamp => c,
+                ToneMapOperator::Reinhard => c / (1.0 + c),
+                // A white point of 0 would turn black into 0 / 0
+                ToneMapOperator::ExtendedReinhard { white_point } if *white_point > 0.0 && white_point.is_finite() => {
+                    c * (1.0 + c / (white_point * white_point)) / (1.0 + c)
+                }
+                ToneMapOperator::ExtendedReinhard { .. } => c / (1.0 + c),
+                ToneMapOperator::AcesFilmic => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+            };
+
+            mapped.min(1.0)
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum TransferFunction {
+        // Writes the tone mapped values as they are
+        Linear,
+        // The sRGB curve that image viewers and browsers expect
+        Srgb,
+    }
+
+    impl TransferFunction {
+        pub fn encode(&self, c: f64) -> f64 {
+            match self {
+                TransferFunction::Linear => c,
+                TransferFunction::Srgb => srgb_encode(c),
+            }
+        }
+    }
+
+    pub fn srgb_encode(linear: f64) -> f64 {
+        if linear <= 0.0031308 {
+            linear * 12.92
+        } else {
+            1.055 * linear.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    pub fn srgb_decode(encoded: f64) -> f64 {
+        if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    // Turns linear radiance into display values between 0 and 1, ready for an 8 or 16 bit encoder
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct ToneMapper {
+        pub operator: ToneMapOperator,
+        // In stops, so each step of 1 doubles the brightness before tone mapping
+        pub exposure: f64,
+        pub transfer: TransferFunction,
+    }
+
+    impl ToneMapper {
+        pub fn new_tone_mapper(operator: ToneMapOperator) -> ToneMapper {
+            ToneMapper {
+                operator,
+                exposure: 0.0,
+                transfer: TransferFunction::Srgb,
+            }
+        }
+
+        pub fn map_color(&self, color: &Color) -> Color {
+            let scale = 2.0_f64.powf(self.exposure);
+            let map = |c: f64| self.transfer.encode(self.operator.apply(c * scale));
+
+            Color::new(map(color.r), map(color.g), map(color.b))
+        }
+    }
+
+    impl Canvas {
+        pub fn tone_mapped(&self, tone_mapper: &ToneMapper) -> Canvas {
+            let mut display = Canvas::new(self.width, self.height, Color::new(0.0, 0.0, 0.0));
+
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    display.write_pixel(x, y, &tone_mapper.map_color(&self.read_pixel(x, y)));
+                }
+            }
+
+            display
+        }
+    }
+
+    #[cfg(test)]
+    mod tone_mapping_tests {
+        use super::*;
+        use super::super::rt_prelude::equal_approx;
+
+        #[test]
+        fn tone_map_operators() {
+            // (operator, input, output)
+            let cases = [
+                (ToneMapOperator::Clamp, 0.5, 0.5),
+                (ToneMapOperator::Clamp, 3.0, 1.0),
+                (ToneMapOperator::Reinhard, 1.0, 0.5),
+                (ToneMapOperator::Reinhard, 3.0, 0.75),
+                (ToneMapOperator::ExtendedReinhard { white_point: 4.0 }, 4.0, 1.0),
+                (ToneMapOperator::ExtendedReinhard { white_point: 4.0 }, 1.0, 0.53125),
+                (ToneMapOperator::ExtendedReinhard { white_point: 4.0 }, 100.0, 1.0),
+                (ToneMapOperator::AcesFilmic, 0.0, 0.0),
+                (ToneMapOperator::AcesFilmic, 1.0, 2.54 / 3.16),
+                (ToneMapOperator::AcesFilmic, 1000.0, 1.0),
+            ];
+
+            for (operator, input, output) in cases.iter() {
+                assert!(equal_approx(operator.apply(*input), *output), "{:?} of {}", operator, input);
+            }
+        }
+
+        #[test]
+        fn operators_reject_negative_and_nan() {
+            let operators = [
+                ToneMapOperator::Clamp,
+                ToneMapOperator::Reinhard,
+                ToneMapOperator::ExtendedReinhard { white_point: 2.0 },
+                ToneMapOperator::AcesFilmic,
+            ];
+
+            for operator in operators.iter() {
+                assert_eq!(operator.apply(-1.0), 0.0);
+                assert_eq!(operator.apply(f64::NAN), 0.0);
+                assert_eq!(operator.apply(f64::INFINITY), 1.0);
+            }
+        }
+
+        #[test]
+        fn invalid_white_points_fall_back_to_reinhard() {
+            for white_point in [0.0, -2.0, f64::NAN, f64::INFINITY].iter() {
+                let operator = ToneMapOperator::ExtendedReinhard { white_point: *white_point };
+
+                for c in [0.0, 0.5, 1.0, 3.0].iter() {
+                    assert_eq!(operator.apply(*c), ToneMapOperator::Reinhard.apply(*c), "white point {}", white_point);
+                }
+            }
+        }
+
+        #[test]
+        fn operators_are_monotonic() {
+            let operators = [
+                ToneMapOperator::Reinhard,
+                ToneMapOperator::ExtendedReinhard { white_point: 5.0 },
+                ToneMapOperator::AcesFilmic,
+            ];
+
+            for operator in operators.iter() {
+                let mut previous = 0.0;
+
+                for i in 1..100 {
+                    let mapped = operator.apply(i as f64 * 0.05);
+                    assert!(mapped >= previous);
+                    previous = mapped;
+                }
+            }
+        }
+
+        #[test]
+        fn srgb_transfer_curve() {
+            // (linear, encoded)
+            let cases = [
+                (0.0, 0.0),
+                (0.002, 0.02584),
+                (0.18, 0.46135),
+                (0.5, 0.73536),
+                (1.0, 1.0),
+            ];
+
+            for (linear, encoded) in cases.iter() {
+                assert!((srgb_encode(*linear) - encoded).abs() < 0.00001);
+                assert!((srgb_decode(*encoded) - linear).abs() < 0.00001);
+            }
+        }
+
+        #[test]
+        fn exposure_is_in_stops() {
+            let mut tone_mapper = ToneMapper::new_tone_mapper(ToneMapOperator::Reinhard);
+            tone_mapper.transfer = TransferFunction::Linear;
+
+            assert_eq!(tone_mapper.map_color(&Color::new(1.0, 0.5, 3.0)), Color::new(0.5, 1.0 / 3.0, 0.75));
+
+            tone_mapper.exposure = 1.0;
+            assert_eq!(tone_mapper.map_color(&Color::new(0.5, 0.25, 1.5)), Color::new(0.5, 1.0 / 3.0, 0.75));
+
+            tone_mapper.exposure = -2.0;
+            assert_eq!(tone_mapper.map_color(&Color::new(4.0, 2.0, 12.0)), Color::new(0.5, 1.0 / 3.0, 0.75));
+        }
+
+        #[test]
+        fn tone_map_canvas() {
+            let mut c = Canvas::new(2, 1, Color::new(0.0, 0.0, 0.0));
+            c.write_pixel(1, 0, &Color::new(0.5, 2.0, -1.0));
+
+            let display = c.tone_mapped(&ToneMapper::new_tone_mapper(ToneMapOperator::Clamp));
+
+            assert_eq!((display.width, display.height), (2, 1));
+            assert_eq!(display.read_pixel(0, 0), Color::new(0.0, 0.0, 0.0));
+            assert_eq!(display.read_pixel(1, 0), Color::new(srgb_encode(0.5), 1.0, 0.0));
+
+            // The original canvas keeps its radiance
+            assert_eq!(c.read_pixel(1, 0), Color::new(0.5, 2.0, -1.0));
+        }
+    }
+}
+
+pub mod quantization {
+    use super::rt_prelude::{Color, Rounding, quantize_channel};
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub enum Dither {
+        None,
+        // A 4x4 Bayer matrix, which breaks up banding in smooth gradients and is the same on every run
+        Ordered,
+    }
+
+    const BAYER_MATRIX: [[u8; 4]; 4] = [
+        [0, 8, 2, 10],
+        [12, 4, 14, 6],
+        [3, 11, 1, 9],
+        [15, 7, 13, 5],
+    ];
+
+    // Turns colors into integer samples for the image encoders
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct Quantizer {
+        pub rounding: Rounding,
+        pub dither: Dither,
+    }
+
+    impl Quantizer {
+        pub fn new_quantizer(rounding: Rounding) -> Quantizer {
+            Quantizer {
+                rounding,
+                dither: Dither::None,
+            }
+        }
+
+        // Scales each channel to 0..max_value. x and y are the pixel's coordinates, which pick its dither offset.
+        pub fn quantize(&self, color: &Color, max_value: u16, x: usize, y: usize) -> [u16; 3] {
+            // Offsets average out to 0, so dithering doesn't make the image brighter or darker
+            let offset = match self.dither {
+                Dither::None => 0.0,
+                Dither::Ordered => (BAYER_MATRIX[y % 4][x % 4] as f64 + 0.5) / 16.0 - 0.5,
+            };
+
+            let channel = |c: f64| quantize_channel(c, max_value, self.rounding, offset);
+
+            [channel(color.r), channel(color.g), channel(color.b)]
+        }
+    }
+
+    #[cfg(test)]
+    mod quantization_tests {
+        use super::*;
+
+        #[test]
+        fn quantize_channels() {
+            // (value, max value, rounding, sample)
+            let cases = [
+                (0.5, 255, Rounding::Nearest, 128),
+                (0.5, 255, Rounding::Truncate, 127),
+                (0.999, 255, Rounding::Nearest, 255),
+                (0.999, 255, Rounding::Truncate, 254),
+                (1.5, 255, Rounding::Nearest, 255),
+                (1.5, 255, Rounding::Truncate, 255),
+                (-0.2, 255, Rounding::Nearest, 0),
+                (f64::NAN, 255, Rounding::Nearest, 0),
+                (f64::INFINITY, 255, Rounding::Truncate, 255),
+                (0.5, 65535, Rounding::Nearest, 32768),
+                (0.5, 65535, Rounding::Truncate, 32767),
+            ];
+
+            for (value, max_value, rounding, sample) in cases.iter() {
+                assert_eq!(quantize_channel(*value, *max_value, *rounding, 0.0), *sample, "{} {:?}", value, rounding);
+            }
+        }
+
+        #[test]
+        fn quantize_without_dither() {
+            let q = Quantizer::new_quantizer(Rounding::Nearest);
+            let color = Color::new(0.2, 1.2, 0.5);
+
+            for (x, y) in [(0, 0), (3, 1), (17, 42)].iter() {
+                assert_eq!(q.quantize(&color, 255, *x, *y), [51, 255, 128]);
+                assert_eq!(q.quantize(&color, 65535, *x, *y), [13107, 65535, 32768]);
+            }
+        }
+
+        #[test]
+        fn ordered_dither_keeps_average() {
+            let mut q = Quantizer::new_quantizer(Rounding::Nearest);
+            q.dither = Dither::Ordered;
+
+            // 100.25 out of 255 rounds up in a quarter of the pixels
+            let color = Color::new(100.25 / 255.0, 100.25 / 255.0, 100.25 / 255.0);
+            let mut total = 0;
+
+            for x in 0..4 {
+                for y in 0..4 {
+                    let [r, g, b] = q.quantize(&color, 255, x, y);
+
+                    assert!(r == 100 || r == 101);
+                    assert_eq!((r, r), (g, b));
+                    total += r as u32;
+                }
+            }
+
+            assert_eq!(total, 16 * 100 + 4);
+
+            // The pattern repeats every 4 pixels
+            assert_eq!(q.quantize(&color, 255, 1, 2), q.quantize(&color, 255, 5, 10));
+        }
+
+        #[test]
+        fn ordered_dither_keeps_black_and_white() {
+            let mut q = Quantizer::new_quantizer(Rounding::Nearest);
+            q.dither = Dither::Ordered;
+
+            for x in 0..4 {
+                for y in 0..4 {
+                    assert_eq!(q.quantize(&Color::new(0.0, 1.0, 2.0), 255, x, y), [0, 255, 255]);
+                    assert_eq!(q.quantize(&Color::new(0.0, 1.0, 2.0), 65535, x, y), [0, 65535, 65535]);
+                }
+            }
+        }
+    }
+}